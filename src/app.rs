@@ -1,36 +1,141 @@
-use std::{io::Stdout, ops::Deref, sync::Arc, time::Duration};
+use std::{
+    collections::HashSet,
+    io::Stdout,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
-use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind};
-use eyre::Result;
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use eyre::{eyre, Report, Result};
 use itertools::Itertools;
-use log::{debug, error};
+use log::{debug, error, warn};
 use mpris_server::{LoopStatus, Server, TrackId};
 use ratatui::{
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout},
     prelude::{Backend, Rect},
+    style::{Color, Style},
+    widgets::Paragraph,
     Terminal,
 };
 
-use smol::{channel::Receiver, lock::RwLock, pin, prelude::*};
+use smol::{channel::Receiver, lock::RwLock, pin, prelude::*, Timer};
 use unicode_width::UnicodeWidthStr;
 
 use crate::{
-    audio::{Player, PlayerMessage},
-    library::{Library, Track},
+    audio::{
+        output_devices, LoopPoint, OutputBackend, PlayQueue, Player, PlayerMessage, ShuffleMode,
+        StopAfter,
+    },
+    bookmarks::{Bookmark, Bookmarks},
+    config::Config,
+    errors::ErrorLog,
+    history::{History, Play, PlayEnd},
+    library::{AlbumName, ArtistName, Library, Track},
     library_panel::{LibraryPanel, PanelItem},
-    mpris::MprisAdapter,
+    mpris::{MprisAdapter, MprisPublisher},
+    radio,
+    ratings::{self, Ratings, TrackRating},
+    session::Session,
+    sleep::{SleepAt, SleepTimer},
+    stats::{Stats, TrackStats},
     ui::{
-        album_art::AlbumArt, artist_album_list::ArtistAlbumList, now_playing::NowPlaying,
-        search::Search, spectrogram::Visualizer, Theme, Ui,
+        album_art::AlbumArt,
+        artist_album_list::ArtistAlbumList,
+        bookmark_list::{format_position, BookmarkList},
+        device_picker::DevicePicker,
+        equalizer::{EqEdit, EqEditor},
+        error_list::ErrorList,
+        history_list::HistoryList,
+        now_playing::NowPlaying,
+        queue_list::QueueList,
+        search::{Search, SearchItem},
+        spectrogram::Visualizer,
+        track_list::{TrackInfo, TrackList, TrackListItem},
+        ActiveState, Theme, Ui,
     },
 };
 
+/// How often we save the session, in case we don't get to on quit.
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// How long messages stay in the status bar.
+const STATUS_DURATION: Duration = Duration::from_secs(5);
+
+/// What the sleep timer key cycles through after turning it on. `None` means the end of the
+/// current track.
+const SLEEP_PRESETS: [Option<Duration>; 4] = [
+    Some(Duration::from_secs(15 * 60)),
+    Some(Duration::from_secs(30 * 60)),
+    Some(Duration::from_secs(60 * 60)),
+    None,
+];
+
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum Panel {
     #[default]
     Library,
     Search,
+    /// Picking an output device.
+    Devices,
+    /// Editing the EQ.
+    Equalizer,
+    Bookmarks,
+    /// Viewing and editing the play queue.
+    Queue,
+    History,
+    /// Loved tracks.
+    Favorites,
+    /// Recent errors.
+    Errors,
+}
+
+/// Everything we remember between runs, besides the library and config. These are all saved in
+/// the cache directory.
+#[derive(Debug, Default)]
+pub struct SavedState {
+    pub bookmarks: Bookmarks,
+    pub session: Session,
+    pub history: History,
+    pub stats: Stats,
+    pub ratings: Ratings,
+}
+
+impl SavedState {
+    /// Loads everything from `dir`. Anything that can't be loaded is logged and left empty; it
+    /// won't be saved either, in case the user wants to fix it by hand.
+    pub fn load(dir: &Path) -> Self {
+        fn load<T: Default>(name: &str, result: Result<T>) -> T {
+            result.unwrap_or_else(|e| {
+                error!("Couldn't load {name}, so it won't be saved: {e}");
+                T::default()
+            })
+        }
+        Self {
+            bookmarks: load("bookmarks", Bookmarks::load(dir.join("bookmarks.json"))),
+            session: load("the previous session", Session::load(dir.join("session.json"))),
+            history: load("playback history", History::load(dir.join("history.json"))),
+            stats: load("play statistics", Stats::load(dir.join("stats.json"))),
+            ratings: load("ratings", Ratings::load(dir.join("ratings.json"))),
+        }
+    }
+}
+
+/// Playback modes to start with, e.g. from the command line. These are applied once the previous
+/// session has been restored.
+#[derive(Debug, Default)]
+pub struct PlaybackOptions {
+    pub stop_after: Option<StopAfter>,
+    /// How many more times to play the current track.
+    pub repeats: u32,
+    pub sleep_timer: Option<SleepTimer>,
+}
+
+/// Something that can be rated.
+enum RatingTarget {
+    Track(Arc<Track>),
+    Album(ArtistName, AlbumName),
 }
 
 pub struct App {
@@ -40,20 +145,86 @@ pub struct App {
     library_panel: LibraryPanel,
     visualizer: Visualizer,
     search: Search,
+    device_picker: DevicePicker,
+    eq_editor: EqEditor,
+    /// Name of the EQ preset in use, if any. This is picked based on the current track, falling
+    /// back to the one in the config.
+    eq_preset: Option<String>,
+    bookmarks: Bookmarks,
+    bookmark_list: BookmarkList,
+    queue_list: QueueList,
+    history: History,
+    history_list: HistoryList,
+    stats: Arc<RwLock<Stats>>,
+    ratings: Arc<RwLock<Ratings>>,
+    favorites: TrackList,
+    /// When the current track started playing, for the history.
+    track_started: Option<SystemTime>,
+    /// Where to start playing the next track from, instead of the start or its resume position.
+    start_at: Option<Duration>,
+    session: Session,
+    last_session_save: Instant,
+    /// Applied at startup, and then unused.
+    playback_options: PlaybackOptions,
+    sleep_timer: Option<SleepTimer>,
+    /// Which of [`SLEEP_PRESETS`] the sleep timer was set from, if any.
+    sleep_preset: Option<usize>,
+    /// Whether we add more tracks when the queue runs out.
+    radio: bool,
+    errors: ErrorLog,
+    error_list: ErrorList,
+    /// The most recent error, and when it happened. This is shown at the bottom of the screen for
+    /// [`STATUS_DURATION`].
+    status: Option<(String, Instant)>,
+    /// How many tracks in a row have failed to play. Once every track in the queue has, we stop
+    /// instead of skipping to the next one.
+    failures_in_a_row: usize,
     active_panel: Panel,
     album_art: AlbumArt,
     ui: Ui,
+    config: Config,
     should_quit: bool,
 
     rx_message: Option<Receiver<Message>>,
 }
 
 impl App {
-    pub fn new(library: Library) -> Self {
+    pub fn new(
+        library: Library,
+        config: Config,
+        backend: Box<dyn OutputBackend>,
+        saved: SavedState,
+    ) -> Self {
         let (tx_message, rx_message) = smol::channel::unbounded();
 
-        let player = Arc::new(RwLock::new(Player::new(tx_message.clone()).unwrap()));
-        let mpris = MprisAdapter::new(tx_message.clone(), Arc::clone(&player));
+        let mut player = Player::new(tx_message.clone(), backend, config.output_device()).unwrap();
+        player.set_fade_duration(Duration::from_millis(config.fade_ms));
+        if let Err(e) = player.set_silence(config.silence) {
+            error!("Couldn't set up silence skipping: {e}");
+        }
+        let stats = Arc::new(RwLock::new(saved.stats));
+        let ratings = Arc::new(RwLock::new(saved.ratings));
+        player.set_shuffle_weight({
+            let (stats, ratings) = (Arc::clone(&stats), Arc::clone(&ratings));
+            // Shuffling happens with the player locked, so don't wait on these. Nothing holds
+            // them for long, and if something does, the track just gets an average weight.
+            Arc::new(move |track| match (stats.try_read(), ratings.try_read()) {
+                (Some(stats), Some(ratings)) => shuffle_weight(
+                    stats.get(&track.path),
+                    ratings.track(&track.path),
+                    SystemTime::now(),
+                ),
+                _ => 1.0,
+            })
+        });
+        let player = Arc::new(RwLock::new(player));
+        let mpris = MprisAdapter::new(
+            tx_message.clone(),
+            Arc::clone(&player),
+            Arc::clone(&stats),
+            Arc::clone(&ratings),
+            library.tracks().map(|track| track.path.clone()).collect(),
+        );
 
         Self {
             mpris: Some(mpris),
@@ -62,8 +233,32 @@ impl App {
             library_panel: LibraryPanel::default(),
             visualizer: Visualizer::default(),
             search: Search::default(),
+            device_picker: DevicePicker::default(),
+            eq_editor: EqEditor::default(),
+            eq_preset: None,
+            bookmarks: saved.bookmarks,
+            bookmark_list: BookmarkList::default(),
+            queue_list: QueueList::default(),
+            history: saved.history,
+            history_list: HistoryList::default(),
+            stats,
+            ratings,
+            favorites: TrackList::default(),
+            track_started: None,
+            start_at: None,
+            session: saved.session,
+            last_session_save: Instant::now(),
+            playback_options: PlaybackOptions::default(),
+            sleep_timer: None,
+            sleep_preset: None,
+            radio: config.radio.enabled,
+            errors: ErrorLog::default(),
+            error_list: ErrorList::default(),
+            status: None,
+            failures_in_a_row: 0,
             active_panel: Panel::Library,
             ui: Ui::default(),
+            config,
             should_quit: false,
             album_art: AlbumArt::new(),
 
@@ -71,6 +266,11 @@ impl App {
         }
     }
 
+    /// Sets playback modes to start with. These take effect when [`App::run`] is called.
+    pub fn set_playback_options(&mut self, options: PlaybackOptions) {
+        self.playback_options = options;
+    }
+
     pub async fn run(
         mut self,
         terminal_events: impl Stream<Item = Event> + Send + Sync + 'static,
@@ -80,9 +280,20 @@ impl App {
 
         pin!(terminal_events);
 
-        let mut event_stream = AppEvent::stream(terminal_events, self.rx_message.take().unwrap());
+        let mut event_stream =
+            AppEvent::stream(terminal_events, self.rx_message.take().unwrap(), self.config.fps);
 
-        let _server = Server::new("deimos", self.mpris.take().unwrap()).await?;
+        let server = Server::new_with_track_list("deimos", self.mpris.take().unwrap()).await?;
+        let mut mpris = MprisPublisher::new(server).await;
+        self.select_eq_preset(None).await;
+        self.restore_session().await?;
+        let options = std::mem::take(&mut self.playback_options);
+        {
+            let mut player = self.player.write().await;
+            player.set_stop_after(options.stop_after);
+            player.set_repeats(options.repeats);
+        }
+        self.sleep_timer = options.sleep_timer;
 
         terminal.hide_cursor()?;
         self.draw(terminal).await?;
@@ -91,23 +302,31 @@ impl App {
                 AppEvent::Terminal(terminal_event) => self.lookup_binding(terminal_event),
                 AppEvent::Message(message) => Some(message),
                 AppEvent::Tick => {
+                    let new_audio = self.read_audio().await?;
                     if self.tick().await? {
                         self.draw(terminal).await?;
+                    } else if new_audio {
+                        self.draw_audio_only(terminal).await?;
                     }
+                    // Ticks can change things too, e.g. the sleep timer fading the volume out.
+                    mpris.publish().await;
                     continue;
                 }
             };
-            let audio_only = message.as_ref().map_or(false, |m| m.audio_only());
             if let Some(message) = message {
                 debug!("Received message {message:?}");
-                self.dispatch(message).await?;
-            }
-            if audio_only {
-                self.draw_audio_only(terminal).await?;
-            } else {
-                self.draw(terminal).await?;
+                if let Err(e) = self.dispatch(message).await {
+                    // Don't let one thing going wrong take down the whole app.
+                    self.report_error(None, &e);
+                }
+                mpris.publish().await;
             }
+            self.draw(terminal).await?;
             if self.should_quit {
+                // Otherwise we'd save the faded volume.
+                self.set_sleep_timer(None).await;
+                self.remember_position().await;
+                self.save_session().await;
                 return Ok(());
             }
         }
@@ -131,13 +350,65 @@ impl App {
         let bounds = Bounds::new(frame.size());
         match self.active_panel {
             Panel::Library => {
-                self.library_panel.draw(&self.ui, frame, bounds.panel, player.current())?
+                let (stats, ratings) = (self.stats.read().await, self.ratings.read().await);
+                let info = TrackInfo {
+                    stats: &stats,
+                    ratings: &ratings,
+                };
+                self.library_panel.draw(&self.ui, frame, bounds.panel, player.current(), info)?
+            }
+            Panel::Favorites => {
+                let (stats, ratings) = (self.stats.read().await, self.ratings.read().await);
+                let info = TrackInfo {
+                    stats: &stats,
+                    ratings: &ratings,
+                };
+                self.favorites.draw(
+                    ActiveState::Focused,
+                    &self.ui,
+                    frame,
+                    bounds.panel,
+                    player.current(),
+                    info,
+                )?
             }
             Panel::Search => self.search.draw(&self.ui, frame, bounds.panel)?,
+            Panel::Devices => self.device_picker.draw(&self.ui, frame, bounds.panel)?,
+            Panel::Equalizer => {
+                let name = self.eq_preset.as_deref();
+                self.eq_editor.draw(
+                    &self.ui,
+                    frame,
+                    bounds.panel,
+                    name,
+                    name.and_then(|name| self.config.equalizer.presets.get(name)),
+                    name.is_some() && name != self.config.equalizer.preset.as_deref(),
+                )?
+            }
+            Panel::Bookmarks => self.bookmark_list.draw(
+                &self.ui,
+                frame,
+                bounds.panel,
+                self.bookmarks.bookmarks(),
+            )?,
+            Panel::Queue => self.queue_list.draw(&self.ui, frame, bounds.panel, player.queue())?,
+            Panel::History => self.history_list.draw(&self.ui, frame, bounds.panel)?,
+            Panel::Errors => self.error_list.draw(&self.ui, frame, bounds.panel)?,
+        }
+        if let Some((message, _)) = &self.status {
+            let status = Paragraph::new(message.as_str()).style(Style::default().fg(Color::Red));
+            frame.render_widget(status, bounds.status);
         }
         NowPlaying {
             timestamp: player.timestamp(),
             track: player.current(),
+            speed: player.speed(),
+            shuffle: player.queue().shuffle().then(|| player.queue().shuffle_mode()),
+            stop_after: player.queue().stop_after(),
+            repeats_left: player.queue().repeats_left(),
+            sleep_timer: self.sleep_timer,
+            radio: self.radio,
+            ab_loop: player.ab_loop(),
         }
         .draw(&self.ui, frame, bounds.now_playing)?;
         self.visualizer.draw(&self.ui, frame, bounds.visualizer)?;
@@ -160,6 +431,13 @@ impl App {
         NowPlaying {
             timestamp: player.timestamp(),
             track: player.current(),
+            speed: player.speed(),
+            shuffle: player.queue().shuffle().then(|| player.queue().shuffle_mode()),
+            stop_after: player.queue().stop_after(),
+            repeats_left: player.queue().repeats_left(),
+            sleep_timer: self.sleep_timer,
+            radio: self.radio,
+            ab_loop: player.ab_loop(),
         }
         .draw(&self.ui, frame, bounds.now_playing)?;
         self.visualizer.draw(&self.ui, frame, bounds.visualizer)?;
@@ -196,19 +474,135 @@ impl App {
     fn lookup_binding(&self, ev: Event) -> Option<Message> {
         let Event::Key(KeyEvent {
             code,
+            modifiers,
             kind: KeyEventKind::Press,
             ..
         }) = ev
         else {
             return None;
         };
-        self.key_to_command(code).map(Message::Command)
+        self.key_to_command(code, modifiers).map(Message::Command)
     }
 
     /// Handles a time tick. The return value is true if this needs a refresh; not all ticks
     /// actually require a redraw.
-    async fn tick(&self) -> Result<bool> {
-        Ok(false)
+    async fn tick(&mut self) -> Result<bool> {
+        if self.last_session_save.elapsed() >= SESSION_SAVE_INTERVAL {
+            self.save_session().await;
+        }
+        let status_expired =
+            self.status.as_ref().map_or(false, |(_, at)| at.elapsed() >= STATUS_DURATION);
+        if status_expired {
+            self.status = None;
+        }
+        Ok(self.tick_sleep_timer().await || status_expired)
+    }
+
+    /// Catches up on the audio that's been heard since the last tick, updating the visualizer.
+    /// Returns true if there was any.
+    async fn read_audio(&mut self) -> Result<bool> {
+        let mut samples = vec![];
+        if !self.player.read().await.read_heard_audio(&mut samples) {
+            return Ok(false);
+        }
+        self.failures_in_a_row = 0;
+        self.visualizer.update_spectrum(&samples)?;
+        Ok(true)
+    }
+
+    /// Fades out if the sleep timer's close to going off, and pauses if it has. Returns true if
+    /// it went off.
+    async fn tick_sleep_timer(&mut self) -> bool {
+        let Some(timer) = self.sleep_timer.as_mut() else {
+            return false;
+        };
+        let mut player = self.player.write().await;
+        let now = Instant::now();
+        let remaining = timer.remaining(now, player.time_left());
+        if let Some(volume) = timer.faded_volume(remaining, player.volume()) {
+            player.set_volume(volume);
+        }
+        if !timer.expired(now) {
+            return false;
+        }
+        player.pause();
+        drop(player);
+        self.set_sleep_timer(None).await;
+        true
+    }
+
+    /// In radio mode, adds tracks to the end of the queue if we're on the last one.
+    async fn fill_radio(&mut self) {
+        if !self.radio {
+            return;
+        }
+        let mut player = self.player.write().await;
+        if player.current().is_none() || player.queue().next().is_some() {
+            return;
+        }
+        let tracks = radio::pick_tracks(
+            &self.library,
+            &self.history,
+            player.queue().tracks(),
+            &self.config.radio,
+        );
+        if tracks.is_empty() {
+            debug!("Radio mode couldn't find anything to add");
+        }
+        for track in tracks {
+            player.queue_push(track);
+        }
+    }
+
+    /// Replaces the sleep timer, putting the volume back if the old one had faded it.
+    async fn set_sleep_timer(&mut self, timer: Option<SleepTimer>) {
+        if let Some(volume) = self.sleep_timer.and_then(|t| t.original_volume()) {
+            self.player.write().await.set_volume(volume);
+        }
+        if timer.is_none() {
+            self.sleep_preset = None;
+        }
+        self.sleep_timer = timer;
+    }
+
+    /// Restores the play queue and playback modes from the last session. Playback starts paused.
+    async fn restore_session(&mut self) -> Result<()> {
+        let queue = self.session.queue(&self.library);
+        let current = {
+            let mut player = self.player.write().await;
+            player.set_volume(self.session.volume());
+            player.set_queue(queue).await;
+            let index = player.queue().current();
+            if let Err(e) = player.set_queue_index(index).await {
+                warn!("Couldn't restore the current track: {e}");
+                player.stop().await;
+            }
+            player.current()
+        };
+        if current.is_some() {
+            self.start_at = self.session.timestamp();
+        }
+        self.on_track_change(current.as_deref()).await
+    }
+
+    /// Saves the session, along with the history and stats, which change too often to save every
+    /// time.
+    async fn save_session(&mut self) {
+        self.session.update(&*self.player.read().await);
+        // Otherwise a crash partway through fading out would leave us quiet next time.
+        if let Some(volume) = self.sleep_timer.and_then(|t| t.original_volume()) {
+            self.session.set_volume(volume);
+        }
+        if let Err(e) = self.session.save() {
+            error!("Couldn't save session: {e}");
+        }
+        if let Err(e) = self.history.save() {
+            error!("Couldn't save history: {e}");
+        }
+        if let Err(e) = self.stats.write().await.save() {
+            error!("Couldn't save stats: {e}");
+        }
+        self.last_session_save = Instant::now();
     }
 }
 
@@ -218,6 +612,17 @@ pub enum Motion {
     Down,
 }
 
+/// Where to put tracks when adding them to the play queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enqueue {
+    /// Replace the queue and start playing.
+    Now,
+    /// Insert them right after the current track.
+    Next,
+    /// Add them to the end.
+    Append,
+}
+
 /// An [`message`] corresponds to a mutation of the application state. All mutation of application
 /// state should be done through messages.
 #[derive(Debug)]
@@ -226,18 +631,6 @@ pub enum Message {
     Player(PlayerMessage),
 }
 
-impl Message {
-    /// True if the message corresponds to progress from audio playback. We use this for more
-    /// efficient draw calls.
-    fn audio_only(&self) -> bool {
-        match self {
-            Message::Command(_) => false,
-            Message::Player(PlayerMessage::AudioFragment { .. }) => true,
-            Message::Player(_) => false,
-        }
-    }
-}
-
 /// A [`Command`] corresponds to a single user input. The translation of keys to commands is done
 /// by a match statement on (active panel, keycode).
 #[derive(Debug)]
@@ -246,6 +639,35 @@ pub enum Command {
     Cancel,
     /// Start a new search query.
     StartSearch,
+    /// Open the output device picker.
+    PickOutputDevice,
+    /// Open the EQ editor.
+    OpenEqualizer,
+    /// Change the current EQ preset.
+    EditEq(EqEdit),
+    /// Write the config, including any EQ changes, back to disk.
+    SaveConfig,
+    /// Bookmark the current position, and start naming the bookmark.
+    AddBookmark,
+    OpenBookmarks,
+    DeleteBookmark,
+    StartRenameBookmark,
+    /// User typed something into the new name of a bookmark. `None` is a backspace.
+    RenameBookmarkInput(Option<char>),
+    OpenQueue,
+    OpenHistory,
+    OpenErrors,
+    /// Changes how the track list is sorted.
+    CycleTrackSort,
+    /// Removes the selected track from the play queue.
+    RemoveFromQueue,
+    /// Moves the selected track in the play queue by the given amount.
+    MoveInQueue(isize),
+    ClearQueue,
+    /// Removes everything after the current track from the play queue.
+    ClearUpcoming,
+    /// Removes repeated tracks from the play queue.
+    DedupeQueue,
     /// Move focus to the next item in the panel.
     NextFocus,
     /// Perform an message on the currently-selected item.
@@ -264,10 +686,53 @@ pub enum Command {
         position: Duration,
         mpris_id: TrackId,
     },
+    /// Adds the library track at the given path to the queue after the one with the given ID, or
+    /// at the front if that's [`TrackId::NO_TRACK`]. If `play` is set, it's played right away.
+    /// This is used by the mpris server.
+    AddTrackAfter {
+        path: PathBuf,
+        after: TrackId,
+        play: bool,
+    },
+    /// Removes the first track in the queue with the given ID. This is used by the mpris server.
+    RemoveTrackById(TrackId),
+    /// Plays the first track in the queue with the given ID. This is used by the mpris server.
+    GoToTrack(TrackId),
     SetLoopStatus(LoopStatus),
     SetShuffle(bool),
-    /// Adds the currently selected song to the play queue.
-    AddSongToQueue,
+    /// Turns shuffling on, moves on to the next shuffle mode, or turns it off after the last one.
+    CycleShuffle,
+    /// Switches between not stopping, stopping after the current track, and stopping after the
+    /// current album.
+    CycleStopAfter,
+    /// Plays the current track one more time than before.
+    AddRepeat,
+    /// Sets how many more times to play the current track.
+    SetRepeats(u32),
+    /// Turns the sleep timer on, moves on to the next preset, or turns it off after the last one.
+    CycleSleepTimer,
+    /// Turns radio mode, where similar tracks are added when the queue runs out, on or off.
+    ToggleRadio,
+    /// Sets one end of the A-B loop to the current position.
+    SetLoopPoint(LoopPoint),
+    /// Moves one end of the A-B loop by the given number of milliseconds.
+    NudgeLoopPoint(LoopPoint, i64),
+    ClearAbLoop,
+    /// Sets the playback speed to the given multiple of normal.
+    SetSpeed(f64),
+    /// Adds the given amount to the playback speed.
+    ChangeSpeed(f64),
+    /// Sets the volume, where 1.0 is full volume.
+    SetVolume(f64),
+    /// Adds the given amount to the volume.
+    ChangeVolume(f64),
+    /// Adds whatever's selected (a track, album or artist) to the play queue.
+    Enqueue(Enqueue),
+    /// Gives the selected track or album this many stars. `None` clears the rating.
+    Rate(Option<u8>),
+    /// Loves or unloves the selected track.
+    ToggleLoved,
+    OpenFavorites,
     /// Seeks to the previous song if near the beginning, or restarts the song if not.
     PreviousOrSeekToStart,
     Play,
@@ -279,12 +744,79 @@ pub enum Command {
 }
 
 impl App {
-    fn key_to_command(&self, key: KeyCode) -> Option<Command> {
+    fn key_to_command(&self, key: KeyCode, modifiers: KeyModifiers) -> Option<Command> {
+        // These work in the search panel too, where plain letters are taken by the input.
+        if modifiers.contains(KeyModifiers::CONTROL) {
+            return match key {
+                KeyCode::Char('p') => Some(Command::Enqueue(Enqueue::Now)),
+                KeyCode::Char('n') => Some(Command::Enqueue(Enqueue::Next)),
+                KeyCode::Char('a') => Some(Command::Enqueue(Enqueue::Append)),
+                _ => None,
+            };
+        }
+        if modifiers.contains(KeyModifiers::ALT) {
+            return match key {
+                KeyCode::Char(c @ '0'..='5') => Some(Command::Rate(rating_for_key(c))),
+                KeyCode::Char('l') => Some(Command::ToggleLoved),
+                KeyCode::Char(',') => Some(Command::NudgeLoopPoint(LoopPoint::Start, -100)),
+                KeyCode::Char('.') => Some(Command::NudgeLoopPoint(LoopPoint::Start, 100)),
+                KeyCode::Char('<') => Some(Command::NudgeLoopPoint(LoopPoint::End, -100)),
+                KeyCode::Char('>') => Some(Command::NudgeLoopPoint(LoopPoint::End, 100)),
+                _ => None,
+            };
+        }
         let message = match (self.active_panel, key) {
             (Panel::Library, KeyCode::Char('/')) => Command::StartSearch,
             (Panel::Library, KeyCode::Char('q')) => Command::Quit,
             (Panel::Library, KeyCode::Tab) => Command::NextFocus,
-            (Panel::Library, KeyCode::Char('u')) => Command::AddSongToQueue,
+            (Panel::Library, KeyCode::Char('p')) => Command::Enqueue(Enqueue::Now),
+            (Panel::Library, KeyCode::Char('U')) => Command::Enqueue(Enqueue::Next),
+            (Panel::Library, KeyCode::Char('u')) => Command::Enqueue(Enqueue::Append),
+            (Panel::Library, KeyCode::Char('Q')) => Command::OpenQueue,
+            (Panel::Library, KeyCode::Char('H')) => Command::OpenHistory,
+            (Panel::Library, KeyCode::Char('E')) => Command::OpenErrors,
+            (Panel::Library, KeyCode::Char('s')) => Command::CycleTrackSort,
+            (Panel::Library, KeyCode::Char('F')) => Command::OpenFavorites,
+            (Panel::Library | Panel::Favorites, KeyCode::Char(c @ '0'..='5')) => {
+                Command::Rate(rating_for_key(c))
+            }
+            (Panel::Library | Panel::Favorites, KeyCode::Char('l')) => Command::ToggleLoved,
+            (Panel::Library, KeyCode::Char('o')) => Command::PickOutputDevice,
+            (Panel::Library, KeyCode::Char('e')) => Command::OpenEqualizer,
+            (Panel::Library, KeyCode::Char('b')) => Command::AddBookmark,
+            (Panel::Library, KeyCode::Char('B')) => Command::OpenBookmarks,
+            (Panel::Bookmarks, KeyCode::Char(c)) if self.bookmark_list.renaming() => {
+                Command::RenameBookmarkInput(Some(c))
+            }
+            (Panel::Bookmarks, KeyCode::Backspace) if self.bookmark_list.renaming() => {
+                Command::RenameBookmarkInput(None)
+            }
+            (Panel::Bookmarks, KeyCode::Char('d')) => Command::DeleteBookmark,
+            (Panel::Bookmarks, KeyCode::Char('r')) => Command::StartRenameBookmark,
+            (Panel::Queue, KeyCode::Char('d')) => Command::RemoveFromQueue,
+            (Panel::Queue, KeyCode::Char('K')) => Command::MoveInQueue(-1),
+            (Panel::Queue, KeyCode::Char('J')) => Command::MoveInQueue(1),
+            (Panel::Queue, KeyCode::Char('n')) => Command::ClearUpcoming,
+            (Panel::Queue, KeyCode::Char('C')) => Command::ClearQueue,
+            (Panel::Queue, KeyCode::Char('D')) => Command::DedupeQueue,
+            (Panel::Equalizer, KeyCode::Left) => Command::EditEq(EqEdit::SelectBand(-1)),
+            (Panel::Equalizer, KeyCode::Right) => Command::EditEq(EqEdit::SelectBand(1)),
+            (Panel::Equalizer, KeyCode::Up) => Command::EditEq(EqEdit::Gain(1.0)),
+            (Panel::Equalizer, KeyCode::Down) => Command::EditEq(EqEdit::Gain(-1.0)),
+            // a sixth of an octave per step
+            (Panel::Equalizer, KeyCode::Char('[')) => {
+                Command::EditEq(EqEdit::Frequency(2f32.powf(-1.0 / 6.0)))
+            }
+            (Panel::Equalizer, KeyCode::Char(']')) => {
+                Command::EditEq(EqEdit::Frequency(2f32.powf(1.0 / 6.0)))
+            }
+            (Panel::Equalizer, KeyCode::Char('{')) => Command::EditEq(EqEdit::Q(1.0 / 1.25)),
+            (Panel::Equalizer, KeyCode::Char('}')) => Command::EditEq(EqEdit::Q(1.25)),
+            (Panel::Equalizer, KeyCode::Char('t')) => Command::EditEq(EqEdit::CycleKind),
+            (Panel::Equalizer, KeyCode::Char('a')) => Command::EditEq(EqEdit::AddBand),
+            (Panel::Equalizer, KeyCode::Char('d')) => Command::EditEq(EqEdit::RemoveBand),
+            (Panel::Equalizer, KeyCode::Tab) => Command::EditEq(EqEdit::NextPreset),
+            (Panel::Equalizer, KeyCode::Char('w')) => Command::SaveConfig,
             (Panel::Search, KeyCode::Char(c)) => Command::SearchInput(c),
             (Panel::Search, KeyCode::Backspace) => Command::SearchBackspace,
             (_, KeyCode::Up) => Command::MoveCursor(Motion::Up),
@@ -292,6 +824,20 @@ impl App {
             (_, KeyCode::Enter) => Command::Activate,
             (_, KeyCode::Char(',')) => Command::Seek(-5),
             (_, KeyCode::Char('.')) => Command::Seek(5),
+            (_, KeyCode::Char('<')) => Command::ChangeSpeed(-0.1),
+            (_, KeyCode::Char('>')) => Command::ChangeSpeed(0.1),
+            (_, KeyCode::Char('=')) => Command::SetSpeed(1.0),
+            (_, KeyCode::Char('-')) => Command::ChangeVolume(-0.05),
+            (_, KeyCode::Char('+')) => Command::ChangeVolume(0.05),
+            (_, KeyCode::Char('S')) => Command::CycleShuffle,
+            (_, KeyCode::Char('k')) => Command::CycleStopAfter,
+            (_, KeyCode::Char('r')) => Command::AddRepeat,
+            (_, KeyCode::Char('R')) => Command::SetRepeats(0),
+            (_, KeyCode::Char('Z')) => Command::CycleSleepTimer,
+            (_, KeyCode::Char('A')) => Command::ToggleRadio,
+            (_, KeyCode::Char('[')) => Command::SetLoopPoint(LoopPoint::Start),
+            (_, KeyCode::Char(']')) => Command::SetLoopPoint(LoopPoint::End),
+            (_, KeyCode::Char('\\')) => Command::ClearAbLoop,
             (_, KeyCode::Char('z')) => Command::PreviousOrSeekToStart,
             (_, KeyCode::Char('x')) => Command::PlayPause,
             (_, KeyCode::Char('c')) => Command::NextTrack,
@@ -303,24 +849,70 @@ impl App {
 
     async fn dispatch(&mut self, message: Message) -> Result<()> {
         use Message::*;
-        let old_track = self.player.read().await.current();
+        let (old_track, old_timestamp) = {
+            let player = self.player.read().await;
+            (player.current(), player.timestamp())
+        };
+        let finished = matches!(message, Player(PlayerMessage::Finished));
+        let failed = matches!(message, Player(PlayerMessage::Error { .. }));
         match message {
             Command(command) => {
                 self.dispatch_command(command).await?;
             }
 
-            Player(PlayerMessage::AudioFragment { buffer, timestamp }) => {
-                self.player.write().await.set_timestamp(Some(timestamp));
-                self.visualizer.update_spectrum(buffer)?;
-            }
             Player(PlayerMessage::Finished) => {
-                self.dispatch_command(self::Command::NextTrack).await?;
+                self.fill_radio().await;
+                self.player.write().await.advance().await?;
+                if self.sleep_timer.as_mut().map_or(false, SleepTimer::track_finished) {
+                    self.player.write().await.pause();
+                    self.set_sleep_timer(None).await;
+                }
+                self.visualizer.reset()?;
+            }
+            Player(PlayerMessage::Error { track, error }) => {
+                self.report_error(Some(Arc::clone(&track)), &error);
+                self.fill_radio().await;
+                let mut player = self.player.write().await;
+                // By the time we hear about it, the user might've moved on by themselves.
+                if player.current() == Some(track) {
+                    self.failures_in_a_row += 1;
+                    if self.failures_in_a_row >= player.queue().len() {
+                        // Everything's failed, so going round again won't help.
+                        player.stop().await;
+                    } else {
+                        player.skip_unplayable().await?;
+                    }
+                }
+                drop(player);
+                self.visualizer.reset()?;
+            }
+            Player(PlayerMessage::OutputLost) => {
+                if let Err(e) = self.player.write().await.reopen_output() {
+                    // We'll try again the next time a track starts.
+                    error!("Couldn't reopen audio output: {e}");
+                }
             }
         }
         let new_track = self.player.read().await.current();
         // Check if the track changed; if so, update the theme.
+        // Tracks that couldn't be played don't count as having been listened to.
+        let changed = finished || (old_track != new_track && !failed);
+        if let Some(track) = old_track.as_ref().filter(|_| changed) {
+            let end = if finished {
+                PlayEnd::Completed
+            } else {
+                PlayEnd::Skipped
+            };
+            self.record_play(track, old_timestamp, end).await;
+        }
         if old_track != new_track {
+            if let (Some(track), Some(position)) = (old_track, old_timestamp) {
+                self.remember_position_in(&track, position);
+            }
             self.on_track_change(new_track.as_deref()).await?;
+        } else if finished {
+            // The track is on repeat, so this is a new play of it.
+            self.track_started = new_track.map(|_| SystemTime::now());
         }
         Ok(())
     }
@@ -330,20 +922,154 @@ impl App {
         match command {
             Cancel => match self.active_panel {
                 Panel::Library => (),
-                Panel::Search => self.active_panel = Panel::Library,
+                Panel::Bookmarks if self.bookmark_list.renaming() => {
+                    self.bookmark_list.finish_rename();
+                }
+                Panel::Search
+                | Panel::Devices
+                | Panel::Equalizer
+                | Panel::Bookmarks
+                | Panel::Queue
+                | Panel::History
+                | Panel::Errors
+                | Panel::Favorites => self.active_panel = Panel::Library,
             },
             StartSearch => {
                 self.active_panel = Panel::Search;
                 self.search = Search::default();
             }
+            PickOutputDevice => {
+                let player = self.player.read().await;
+                self.device_picker = DevicePicker::new(output_devices(), player.output_device());
+                self.active_panel = Panel::Devices;
+            }
+            OpenEqualizer => {
+                self.eq_editor = EqEditor::default();
+                self.active_panel = Panel::Equalizer;
+            }
+            EditEq(edit) => self.edit_eq(edit).await,
+            SaveConfig => {
+                if let Err(e) = self.config.save() {
+                    error!("Couldn't save config: {e}");
+                }
+            }
+            AddBookmark => {
+                let player = self.player.read().await;
+                let (Some(track), Some(position)) = (player.current(), player.timestamp()) else {
+                    return Ok(());
+                };
+                let title = track.title.as_deref().unwrap_or("<unknown>");
+                let index = self.bookmarks.add(Bookmark {
+                    name: format!("{title} @ {}", format_position(position)),
+                    path: track.path.clone(),
+                    position,
+                });
+                self.save_bookmarks();
+                self.bookmark_list =
+                    BookmarkList::new(self.bookmarks.bookmarks().len(), Some(index));
+                self.bookmark_list.start_rename(&self.bookmarks.bookmarks()[index].name);
+                self.active_panel = Panel::Bookmarks;
+            }
+            OpenBookmarks => {
+                self.bookmark_list = BookmarkList::new(self.bookmarks.bookmarks().len(), None);
+                self.active_panel = Panel::Bookmarks;
+            }
+            DeleteBookmark => {
+                if let Some(index) = self.bookmark_list.selected() {
+                    self.bookmarks.remove(index);
+                    self.bookmark_list.on_remove(self.bookmarks.bookmarks().len());
+                    self.save_bookmarks();
+                }
+            }
+            StartRenameBookmark => {
+                let selected = self.bookmark_list.selected();
+                if let Some(bookmark) = selected.and_then(|i| self.bookmarks.bookmarks().get(i)) {
+                    self.bookmark_list.start_rename(&bookmark.name);
+                }
+            }
+            RenameBookmarkInput(c) => self.bookmark_list.rename_input(c),
+            OpenQueue => {
+                let player = self.player.read().await;
+                self.queue_list = QueueList::new(player.queue().len(), player.queue().current());
+                self.active_panel = Panel::Queue;
+            }
+            OpenFavorites => {
+                let ratings = self.ratings.read().await;
+                let loved: HashSet<&Path> = ratings.loved().collect();
+                let tracks = self
+                    .library
+                    .tracks()
+                    .filter(|track| loved.contains(track.path.as_path()))
+                    .sorted_by_key(|track| {
+                        (track.artist.clone(), track.album.clone(), track.number)
+                    })
+                    .map(TrackListItem::Track)
+                    .collect();
+                self.favorites = TrackList::new(tracks).with_title("Favorites");
+                self.favorites.move_selection(1);
+                self.active_panel = Panel::Favorites;
+            }
+            Rate(stars) => self.rate(stars).await,
+            ToggleLoved => {
+                let Some(RatingTarget::Track(track)) = self.rating_target() else {
+                    return Ok(());
+                };
+                let mut ratings = self.ratings.write().await;
+                ratings.toggle_loved(&track.path);
+                if let Err(e) = ratings.save() {
+                    error!("Couldn't save ratings: {e}");
+                }
+            }
+            OpenHistory => {
+                self.history_list = HistoryList::new(&self.history, &self.library);
+                self.active_panel = Panel::History;
+            }
+            OpenErrors => {
+                self.error_list = ErrorList::new(&self.errors);
+                self.active_panel = Panel::Errors;
+            }
+            RemoveFromQueue => {
+                let Some(index) = self.queue_list.selected() else {
+                    return Ok(());
+                };
+                let mut player = self.player.write().await;
+                player.queue_remove(index).await?;
+                self.queue_list.on_resize(player.queue().len());
+            }
+            MoveInQueue(delta) => {
+                let Some(from) = self.queue_list.selected() else {
+                    return Ok(());
+                };
+                let mut player = self.player.write().await;
+                let len = player.queue().len();
+                let to = from.saturating_add_signed(delta).min(len.saturating_sub(1));
+                player.queue_move(from, to);
+                self.queue_list.select(to);
+            }
+            ClearQueue => {
+                self.player.write().await.queue_clear().await;
+                self.queue_list.on_resize(0);
+            }
+            ClearUpcoming => {
+                let mut player = self.player.write().await;
+                player.queue_clear_upcoming();
+                self.queue_list.on_resize(player.queue().len());
+            }
+            DedupeQueue => {
+                let mut player = self.player.write().await;
+                let removed = player.queue_dedupe();
+                debug!("Removed {removed} repeated tracks from the queue");
+                self.queue_list.on_resize(player.queue().len());
+            }
             SearchInput(c) => {
-                self.search.run_query(&self.library, format!("{}{}", self.search.query(), c))?;
+                let query = format!("{}{}", self.search.query(), c);
+                self.search.run_query(&self.library, &*self.stats.read().await, query)?;
             }
             SearchBackspace => {
                 let mut chars = self.search.query().chars();
                 chars.next_back();
                 let query = chars.as_str().to_owned();
-                self.search.run_query(&self.library, query)?;
+                self.search.run_query(&self.library, &*self.stats.read().await, query)?;
             }
             Activate => {
                 self.activate_item().await?;
@@ -356,11 +1082,36 @@ impl App {
                 };
                 match self.active_panel {
                     Panel::Library => {
-                        self.library_panel.move_selection(&self.library, delta)?;
+                        let (stats, ratings) = (self.stats.read().await, self.ratings.read().await);
+                        let info = TrackInfo {
+                            stats: &stats,
+                            ratings: &ratings,
+                        };
+                        self.library_panel.move_selection(&self.library, info, delta)?;
                     }
                     Panel::Search => self.search.move_cursor(delta),
+                    Panel::Devices => self.device_picker.move_cursor(delta),
+                    Panel::Equalizer => (),
+                    Panel::Bookmarks => {
+                        self.bookmark_list.move_cursor(self.bookmarks.bookmarks().len(), delta)
+                    }
+                    Panel::Queue => {
+                        let len = self.player.read().await.queue().len();
+                        self.queue_list.move_cursor(len, delta)
+                    }
+                    Panel::History => self.history_list.move_cursor(delta),
+                    Panel::Errors => self.error_list.move_cursor(delta),
+                    Panel::Favorites => self.favorites.move_selection(delta),
                 }
             }
+            CycleTrackSort => {
+                let (stats, ratings) = (self.stats.read().await, self.ratings.read().await);
+                let info = TrackInfo {
+                    stats: &stats,
+                    ratings: &ratings,
+                };
+                self.library_panel.cycle_sort(&self.library, info)?;
+            }
             NextFocus => self.library_panel.focus = self.library_panel.focus.next(),
             Seek(seconds) => {
                 let mut player = self.player.write().await;
@@ -389,40 +1140,152 @@ impl App {
                 }
                 self.visualizer.reset()?;
             }
+            AddTrackAfter { path, after, play } => {
+                let track = self
+                    .library
+                    .tracks()
+                    .find(|track| track.path == path)
+                    .ok_or_else(|| eyre!("{} isn't in the library", path.display()))?;
+                let mut player = self.player.write().await;
+                let index = if after == TrackId::NO_TRACK {
+                    0
+                } else {
+                    queue_position(player.queue(), &after)
+                        .ok_or_else(|| eyre!("no track {} in the queue", after.as_str()))?
+                        + 1
+                };
+                player.queue_insert(index, track);
+                self.queue_list.on_resize(player.queue().len());
+                if play {
+                    player.set_queue_index(Some(index)).await?;
+                    player.play().await?;
+                }
+            }
+            RemoveTrackById(id) => {
+                let mut player = self.player.write().await;
+                let Some(index) = queue_position(player.queue(), &id) else {
+                    return Ok(());
+                };
+                player.queue_remove(index).await?;
+                self.queue_list.on_resize(player.queue().len());
+            }
+            GoToTrack(id) => {
+                let mut player = self.player.write().await;
+                let Some(index) = queue_position(player.queue(), &id) else {
+                    return Ok(());
+                };
+                player.set_queue_index(Some(index)).await?;
+                player.play().await?;
+            }
             SetLoopStatus(loop_status) => {
                 self.player.write().await.set_loop_status(loop_status);
             }
             SetShuffle(shuffle) => {
                 self.player.write().await.set_shuffle(shuffle);
             }
-            AddSongToQueue => {
-                let Some(selected) = self.library_panel.track_list.selected() else {
-                    return Ok(());
+            CycleShuffle => {
+                let mut player = self.player.write().await;
+                let queue = player.queue();
+                match (queue.shuffle(), queue.shuffle_mode()) {
+                    (false, _) => {
+                        player.set_shuffle_mode(ShuffleMode::default());
+                        player.set_shuffle(true);
+                    }
+                    (true, ShuffleMode::Weighted) => player.set_shuffle(false),
+                    (true, mode) => player.set_shuffle_mode(mode.next()),
+                }
+            }
+            CycleStopAfter => {
+                let mut player = self.player.write().await;
+                let stop_after = match player.queue().stop_after() {
+                    None => Some(StopAfter::Track),
+                    Some(StopAfter::Track) => Some(StopAfter::Album),
+                    Some(StopAfter::Album) => None,
                 };
-                self.player.write().await.queue_push(selected);
+                player.set_stop_after(stop_after);
+            }
+            AddRepeat => {
+                let mut player = self.player.write().await;
+                let repeats = player.queue().repeats_left() + 1;
+                player.set_repeats(repeats);
+            }
+            SetRepeats(repeats) => self.player.write().await.set_repeats(repeats),
+            CycleSleepTimer => {
+                let preset = match self.sleep_preset {
+                    None => Some(0),
+                    Some(i) => Some(i + 1).filter(|i| *i < SLEEP_PRESETS.len()),
+                };
+                let fade_out = self.config.sleep_fade_out;
+                let timer = preset.map(|i| match SLEEP_PRESETS[i] {
+                    Some(duration) => SleepTimer::after(duration, fade_out),
+                    None => SleepTimer::new(SleepAt::Tracks(1), fade_out),
+                });
+                self.set_sleep_timer(timer).await;
+                self.sleep_preset = preset;
+            }
+            ToggleRadio => self.radio = !self.radio,
+            SetLoopPoint(point) => self.player.write().await.set_loop_point(point)?,
+            NudgeLoopPoint(point, millis) => {
+                self.player.write().await.nudge_loop_point(point, millis)?;
+            }
+            ClearAbLoop => self.player.write().await.clear_ab_loop()?,
+            SetSpeed(speed) => self.player.write().await.set_speed(speed)?,
+            ChangeSpeed(delta) => {
+                let mut player = self.player.write().await;
+                // round so that repeated steps don't accumulate floating-point error
+                let speed = ((player.speed() + delta) * 100.0).round() / 100.0;
+                player.set_speed(speed)?;
+            }
+            SetVolume(volume) => {
+                if let Some(timer) = self.sleep_timer.as_mut() {
+                    timer.cancel_fade();
+                }
+                self.player.write().await.set_volume(volume);
             }
+            ChangeVolume(delta) => {
+                if let Some(timer) = self.sleep_timer.as_mut() {
+                    timer.cancel_fade();
+                }
+                let mut player = self.player.write().await;
+                let volume = ((player.volume() + delta) * 100.0).round() / 100.0;
+                player.set_volume(volume);
+            }
+            Enqueue(mode) => self.enqueue(mode).await?,
             Play => self.player.write().await.play().await?,
             PlayPause => {
                 let mut player = self.player.write().await;
-                if player.playing().await {
-                    player.pause().await;
+                if player.playing() {
+                    player.pause();
+                    drop(player);
+                    self.remember_position().await;
                 } else {
                     player.play().await?;
                 }
             }
-            Pause => self.player.write().await.pause().await,
+            Pause => {
+                self.player.write().await.pause();
+                self.remember_position().await;
+            }
             Stop => self.player.write().await.stop().await,
             PreviousOrSeekToStart => {
                 const MIN_DURATION_TO_SEEK: Duration = Duration::from_secs(5);
                 let mut player = self.player.write().await;
                 if player.timestamp().map_or(false, |dur| dur >= MIN_DURATION_TO_SEEK) {
                     player.seek(Duration::ZERO).await?;
+                } else if player.queue().current().is_some() && player.queue().previous().is_none()
+                {
+                    // We're at the start of the queue, so go back to whatever we played before it.
+                    match self.history_previous(player.queue()) {
+                        Some(track) => player.go_back_to(track).await?,
+                        None => player.previous().await?,
+                    }
                 } else {
                     player.previous().await?;
                 }
                 self.visualizer.reset()?;
             }
             NextTrack => {
+                self.fill_radio().await;
                 self.player.write().await.next().await?;
                 self.visualizer.reset()?;
             }
@@ -441,27 +1304,352 @@ impl App {
                         return Ok(());
                     };
                     let tracks = self.library_panel.track_list.tracks().collect_vec();
-                    let index = tracks.iter().find_position(|t| **t == selected).unwrap().0;
-                    let mut player = self.player.write().await;
-                    player.set_play_queue(tracks).await;
-                    player.set_queue_index(Some(index)).await?;
-                    player.play().await?;
-                    self.visualizer.reset()?;
+                    self.play_from(tracks, &selected).await?;
                 }
             },
+            Panel::Favorites => {
+                let Some(selected) = self.favorites.selected() else {
+                    return Ok(());
+                };
+                self.play_from(self.favorites.tracks().collect_vec(), &selected).await?;
+            }
             Panel::Search => {
                 let Some(selected) = self.search.selected_item() else {
                     return Ok(());
                 };
                 self.active_panel = Panel::Library;
-                self.library_panel.select_entity(&self.library, &selected)?;
+                let (stats, ratings) = (self.stats.read().await, self.ratings.read().await);
+                let info = TrackInfo {
+                    stats: &stats,
+                    ratings: &ratings,
+                };
+                self.library_panel.select_entity(&self.library, info, &selected)?;
+            }
+            Panel::Devices => {
+                let Some(device) = self.device_picker.selected() else {
+                    return Ok(());
+                };
+                self.active_panel = Panel::Library;
+                let id = device.id();
+                if let Err(e) = self.player.write().await.set_output_device(id.clone()) {
+                    error!("Couldn't switch output device: {e}");
+                }
+                self.config.set_output_device(id);
+                if let Err(e) = self.config.save() {
+                    error!("Couldn't save config: {e}");
+                }
             }
+            Panel::Equalizer => (),
+            Panel::Bookmarks => {
+                if self.bookmark_list.renaming() {
+                    let (Some(index), Some(name)) =
+                        (self.bookmark_list.selected(), self.bookmark_list.finish_rename())
+                    else {
+                        return Ok(());
+                    };
+                    self.bookmarks.rename(index, name);
+                    self.save_bookmarks();
+                } else {
+                    self.jump_to_bookmark().await?;
+                }
+            }
+            Panel::Queue => {
+                let mut player = self.player.write().await;
+                if let Some(index) = self.queue_list.selected() {
+                    player.set_queue_index(Some(index)).await?;
+                    player.play().await?;
+                }
+            }
+            Panel::History | Panel::Errors => self.enqueue(Enqueue::Now).await?,
         }
         Ok(())
     }
 
+    /// The tracks that are selected in the current panel: a single track, or everything on the
+    /// selected album or by the selected artist.
+    fn selected_tracks(&self) -> Vec<Arc<Track>> {
+        match self.active_panel {
+            Panel::Library => match self.library_panel.focus {
+                PanelItem::ArtistAlbumList => {
+                    let list = &self.library_panel.artist_album_list;
+                    match (list.artist(), list.album()) {
+                        (Some(artist), Some(album)) => self.library.album_tracks(&artist, &album),
+                        (Some(artist), None) => self.library.artist_tracks(&artist),
+                        (None, _) => vec![],
+                    }
+                }
+                PanelItem::TrackList => {
+                    self.library_panel.track_list.selected().into_iter().collect()
+                }
+            },
+            Panel::Search => self
+                .search
+                .selected_item()
+                .map_or_else(Vec::new, |item| item.tracks(&self.library)),
+            Panel::History => self.history_list.selected().into_iter().collect(),
+            Panel::Favorites => self.favorites.selected().into_iter().collect(),
+            Panel::Errors => self.error_list.selected().into_iter().collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Replaces the play queue with `tracks` and starts playing `selected`, which must be one of
+    /// them.
+    async fn play_from(&mut self, tracks: Vec<Arc<Track>>, selected: &Track) -> Result<()> {
+        let index = tracks.iter().position(|t| **t == *selected).unwrap();
+        let mut player = self.player.write().await;
+        player.set_play_queue(tracks).await;
+        player.set_queue_index(Some(index)).await?;
+        player.play().await?;
+        self.visualizer.reset()?;
+        Ok(())
+    }
+
+    /// The track or album that rating commands apply to in the current panel.
+    fn rating_target(&self) -> Option<RatingTarget> {
+        let track = match self.active_panel {
+            Panel::Library => match self.library_panel.focus {
+                PanelItem::ArtistAlbumList => {
+                    let list = &self.library_panel.artist_album_list;
+                    return Some(RatingTarget::Album(list.artist()?, list.album()?));
+                }
+                PanelItem::TrackList => self.library_panel.track_list.selected(),
+            },
+            Panel::Search => match self.search.selected_item()? {
+                SearchItem::Artist(_) => None,
+                SearchItem::Album(album, artist) => {
+                    return Some(RatingTarget::Album(artist, album))
+                }
+                SearchItem::Track(track) => Some(track),
+            },
+            Panel::History => self.history_list.selected(),
+            Panel::Favorites => self.favorites.selected(),
+            _ => None,
+        };
+        track.map(RatingTarget::Track)
+    }
+
+    async fn rate(&mut self, stars: Option<u8>) {
+        let Some(target) = self.rating_target() else {
+            return;
+        };
+        if let RatingTarget::Track(track) = &target {
+            if self.config.rating_tags {
+                if let Err(e) = ratings::write_tag_stars(&track.path, stars) {
+                    error!("Couldn't write rating to {}: {e}", track.path.display());
+                }
+            }
+        }
+        let mut ratings = self.ratings.write().await;
+        match target {
+            RatingTarget::Track(track) => ratings.set_stars(&track.path, stars),
+            RatingTarget::Album(artist, album) => ratings.set_album_stars(&artist, &album, stars),
+        }
+        if let Err(e) = ratings.save() {
+            error!("Couldn't save ratings: {e}");
+        }
+    }
+
+    /// If we're reading ratings from tags and `track` isn't rated yet, picks up its rating from
+    /// its tags.
+    async fn import_tag_rating(&mut self, track: &Track) {
+        if !self.config.rating_tags || self.ratings.read().await.track(&track.path).stars.is_some()
+        {
+            return;
+        }
+        // Reading tags touches the disk, so don't hold the ratings lock meanwhile.
+        match ratings::read_tag_stars(&track.path) {
+            Ok(None) => (),
+            Ok(stars) => {
+                let mut ratings = self.ratings.write().await;
+                ratings.set_stars(&track.path, stars);
+                if let Err(e) = ratings.save() {
+                    error!("Couldn't save ratings: {e}");
+                }
+            }
+            Err(e) => error!("Couldn't read rating from {}: {e}", track.path.display()),
+        }
+    }
+
+    async fn enqueue(&mut self, mode: Enqueue) -> Result<()> {
+        let tracks = self.selected_tracks();
+        if tracks.is_empty() {
+            return Ok(());
+        }
+        let mut player = self.player.write().await;
+        match mode {
+            Enqueue::Now => {
+                player.set_play_queue(tracks).await;
+                player.set_queue_index(Some(0)).await?;
+                player.play().await?;
+                self.visualizer.reset()?;
+            }
+            // Insert backwards so that they end up in order.
+            Enqueue::Next => tracks.into_iter().rev().for_each(|t| player.queue_insert_next(t)),
+            Enqueue::Append => tracks.into_iter().for_each(|t| player.queue_push(t)),
+        }
+        Ok(())
+    }
+
+    /// Plays the selected bookmark's track from the bookmarked position. The rest of its album is
+    /// queued up after it.
+    async fn jump_to_bookmark(&mut self) -> Result<()> {
+        let selected = self.bookmark_list.selected();
+        let Some(bookmark) = selected.and_then(|i| self.bookmarks.bookmarks().get(i)).cloned()
+        else {
+            return Ok(());
+        };
+        let Some(track) = self.library.tracks().find(|t| t.path == bookmark.path) else {
+            error!("Bookmarked track {} isn't in the library", bookmark.path.display());
+            return Ok(());
+        };
+        self.active_panel = Panel::Library;
+        let mut player = self.player.write().await;
+        if player.current().as_ref() == Some(&track) {
+            player.seek(bookmark.position).await?;
+            return player.play().await;
+        }
+        let tracks = self
+            .library
+            .artists
+            .get(&track.artist)
+            .and_then(|artist| artist.albums.get(&track.album))
+            .map_or_else(|| vec![Arc::clone(&track)], |album| album.tracks.clone());
+        let index = tracks.iter().position(|t| *t == track).unwrap_or(0);
+        player.set_play_queue(tracks).await;
+        player.set_queue_index(Some(index)).await?;
+        player.play().await?;
+        // This gets picked up by on_track_change.
+        self.start_at = Some(bookmark.position);
+        self.visualizer.reset()?;
+        Ok(())
+    }
+
+    /// Remembers the current position, if the current track is long enough for that.
+    async fn remember_position(&mut self) {
+        let (track, position) = {
+            let player = self.player.read().await;
+            (player.current(), player.timestamp())
+        };
+        if let (Some(track), Some(position)) = (track, position) {
+            self.remember_position_in(&track, position);
+        }
+    }
+
+    fn remember_position_in(&mut self, track: &Track, position: Duration) {
+        self.bookmarks.record_position(track, position, self.config.resume_threshold());
+        self.save_bookmarks();
+    }
+
+    /// Logs an error and shows it in the status bar. `track` is the track that couldn't be played,
+    /// if that's what went wrong.
+    fn report_error(&mut self, track: Option<Arc<Track>>, error: &Report) {
+        error!("{error:#}");
+        let logged = self.errors.push(track, error);
+        self.status = Some((logged.summary(), Instant::now()));
+    }
+
+    /// Adds a play of `track`, which got as far as `position`, to the history.
+    async fn record_play(&mut self, track: &Track, position: Option<Duration>, end: PlayEnd) {
+        let Some(started) = self.track_started.take() else {
+            return;
+        };
+        let listened = position.unwrap_or_default();
+        self.history.record(Play {
+            path: track.path.clone(),
+            started,
+            listened,
+            end,
+        });
+        let play_fraction = self.config.play_count_fraction;
+        self.stats.write().await.record(track, listened, end, started, play_fraction);
+    }
+
+    /// The track to go back to from the start of the queue: the most recently played track that
+    /// isn't coming up in the queue anyway.
+    fn history_previous(&self, queue: &PlayQueue) -> Option<Arc<Track>> {
+        let upcoming: HashSet<&Path> = queue.tracks()[queue.current().unwrap_or(0)..]
+            .iter()
+            .map(|track| track.path.as_path())
+            .collect();
+        let play = self.history.recent().find(|play| !upcoming.contains(play.path.as_path()))?;
+        self.library.tracks().find(|track| track.path == play.path)
+    }
+
+    fn save_bookmarks(&self) {
+        if let Err(e) = self.bookmarks.save() {
+            error!("Couldn't save bookmarks: {e}");
+        }
+    }
+
+    /// Picks the EQ preset for `track` according to the config, and sends it to the player.
+    async fn select_eq_preset(&mut self, track: Option<&Track>) {
+        let config = &self.config.equalizer;
+        let default = config.preset.as_deref().filter(|name| config.presets.contains_key(*name));
+        self.eq_preset = track.and_then(|t| config.preset_for(t)).or(default).map(str::to_owned);
+        self.apply_eq().await;
+    }
+
+    /// Sends the current EQ preset to the player.
+    async fn apply_eq(&self) {
+        let preset =
+            self.eq_preset.as_ref().and_then(|name| self.config.equalizer.presets.get(name));
+        if let Err(e) = self.player.read().await.set_equalizer(preset.cloned()) {
+            error!("Couldn't set EQ: {e}");
+        }
+    }
+
+    async fn edit_eq(&mut self, edit: EqEdit) {
+        let config = &mut self.config.equalizer;
+        match edit {
+            EqEdit::NextPreset => {
+                // Cycle through the presets in order, then through 'off'. This also changes the
+                // default, so that it sticks around when saved.
+                let mut names = config.presets.keys();
+                let next = match &self.eq_preset {
+                    Some(current) => names.skip_while(|name| *name != current).nth(1),
+                    None => names.next(),
+                };
+                self.eq_preset = next.cloned();
+                config.preset = self.eq_preset.clone();
+                self.eq_editor = EqEditor::default();
+            }
+            _ => {
+                if self.eq_preset.is_none() && edit == EqEdit::AddBand {
+                    // Start a new preset, since there's nothing to add the band to.
+                    let name = (1..)
+                        .map(|i| format!("custom {i}"))
+                        .find(|name| !config.presets.contains_key(name))
+                        .unwrap();
+                    config.presets.insert(name.clone(), Default::default());
+                    config.preset = Some(name.clone());
+                    self.eq_preset = Some(name);
+                }
+                let Some(preset) = self.eq_preset.as_ref().and_then(|n| config.presets.get_mut(n))
+                else {
+                    return;
+                };
+                self.eq_editor.edit(preset, edit);
+            }
+        }
+        self.apply_eq().await;
+    }
+
     async fn on_track_change(&mut self, track: Option<&Track>) -> Result<()> {
         self.album_art.set_track(track)?;
+        self.select_eq_preset(track).await;
+        self.track_started = track.map(|_| SystemTime::now());
+        let start_at = self.start_at.take();
+        if let Some(track) = track {
+            self.import_tag_rating(track).await;
+            let threshold = self.config.resume_threshold();
+            if let Some(position) = start_at.or(self.bookmarks.resume_position(track, threshold)) {
+                debug!("Starting {} at {position:?}", track.path.display());
+                if let Err(e) = self.player.write().await.seek(position).await {
+                    error!("Couldn't resume {}: {e}", track.path.display());
+                }
+            }
+        }
         self.ui.theme = match track.map(Theme::from_track) {
             Some(Ok(t)) => t,
             Some(Err(e)) => {
@@ -474,8 +1662,34 @@ impl App {
     }
 }
 
+/// Where the first track in the queue with the given MPRIS ID is.
+fn queue_position(queue: &PlayQueue, id: &TrackId) -> Option<usize> {
+    queue.tracks().iter().position(|track| track.mpris_id() == *id)
+}
+
+/// Maps a digit key to a number of stars. 0 clears the rating.
+fn rating_for_key(c: char) -> Option<u8> {
+    c.to_digit(10).filter(|d| *d > 0).map(|d| d as u8)
+}
+
+/// How much weighted shuffle favors a track. Higher-rated and loved tracks are favored, as are
+/// ones that haven't been played in a while. Unrated tracks count as three stars.
+fn shuffle_weight(stats: TrackStats, rating: TrackRating, now: SystemTime) -> f64 {
+    let mut weight = f64::from(rating.stars.unwrap_or(3));
+    if rating.loved {
+        weight *= 2.0;
+    }
+    // Anything not played in the last month (or ever) gets the full boost.
+    let days = stats
+        .last_played
+        .and_then(|last| now.duration_since(last).ok())
+        .map_or(30.0, |age| age.as_secs_f64() / 86400.0);
+    weight * (1.0 + days.min(30.0) / 10.0)
+}
+
 struct Bounds {
     panel: Rect,
+    status: Rect,
     now_playing: Rect,
     album_art: Rect,
     visualizer: Rect,
@@ -483,9 +1697,13 @@ struct Bounds {
 
 impl Bounds {
     fn new(area: Rect) -> Self {
-        let [main, visualizer] = Layout::default()
+        let [main, status, visualizer] = Layout::default()
             .direction(Direction::Vertical)
-            .constraints([Constraint::Min(1), Constraint::Length(4)])
+            .constraints([
+                Constraint::Min(1),
+                Constraint::Length(1),
+                Constraint::Length(4),
+            ])
             .splits(area);
         let [side, panel] = Layout::default()
             .direction(Direction::Horizontal)
@@ -501,6 +1719,7 @@ impl Bounds {
             .splits(side);
         Self {
             panel,
+            status,
             now_playing,
             visualizer,
             album_art,
@@ -511,7 +1730,8 @@ impl Bounds {
 enum AppEvent {
     Terminal(Event),
     Message(Message),
-    /// Sent every so often. This normally doesn't trigger anything in and of itself.
+    /// Sent `fps` times a second. Redraws for new audio only happen on ticks, so that a slow
+    /// terminal can't fall behind.
     Tick,
 }
 
@@ -519,8 +1739,9 @@ impl AppEvent {
     fn stream(
         terminal_events: impl Stream<Item = Event>,
         rx_message: Receiver<Message>,
+        fps: u32,
     ) -> impl Stream<Item = Self> {
-        let ticks = smol::stream::iter(std::iter::from_fn(|| Some(AppEvent::Tick)));
+        let ticks = Timer::interval(Duration::from_secs(1) / fps.max(1)).map(|_| AppEvent::Tick);
 
         Box::pin(rx_message)
             .map(AppEvent::Message)
@@ -0,0 +1,333 @@
+use std::f64::consts::PI;
+
+/// Number of input frames on either side of the output position that contribute to each output
+/// frame.
+const HALF_TAPS: usize = 16;
+const TAPS: usize = 2 * HALF_TAPS;
+/// Number of precomputed fractional offsets in the interpolation kernel. We linearly interpolate
+/// between adjacent phases, so this doesn't need to be huge.
+const PHASES: usize = 128;
+
+/// Converts interleaved samples from one channel layout and sample rate to another. Used so that
+/// we can play back tracks even if the output device doesn't support their native format.
+pub struct Converter {
+    channel_map: ChannelMap,
+    resampler: Option<Resampler>,
+    /// Output of the channel mapping step, reused between calls to avoid allocating.
+    scratch: Vec<f32>,
+}
+
+impl Converter {
+    pub fn new(
+        in_channels: usize,
+        in_sample_rate: u32,
+        out_channels: usize,
+        out_sample_rate: u32,
+    ) -> Self {
+        let resampler = (in_sample_rate != out_sample_rate)
+            .then(|| Resampler::new(out_channels, in_sample_rate, out_sample_rate));
+        Self {
+            channel_map: ChannelMap::new(in_channels, out_channels),
+            resampler,
+            scratch: vec![],
+        }
+    }
+
+    /// Converts `input`, appending the result to `output`. Because of resampling, the output may
+    /// lag behind the input by a few frames; call [`Converter::flush`] at the end of the stream.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        match self.resampler.as_mut() {
+            Some(resampler) => {
+                self.scratch.clear();
+                self.channel_map.apply(input, &mut self.scratch);
+                resampler.process(&self.scratch, output);
+            }
+            None => self.channel_map.apply(input, output),
+        }
+    }
+
+    /// Appends any frames still buffered inside the resampler to `output`. The converter can be
+    /// reused afterwards, as if it were freshly created.
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.flush(output);
+        }
+    }
+
+    /// Discards any buffered input, e.g. after a seek.
+    pub fn reset(&mut self) {
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reset();
+        }
+    }
+}
+
+/// How to turn frames with one channel count into frames with another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelMap {
+    /// Same channel count; pass the samples through.
+    Identity,
+    /// Copy the single input channel into every output channel.
+    FromMono { out: usize },
+    /// Average all the input channels together.
+    ToMono { channels: usize },
+    /// Standard ITU downmix of 5.1 (in symphonia's FL, FR, FC, LFE, RL, RR order) to stereo. The
+    /// LFE channel is dropped.
+    Surround51ToStereo,
+    /// Anything else. Input channels that don't exist in the output are dropped, and output
+    /// channels that don't exist in the input are silent.
+    Truncate { channels: usize, out: usize },
+}
+
+impl ChannelMap {
+    pub fn new(channels: usize, out: usize) -> Self {
+        match (channels, out) {
+            _ if channels == out => ChannelMap::Identity,
+            (1, _) => ChannelMap::FromMono { out },
+            (_, 1) => ChannelMap::ToMono { channels },
+            (6, 2) => ChannelMap::Surround51ToStereo,
+            _ => ChannelMap::Truncate { channels, out },
+        }
+    }
+
+    /// Maps the interleaved frames in `input`, appending them to `output`.
+    pub fn apply(&self, input: &[f32], output: &mut Vec<f32>) {
+        match *self {
+            ChannelMap::Identity => output.extend_from_slice(input),
+            ChannelMap::FromMono { out } => {
+                for sample in input {
+                    output.resize(output.len() + out, *sample);
+                }
+            }
+            ChannelMap::ToMono { channels } => output.extend(
+                input
+                    .chunks_exact(channels)
+                    .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+            ),
+            ChannelMap::Surround51ToStereo => {
+                // -3dB for the center and surround channels, then normalize so that a full-scale
+                // signal on every channel doesn't clip.
+                const MIX: f32 = std::f32::consts::FRAC_1_SQRT_2;
+                const NORM: f32 = 1.0 / (1.0 + 2.0 * MIX);
+                for frame in input.chunks_exact(6) {
+                    let [fl, fr, fc, _lfe, rl, rr] = frame.try_into().unwrap();
+                    output.push((fl + MIX * fc + MIX * rl) * NORM);
+                    output.push((fr + MIX * fc + MIX * rr) * NORM);
+                }
+            }
+            ChannelMap::Truncate { channels, out } => {
+                for frame in input.chunks_exact(channels) {
+                    output.extend((0..out).map(|i| frame.get(i).copied().unwrap_or(0.0)));
+                }
+            }
+        }
+    }
+}
+
+/// Sample rate converter using windowed sinc interpolation. Operates on interleaved samples with a
+/// fixed channel count.
+pub struct Resampler {
+    channels: usize,
+    /// Number of input frames we advance by for every output frame.
+    step: f64,
+    /// Position of the next output frame, in input frames relative to the start of `buffer`.
+    position: f64,
+    /// Interleaved input that we haven't fully consumed yet. Always contains at least
+    /// `HALF_TAPS - 1` frames of history before `position`.
+    buffer: Vec<f32>,
+    /// `PHASES + 1` rows of `TAPS` coefficients each. Row `p` is the kernel for an output position
+    /// that's `p / PHASES` of the way between two input frames.
+    kernel: Vec<[f32; TAPS]>,
+}
+
+impl Resampler {
+    pub fn new(channels: usize, in_sample_rate: u32, out_sample_rate: u32) -> Self {
+        let step = in_sample_rate as f64 / out_sample_rate as f64;
+        // When downsampling, lower the cutoff so that we don't alias. The extra factor gives the
+        // window's transition band some room.
+        let cutoff = 0.95 * step.recip().min(1.0);
+        let kernel = (0..=PHASES)
+            .map(|phase| {
+                let frac = phase as f64 / PHASES as f64;
+                let mut row = [0.0; TAPS];
+                for (tap, coeff) in row.iter_mut().enumerate() {
+                    let x = (tap as f64 + 1.0 - HALF_TAPS as f64) - frac;
+                    *coeff = cutoff * sinc(cutoff * x) * blackman(x / HALF_TAPS as f64);
+                }
+                // normalize so that DC passes through unchanged
+                let sum: f64 = row.iter().sum();
+                row.map(|coeff| (coeff / sum) as f32)
+            })
+            .collect();
+        let mut resampler = Self {
+            channels,
+            step,
+            position: 0.0,
+            buffer: vec![],
+            kernel,
+        };
+        resampler.reset();
+        resampler
+    }
+
+    /// Discards all buffered input.
+    pub fn reset(&mut self) {
+        // Prime the buffer with silence so that the first output frame lines up with the first
+        // input frame.
+        self.buffer.clear();
+        self.buffer.resize((HALF_TAPS - 1) * self.channels, 0.0);
+        self.position = (HALF_TAPS - 1) as f64;
+    }
+
+    /// Resamples `input`, appending the result to `output`.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        self.buffer.extend_from_slice(input);
+        let frames = self.buffer.len() / self.channels;
+        while self.position as usize + HALF_TAPS < frames {
+            let index = self.position as usize;
+            let coeffs = self.coefficients(self.position.fract());
+            let start = (index + 1 - HALF_TAPS) * self.channels;
+            let window = &self.buffer[start..start + TAPS * self.channels];
+            for channel in 0..self.channels {
+                output.push(
+                    window
+                        .iter()
+                        .skip(channel)
+                        .step_by(self.channels)
+                        .zip(coeffs.iter())
+                        .map(|(sample, coeff)| sample * coeff)
+                        .sum(),
+                );
+            }
+            self.position += self.step;
+        }
+
+        // Drop everything that's no longer within reach of the kernel.
+        let consumed = (self.position as usize + 1 - HALF_TAPS).min(frames);
+        self.buffer.drain(..consumed * self.channels);
+        self.position -= consumed as f64;
+    }
+
+    /// Pushes enough silence through to get all the buffered input out, then resets.
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        let frames = self.buffer.len() / self.channels;
+        // Stop once we've produced output up to the end of the real input.
+        let remaining = ((frames as f64 - self.position) / self.step).ceil().max(0.0) as usize;
+        let mut flushed = vec![];
+        self.process(&vec![0.0; (HALF_TAPS + 1) * self.channels], &mut flushed);
+        flushed.truncate(remaining * self.channels);
+        output.extend(flushed);
+        self.reset();
+    }
+
+    /// Kernel for an output position `frac` of the way between two input frames.
+    fn coefficients(&self, frac: f64) -> [f32; TAPS] {
+        let phase = frac * PHASES as f64;
+        let index = phase as usize;
+        let t = phase.fract() as f32;
+        let (a, b) = (&self.kernel[index], &self.kernel[index + 1]);
+        std::array::from_fn(|i| a[i] + (b[i] - a[i]) * t)
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Blackman window over `[-1, 1]`.
+fn blackman(x: f64) -> f64 {
+    if x.abs() > 1.0 {
+        return 0.0;
+    }
+    let x = PI * (x + 1.0);
+    0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f32, sample_rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (2.0 * std::f32::consts::PI * freq * i as f32 / sample_rate as f32).sin())
+            .collect()
+    }
+
+    /// Resamples `input` in chunks of `chunk` samples, flushing at the end.
+    fn resample(input: &[f32], from: u32, to: u32, chunk: usize) -> Vec<f32> {
+        let mut resampler = Resampler::new(1, from, to);
+        let mut output = vec![];
+        for chunk in input.chunks(chunk) {
+            resampler.process(chunk, &mut output);
+        }
+        resampler.flush(&mut output);
+        output
+    }
+
+    #[test]
+    fn resampled_length() {
+        let input = sine(440.0, 96000, 96000);
+        let output = resample(&input, 96000, 48000, 1024);
+        assert!(output.len().abs_diff(48000) <= 1, "got {} frames", output.len());
+        let output = resample(&input, 96000, 44100, 1000);
+        assert!(output.len().abs_diff(44100) <= 1, "got {} frames", output.len());
+    }
+
+    #[test]
+    fn resampling_preserves_sine() {
+        let input = sine(1000.0, 88200, 88200);
+        let output = resample(&input, 88200, 48000, 4096);
+        let expected = sine(1000.0, 48000, output.len());
+        // skip the edges, where the kernel hangs off the end of the input
+        let max_error = output[100..output.len() - 100]
+            .iter()
+            .zip(&expected[100..expected.len() - 100])
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max);
+        assert!(max_error < 0.01, "max error was {max_error}");
+    }
+
+    #[test]
+    fn chunking_does_not_matter() {
+        let input = sine(440.0, 44100, 10000);
+        let one = resample(&input, 44100, 48000, 1);
+        let many = resample(&input, 44100, 48000, 4096);
+        assert_eq!(one.len(), many.len());
+        assert!(one.iter().zip(&many).all(|(a, b)| (a - b).abs() < 1e-4));
+    }
+
+    #[test]
+    fn mono_to_stereo() {
+        let mut output = vec![];
+        ChannelMap::new(1, 2).apply(&[0.1, 0.2], &mut output);
+        assert_eq!(output, vec![0.1, 0.1, 0.2, 0.2]);
+    }
+
+    #[test]
+    fn surround_to_stereo() {
+        let map = ChannelMap::new(6, 2);
+        assert_eq!(map, ChannelMap::Surround51ToStereo);
+        let mut output = vec![];
+        // center and LFE only
+        map.apply(&[0.0, 0.0, 1.0, 1.0, 0.0, 0.0], &mut output);
+        assert_eq!(output.len(), 2);
+        assert_eq!(output[0], output[1], "center channel should be split evenly");
+        let mut output = vec![];
+        // everything at full scale shouldn't clip
+        map.apply(&[1.0; 6], &mut output);
+        assert!(output.iter().all(|sample| *sample <= 1.0 + f32::EPSILON));
+    }
+
+    #[test]
+    fn converter_identity() {
+        let mut converter = Converter::new(2, 44100, 2, 44100);
+        let mut output = vec![];
+        converter.process(&[0.5, -0.5], &mut output);
+        converter.flush(&mut output);
+        assert_eq!(output, vec![0.5, -0.5]);
+    }
+}
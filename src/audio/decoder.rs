@@ -0,0 +1,558 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, TryRecvError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use eyre::{eyre, Report, Result};
+use log::warn;
+use symphonia::core::audio::{SampleBuffer, Signal};
+
+use super::{
+    convert::Converter,
+    eq::{EqPreset, Equalizer},
+    fade::Fader,
+    reader::SymphoniaReader,
+    ring_buffer::{Consumer, Producer},
+    silence::{SilenceSettings, SilenceSkipper},
+    stretch::TimeStretch,
+    tap::AudioTap,
+    timeline::{OutputClock, Timeline},
+};
+
+/// How long the decode thread sleeps when the ring buffer is full or it's waiting for the output
+/// to drain.
+const IDLE_WAIT: Duration = Duration::from_millis(5);
+
+/// Called with the error that cut the track short, if any.
+pub(super) type FinishCallback = Box<dyn FnOnce(Option<Report>) + Send + 'static>;
+
+/// Decodes a reader's packets and converts them to the output format, invoking a callback once the
+/// track has been fully played.
+pub(super) struct Source {
+    reader: SymphoniaReader,
+    converter: Converter,
+    /// Timestamp of the end of the most recently decoded packet.
+    position: Duration,
+    /// Start and end of the part of the track to loop over. Once decoding passes the end, we go
+    /// back to the start.
+    ab_loop: Option<(Duration, Duration)>,
+    /// Set when decoding goes back to the start of the loop, until the decode thread notices.
+    looped: bool,
+    /// Why the reader gave up early, once it has.
+    error: Option<Report>,
+    on_finish: Option<FinishCallback>,
+}
+
+impl Source {
+    /// Creates a source whose output format matches the reader's. Use
+    /// [`Source::set_output_format`] to change that.
+    pub fn new(reader: SymphoniaReader, on_finish: FinishCallback) -> Self {
+        let converter = Converter::new(
+            reader.channels(),
+            reader.sample_rate(),
+            reader.channels(),
+            reader.sample_rate(),
+        );
+        Self {
+            reader,
+            converter,
+            position: Duration::ZERO,
+            ab_loop: None,
+            looped: false,
+            error: None,
+            on_finish: Some(on_finish),
+        }
+    }
+
+    /// Changes the channel count and sample rate of the samples we produce.
+    pub fn set_output_format(&mut self, channels: usize, sample_rate: u32) {
+        self.converter = Converter::new(
+            self.reader.channels(),
+            self.reader.sample_rate(),
+            channels,
+            sample_rate,
+        );
+    }
+
+    fn seek(&mut self, target: Duration) -> Result<()> {
+        self.reader.seek(target)?;
+        self.converter.reset();
+        self.position = target;
+        Ok(())
+    }
+
+    /// Decodes the next packet, appending the converted samples to `out`. Returns false if there's
+    /// nothing left.
+    fn decode(&mut self, out: &mut Vec<f32>) -> bool {
+        let Some(mut fragment) = self.reader.next() else {
+            self.error = self.reader.take_error();
+            // Get out whatever the converter was holding on to.
+            self.converter.flush(out);
+            return false;
+        };
+        let loop_start = match self.ab_loop {
+            Some((start, end)) if fragment.timestamp >= end => {
+                // Cut off whatever's past the end of the loop.
+                let over = (fragment.timestamp - end).as_secs_f64();
+                let over = (over * f64::from(self.reader.sample_rate())).round() as usize;
+                let frames = fragment.buffer.frames();
+                fragment.buffer.truncate(frames.saturating_sub(over));
+                fragment.timestamp = end;
+                Some(start)
+            }
+            _ => None,
+        };
+        if fragment.buffer.frames() > 0 {
+            let buffer = &fragment.buffer;
+            let mut samples = SampleBuffer::new(buffer.capacity() as u64, *buffer.spec());
+            samples.copy_interleaved_typed(buffer);
+            self.position = fragment.timestamp;
+            self.converter.process(samples.samples(), out);
+        }
+        if let Some(start) = loop_start {
+            // Unlike a normal seek, we keep the converter's state, since the audio from the start
+            // of the loop is meant to follow straight on from the end.
+            match self.reader.seek(start) {
+                Ok(()) => {
+                    self.position = start;
+                    self.looped = true;
+                }
+                Err(e) => {
+                    warn!("Couldn't go back to the start of the loop: {e}");
+                    self.ab_loop = None;
+                }
+            }
+        }
+        true
+    }
+
+    fn finish(&mut self) {
+        if let Some(f) = self.on_finish.take() {
+            f(self.error.take())
+        }
+    }
+}
+
+/// State shared between the [`Player`](super::Player), the decode thread, and the output
+/// callback. Everything the output callback touches is atomic, since it can't block.
+#[derive(Debug)]
+pub(super) struct PlaybackState {
+    pub paused: AtomicBool,
+    /// Bits of an `f32` that every sample is multiplied by. Applied at output time so that
+    /// changes are heard immediately.
+    pub volume: AtomicU32,
+    /// How long a fade in or out takes, in milliseconds. See [`Fader`].
+    pub fade_millis: AtomicU32,
+    /// True while the decoder has a track that it's still decoding. If the output runs dry while
+    /// this is set, that's an underrun.
+    pub decoding: AtomicBool,
+    pub underruns: AtomicU64,
+    pub underrun_samples: AtomicU64,
+    /// How many times decoding has gone back to the start of an A-B loop.
+    pub loops: AtomicU64,
+    /// Which sample in the ring buffer is being heard.
+    pub clock: OutputClock,
+    /// Where the samples in the ring buffer are in the track.
+    pub timeline: Timeline,
+    /// What's been written to the ring buffer, for visualizing.
+    pub tap: AudioTap,
+}
+
+impl Default for PlaybackState {
+    fn default() -> Self {
+        Self {
+            paused: AtomicBool::new(false),
+            volume: AtomicU32::new(1f32.to_bits()),
+            fade_millis: AtomicU32::new(0),
+            decoding: AtomicBool::new(false),
+            underruns: AtomicU64::new(0),
+            underrun_samples: AtomicU64::new(0),
+            loops: AtomicU64::new(0),
+            clock: OutputClock::default(),
+            timeline: Timeline::default(),
+            tap: AudioTap::default(),
+        }
+    }
+}
+
+impl PlaybackState {
+    /// Fills `data` from `consumer`, or with silence if we're paused or the decoder can't keep up.
+    /// `fader` smooths over pausing, resuming, and jumps in the audio, and `latency` is how long
+    /// until `data` is heard. Called from the output callback, so this must not block. Returns the
+    /// number of samples that weren't silence padding.
+    pub fn fill(
+        &self,
+        consumer: &mut Consumer,
+        fader: &mut Fader,
+        data: &mut [f32],
+        latency: Duration,
+    ) -> usize {
+        let fade_millis = self.fade_millis.load(Ordering::Relaxed);
+        if consumer.has_discarded() {
+            fader.cut();
+        }
+        let playing = !self.paused.load(Ordering::Relaxed);
+        // When pausing, keep playing just long enough to fade out.
+        let wanted = if playing {
+            data.len()
+        } else {
+            fader.samples_until_silent(fade_millis).min(data.len())
+        };
+        // Even if we're not reading anything, this frees up the room the old samples were taking
+        // after seeking while paused.
+        let (from, count) = consumer.pop(&mut data[..wanted]);
+        self.clock.update(from, count, latency);
+        data[count..].fill(0.0);
+        fader.apply(data, playing, fade_millis);
+        let volume = f32::from_bits(self.volume.load(Ordering::Relaxed));
+        if volume != 1.0 {
+            data.iter_mut().for_each(|sample| *sample *= volume);
+        }
+        if playing && count < data.len() && self.decoding.load(Ordering::Relaxed) {
+            self.underruns.fetch_add(1, Ordering::Relaxed);
+            self.underrun_samples.fetch_add((data.len() - count) as u64, Ordering::Relaxed);
+        }
+        count
+    }
+}
+
+enum Command {
+    /// Start decoding the given source, throwing away anything buffered from the previous one.
+    Load(Box<Source>),
+    /// Stop decoding and throw away anything buffered.
+    Stop,
+    Seek(Duration, smol::channel::Sender<Result<()>>),
+    /// Write to a new ring buffer, converting to the given channel count and sample rate.
+    SetOutput {
+        producer: Producer,
+        channels: usize,
+        sample_rate: u32,
+    },
+    /// Filter everything through the given EQ preset, or turn the EQ off if `None`.
+    SetEqualizer(Option<EqPreset>),
+    SetSilence(SilenceSettings),
+    /// Play back at the given multiple of normal speed.
+    SetSpeed(f64),
+    /// Loop between the given start and end of the current track, or stop looping if `None`.
+    SetAbLoop(Option<(Duration, Duration)>),
+}
+
+/// Handle to a thread that decodes audio into the ring buffer that the output callback reads from.
+/// The thread exits when this is dropped.
+pub(super) struct Decoder {
+    tx: mpsc::Sender<Command>,
+}
+
+impl Decoder {
+    pub fn spawn(state: Arc<PlaybackState>) -> Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("decoder".into())
+            .spawn(move || DecodeThread::new(rx, state).run())?;
+        Ok(Self { tx })
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        self.tx.send(command).map_err(|_| eyre!("decode thread died"))
+    }
+
+    pub fn load(&self, source: Source) -> Result<()> {
+        self.send(Command::Load(Box::new(source)))
+    }
+
+    pub fn stop(&self) -> Result<()> {
+        self.send(Command::Stop)
+    }
+
+    pub async fn seek(&self, target: Duration) -> Result<()> {
+        let (tx, rx) = smol::channel::bounded(1);
+        self.send(Command::Seek(target, tx))?;
+        rx.recv().await?
+    }
+
+    pub fn set_output(&self, producer: Producer, channels: usize, sample_rate: u32) -> Result<()> {
+        self.send(Command::SetOutput {
+            producer,
+            channels,
+            sample_rate,
+        })
+    }
+
+    /// Changes the EQ. Since this happens at decode time, it takes as long as the ring buffer is
+    /// to be heard.
+    pub fn set_equalizer(&self, preset: Option<EqPreset>) -> Result<()> {
+        self.send(Command::SetEqualizer(preset))
+    }
+
+    /// Changes which silences get skipped. Like the EQ, this takes as long as the ring buffer to
+    /// be heard.
+    pub fn set_silence(&self, settings: SilenceSettings) -> Result<()> {
+        self.send(Command::SetSilence(settings))
+    }
+
+    /// Changes the playback speed. Like the EQ, this takes as long as the ring buffer to be heard.
+    pub fn set_speed(&self, speed: f64) -> Result<()> {
+        self.send(Command::SetSpeed(speed))
+    }
+
+    /// Loops part of the current track. This is forgotten when a new track is loaded.
+    pub fn set_ab_loop(&self, region: Option<(Duration, Duration)>) -> Result<()> {
+        self.send(Command::SetAbLoop(region))
+    }
+}
+
+struct DecodeThread {
+    rx: Receiver<Command>,
+    state: Arc<PlaybackState>,
+    source: Option<Source>,
+    /// True once `source` has run out of packets; we're just waiting for the output to play what's
+    /// left.
+    exhausted: bool,
+    producer: Option<Producer>,
+    format: Option<(usize, u32)>,
+    /// Converted samples that haven't fit into the ring buffer yet.
+    pending: Vec<f32>,
+    /// Samples from the source that haven't had silence skipped yet, and ones that haven't been
+    /// stretched yet. Only used inside `step`; these are just here to avoid reallocating.
+    undecoded: Vec<f32>,
+    decoded: Vec<f32>,
+    /// Changes the speed of samples after they've been converted to the output format.
+    stretch: TimeStretch,
+    /// Applied to samples after they've been stretched.
+    equalizer: Equalizer,
+    /// Drops silence from decoded samples before they're stretched.
+    silence: SilenceSkipper,
+}
+
+impl DecodeThread {
+    fn new(rx: Receiver<Command>, state: Arc<PlaybackState>) -> Self {
+        Self {
+            rx,
+            state,
+            source: None,
+            exhausted: false,
+            producer: None,
+            format: None,
+            pending: vec![],
+            undecoded: vec![],
+            decoded: vec![],
+            // The format doesn't matter, since we'll get a real one before we decode anything.
+            stretch: TimeStretch::new(2, 44100),
+            equalizer: Equalizer::new(2, 44100),
+            silence: SilenceSkipper::new(2, 44100),
+        }
+    }
+
+    fn run(mut self) {
+        loop {
+            let command = if self.has_work() {
+                match self.rx.try_recv() {
+                    Ok(command) => Some(command),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            } else {
+                match self.rx.recv_timeout(IDLE_WAIT) {
+                    Ok(command) => Some(command),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            };
+            match command {
+                Some(command) => self.handle(command),
+                None => self.step(),
+            }
+        }
+    }
+
+    /// True if there's decoding we could be doing right now.
+    fn has_work(&self) -> bool {
+        let has_room = self.producer.as_ref().map_or(false, |p| p.free() > self.pending.len());
+        self.source.is_some() && !self.exhausted && has_room
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Load(mut source) => {
+                if let Some((channels, sample_rate)) = self.format {
+                    source.set_output_format(channels, sample_rate);
+                }
+                self.source = Some(*source);
+                self.exhausted = false;
+                self.discard();
+                self.silence.reset(true);
+                self.state.decoding.store(true, Ordering::Relaxed);
+            }
+            Command::Stop => {
+                self.source = None;
+                self.discard();
+                self.state.decoding.store(false, Ordering::Relaxed);
+            }
+            Command::Seek(target, reply) => {
+                let result = match self.source.as_mut() {
+                    Some(source) => source.seek(target),
+                    None => Ok(()),
+                };
+                if result.is_ok() && self.source.is_some() {
+                    self.exhausted = false;
+                    self.discard();
+                    self.silence.reset(target.is_zero());
+                    self.state.decoding.store(true, Ordering::Relaxed);
+                }
+                let _ = reply.send_blocking(result);
+            }
+            Command::SetEqualizer(preset) => self.equalizer.set_preset(preset),
+            Command::SetSilence(settings) => self.silence.set_settings(settings),
+            Command::SetSpeed(speed) if speed != self.stretch.speed() => {
+                // The stretcher throws away what it's holding on to, so rewind to get it back.
+                self.rewind_stretched();
+                self.stretch.set_speed(speed);
+            }
+            Command::SetSpeed(_) => (),
+            Command::SetAbLoop(region) => {
+                if let Some(source) = self.source.as_mut() {
+                    source.ab_loop = region;
+                }
+            }
+            Command::SetOutput {
+                producer,
+                channels,
+                sample_rate,
+            } => {
+                self.rewind_unplayed();
+                // The new ring buffer counts its samples from zero.
+                self.state.timeline.clear();
+                self.state.tap.clear();
+                self.producer = Some(producer);
+                self.format = Some((channels, sample_rate));
+                self.stretch.set_format(channels, sample_rate);
+                self.equalizer.set_format(channels, sample_rate);
+                self.silence.set_format(channels, sample_rate);
+                if let Some(source) = self.source.as_mut() {
+                    source.set_output_format(channels, sample_rate);
+                }
+            }
+        }
+    }
+
+    /// Seeks the source back to the first sample that was decoded but never played, then throws
+    /// away those samples. Used when the old output goes away, so that switching outputs doesn't
+    /// skip anything.
+    fn rewind_unplayed(&mut self) {
+        let buffered = self.producer.as_ref().map_or(0, |p| p.len()) + self.pending.len();
+        self.pending.clear();
+        let Some((channels, sample_rate)) = self.format else {
+            return;
+        };
+        // Output samples are stretched, so they stand for more or less of the track.
+        let frames = buffered as f64 / channels as f64 * self.stretch.speed()
+            + self.stretch.buffered() as f64;
+        self.rewind(frames / sample_rate as f64);
+    }
+
+    /// Seeks the source back by however much input the stretcher is holding on to.
+    fn rewind_stretched(&mut self) {
+        let Some((_, sample_rate)) = self.format else {
+            return;
+        };
+        self.rewind(self.stretch.buffered() as f64 / sample_rate as f64);
+    }
+
+    /// Seeks the source back by the given number of seconds of decoded audio, clearing out all of
+    /// our processing state.
+    fn rewind(&mut self, seconds: f64) {
+        self.stretch.reset();
+        self.equalizer.reset();
+        self.silence.reset(false);
+        let Some(source) = self.source.as_mut() else {
+            return;
+        };
+        if seconds <= 0.0 {
+            return;
+        }
+        let unplayed = Duration::from_secs_f64(seconds);
+        match source.seek(source.position.saturating_sub(unplayed)) {
+            Ok(()) => {
+                self.exhausted = false;
+                self.state.decoding.store(true, Ordering::Relaxed);
+            }
+            Err(e) => warn!("Couldn't rewind after switching outputs: {e}"),
+        }
+    }
+
+    /// Throws away everything that's been decoded but not played.
+    fn discard(&mut self) {
+        self.pending.clear();
+        self.stretch.reset();
+        self.equalizer.reset();
+        self.state.timeline.clear();
+        self.state.tap.clear();
+        if let Some(producer) = self.producer.as_mut() {
+            producer.discard();
+        }
+    }
+
+    /// Does a single unit of work: either decoding a packet, or checking whether the output has
+    /// finished playing an exhausted source.
+    fn step(&mut self) {
+        let (Some(source), Some(producer), Some((channels, sample_rate))) =
+            (self.source.as_mut(), self.producer.as_mut(), self.format)
+        else {
+            return;
+        };
+        let written = producer.push(&self.pending);
+        self.pending.drain(..written);
+        if !self.pending.is_empty() {
+            return;
+        }
+
+        if !self.exhausted {
+            // The first sample we're about to produce comes from whatever input the stretcher and
+            // the silence skipper are holding on to.
+            let held = self.stretch.buffered() + self.silence.held_frames();
+            let held = Duration::from_secs_f64(held as f64 / f64::from(sample_rate));
+            let timestamp = source.position.saturating_sub(held);
+            let index = producer.written();
+            self.decoded.clear();
+            let more = if self.silence.owing() {
+                self.silence.pay(&mut self.decoded);
+                true
+            } else {
+                self.undecoded.clear();
+                let more = source.decode(&mut self.undecoded);
+                if std::mem::take(&mut source.looped) {
+                    self.state.loops.fetch_add(1, Ordering::Relaxed);
+                }
+                self.silence.process(&self.undecoded, &mut self.decoded);
+                if !more {
+                    self.silence.finish(&mut self.decoded);
+                }
+                more
+            };
+            self.stretch.process(&self.decoded, &mut self.pending);
+            if !more {
+                self.stretch.flush(&mut self.pending);
+            }
+            self.equalizer.process(&mut self.pending);
+            if !self.pending.is_empty() {
+                let samples_per_second =
+                    (channels as f64 * f64::from(sample_rate)) / self.stretch.speed();
+                self.state.timeline.mark(index, timestamp, samples_per_second);
+                self.state.tap.push(&self.pending, channels, index);
+            }
+            if !more {
+                self.exhausted = true;
+                self.state.decoding.store(false, Ordering::Relaxed);
+            }
+        } else if producer.is_empty() {
+            // Everything's been played, so we're done with this track.
+            source.finish();
+            self.source = None;
+        }
+    }
+}
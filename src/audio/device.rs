@@ -0,0 +1,113 @@
+use std::iter;
+
+use cpal::{
+    traits::{DeviceTrait, HostTrait},
+    Device, Host,
+};
+use eyre::{eyre, Result};
+use log::warn;
+
+/// Which output device to play through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceId {
+    /// Name of the audio API the device belongs to. If `None`, we look at every host, starting
+    /// with the default one.
+    pub host: Option<String>,
+    pub name: String,
+}
+
+impl DeviceId {
+    /// Whether this picks out `device`.
+    pub fn matches(&self, device: &OutputDevice) -> bool {
+        self.name == device.name && self.host.as_ref().map_or(true, |host| *host == device.host)
+    }
+}
+
+/// An output device, as presented to the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputDevice {
+    /// Name of the audio API the device belongs to, e.g. "ALSA".
+    pub host: String,
+    pub name: String,
+    /// True if this is its host's default output device.
+    pub default: bool,
+}
+
+/// Lists the output devices on every available host. Hosts or devices that error out are skipped.
+pub fn output_devices() -> Vec<OutputDevice> {
+    cpal::available_hosts()
+        .into_iter()
+        .filter_map(|id| match cpal::host_from_id(id) {
+            Ok(host) => Some(host),
+            Err(e) => {
+                warn!("Couldn't open audio host {}: {e}", id.name());
+                None
+            }
+        })
+        .flat_map(|host| host_devices(&host))
+        .collect()
+}
+
+impl OutputDevice {
+    /// How to refer to this device later. The system default device (the default host's default
+    /// device) is `None`, so that we follow it if it changes.
+    pub fn id(&self) -> Option<DeviceId> {
+        let system_default = self.default && self.host == cpal::default_host().id().name();
+        (!system_default).then(|| DeviceId {
+            host: Some(self.host.clone()),
+            name: self.name.clone(),
+        })
+    }
+}
+
+fn host_devices(host: &Host) -> Vec<OutputDevice> {
+    let default = host.default_output_device().and_then(|device| device.name().ok());
+    let devices = match host.output_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            warn!("Couldn't list devices for audio host {}: {e}", host.id().name());
+            return vec![];
+        }
+    };
+    devices
+        .filter_map(|device| device.name().ok())
+        .map(|name| OutputDevice {
+            host: host.id().name().to_owned(),
+            default: default.as_ref() == Some(&name),
+            name,
+        })
+        .collect()
+}
+
+/// Finds the given output device, looking at the default host first if `id` doesn't say which
+/// host it's on. If `id` is `None` or there's no such device, falls back to the default device.
+pub(super) fn find_output_device(id: Option<&DeviceId>) -> Result<Device> {
+    if let Some(DeviceId { host, name }) = id {
+        let default_id = cpal::default_host().id();
+        let hosts = iter::once(default_id)
+            .chain(cpal::available_hosts().into_iter().filter(|id| *id != default_id))
+            .filter(|id| host.as_ref().map_or(true, |host| host == id.name()));
+        for id in hosts {
+            let Ok(host) = cpal::host_from_id(id) else {
+                continue;
+            };
+            let Ok(mut devices) = host.output_devices() else {
+                continue;
+            };
+            if let Some(device) =
+                devices.find(|device| device.name().ok().as_deref() == Some(name.as_str()))
+            {
+                return Ok(device);
+            }
+        }
+        match host {
+            Some(host) => warn!(
+                "Output device {name:?} not found on {host}; falling back to the default device"
+            ),
+            None => warn!("Output device {name:?} not found; falling back to the default device"),
+        }
+    }
+    cpal::default_host()
+        .default_output_device()
+        .ok_or_else(|| eyre!("no default output device"))
+}
@@ -0,0 +1,292 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterKind {
+    /// Boosts or cuts a band around the frequency.
+    Peaking,
+    /// Boosts or cuts everything below the frequency.
+    LowShelf,
+    /// Boosts or cuts everything above the frequency.
+    HighShelf,
+}
+
+impl FilterKind {
+    pub fn next(self) -> Self {
+        match self {
+            FilterKind::Peaking => FilterKind::LowShelf,
+            FilterKind::LowShelf => FilterKind::HighShelf,
+            FilterKind::HighShelf => FilterKind::Peaking,
+        }
+    }
+}
+
+/// A single band of a parametric EQ.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Band {
+    pub kind: FilterKind,
+    /// Center frequency for peaking filters, or the midpoint of the slope for shelves, in Hz.
+    pub frequency: f32,
+    /// In decibels. Negative values cut.
+    pub gain: f32,
+    /// Higher values make a peak narrower or a shelf steeper. 0.707 is a good default.
+    #[serde(default = "Band::default_q")]
+    pub q: f32,
+}
+
+impl Band {
+    pub fn default_q() -> f32 {
+        std::f32::consts::FRAC_1_SQRT_2
+    }
+
+    /// The gain this band applies at `frequency`, in decibels.
+    pub fn response(&self, frequency: f32, sample_rate: u32) -> f32 {
+        Biquad::new(self, sample_rate).response(frequency as f64, sample_rate) as f32
+    }
+}
+
+/// A named EQ curve. Bands are applied in series.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct EqPreset {
+    /// Gain applied before the bands, in decibels. Use a negative value to avoid clipping when
+    /// boosting.
+    #[serde(default)]
+    pub preamp: f32,
+    pub bands: Vec<Band>,
+}
+
+impl EqPreset {
+    /// The gain the whole preset applies at `frequency`, in decibels.
+    pub fn response(&self, frequency: f32, sample_rate: u32) -> f32 {
+        self.preamp + self.bands.iter().map(|b| b.response(frequency, sample_rate)).sum::<f32>()
+    }
+}
+
+/// Coefficients for a biquad filter, normalized so that `a0` is 1. See
+/// <https://www.w3.org/TR/audio-eq-cookbook/>.
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+impl Biquad {
+    fn new(band: &Band, sample_rate: u32) -> Self {
+        let sample_rate = sample_rate as f64;
+        // The formulas break down at and above Nyquist.
+        let frequency = (band.frequency as f64).clamp(1.0, sample_rate * 0.49);
+        let a = 10f64.powf(band.gain as f64 / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * (band.q as f64).max(0.01));
+        let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+        let (b0, b1, b2, a0, a1, a2) = match band.kind {
+            FilterKind::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            FilterKind::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha),
+                (a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha,
+            ),
+            FilterKind::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha),
+                (a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha,
+            ),
+        };
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    /// Magnitude response at `frequency`, in decibels.
+    fn response(&self, frequency: f64, sample_rate: u32) -> f64 {
+        let w = 2.0 * PI * frequency / sample_rate as f64;
+        // Evaluate the transfer function at z = e^(iw), using z^-1 = cos(w) - i sin(w).
+        let (sin1, cos1) = w.sin_cos();
+        let (sin2, cos2) = (2.0 * w).sin_cos();
+        let num_re = self.b0 + self.b1 * cos1 + self.b2 * cos2;
+        let num_im = -(self.b1 * sin1 + self.b2 * sin2);
+        let den_re = 1.0 + self.a1 * cos1 + self.a2 * cos2;
+        let den_im = -(self.a1 * sin1 + self.a2 * sin2);
+        let power = (num_re.powi(2) + num_im.powi(2)) / (den_re.powi(2) + den_im.powi(2));
+        10.0 * power.log10()
+    }
+
+    /// Filters a single sample using transposed direct form II.
+    fn process(&self, state: &mut [f64; 2], sample: f64) -> f64 {
+        let out = self.b0 * sample + state[0];
+        state[0] = self.b1 * sample - self.a1 * out + state[1];
+        state[1] = self.b2 * sample - self.a2 * out;
+        out
+    }
+}
+
+/// Applies an [`EqPreset`] to interleaved samples.
+#[derive(Debug)]
+pub(super) struct Equalizer {
+    preset: Option<EqPreset>,
+    channels: usize,
+    sample_rate: u32,
+    preamp: f32,
+    filters: Vec<Biquad>,
+    /// Filter state for each (band, channel) pair, indexed by `band * channels + channel`.
+    states: Vec<[f64; 2]>,
+}
+
+impl Equalizer {
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        Self {
+            preset: None,
+            channels,
+            sample_rate,
+            preamp: 1.0,
+            filters: vec![],
+            states: vec![],
+        }
+    }
+
+    /// Switches to a new preset, or turns the EQ off if `None`.
+    pub fn set_preset(&mut self, preset: Option<EqPreset>) {
+        self.preset = preset;
+        self.rebuild();
+    }
+
+    pub fn set_format(&mut self, channels: usize, sample_rate: u32) {
+        self.channels = channels;
+        self.sample_rate = sample_rate;
+        self.rebuild();
+    }
+
+    /// Clears the filters' memory of previous samples. Call this when the input is discontinuous.
+    pub fn reset(&mut self) {
+        self.states.fill([0.0; 2]);
+    }
+
+    fn rebuild(&mut self) {
+        let (preamp, bands) = match &self.preset {
+            Some(preset) => (preset.preamp, preset.bands.as_slice()),
+            None => (0.0, [].as_slice()),
+        };
+        self.preamp = 10f32.powf(preamp / 20.0);
+        self.filters = bands.iter().map(|band| Biquad::new(band, self.sample_rate)).collect();
+        // Keep the existing state where we can, so that tweaking a preset while playing doesn't
+        // click.
+        self.states.resize(self.filters.len() * self.channels, [0.0; 2]);
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if self.filters.is_empty() && self.preamp == 1.0 {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(self.channels) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut value = (*sample * self.preamp) as f64;
+                for (band, filter) in self.filters.iter().enumerate() {
+                    value = filter.process(&mut self.states[band * self.channels + channel], value);
+                }
+                *sample = value as f32;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! assert_close {
+        ($actual:expr, $expected:expr, $epsilon:expr) => {
+            let (actual, expected) = ($actual, $expected);
+            assert!((actual - expected).abs() < $epsilon, "expected {expected}, got {actual}");
+        };
+    }
+
+    fn band(kind: FilterKind, gain: f32) -> Band {
+        Band {
+            kind,
+            frequency: 1000.0,
+            gain,
+            q: Band::default_q(),
+        }
+    }
+
+    #[test]
+    fn peaking_response() {
+        let band = band(FilterKind::Peaking, 6.0);
+        assert_close!(band.response(1000.0, 48000), 6.0, 1e-3);
+        assert_close!(band.response(20.0, 48000), 0.0, 0.1);
+        assert_close!(band.response(20000.0, 48000), 0.0, 0.1);
+    }
+
+    #[test]
+    fn shelf_response() {
+        let low = band(FilterKind::LowShelf, -6.0);
+        assert_close!(low.response(20.0, 48000), -6.0, 0.1);
+        assert_close!(low.response(20000.0, 48000), 0.0, 0.1);
+        let high = band(FilterKind::HighShelf, 6.0);
+        assert_close!(high.response(20.0, 48000), 0.0, 0.1);
+        assert_close!(high.response(20000.0, 48000), 6.0, 0.1);
+    }
+
+    /// Peak amplitude of the second half of `samples`, to skip the filter's transient.
+    fn steady_peak(samples: &[f32]) -> f32 {
+        samples[samples.len() / 2..].iter().fold(0.0, |max, s| s.abs().max(max))
+    }
+
+    #[test]
+    fn boosts_sine() {
+        let sine = |freq: f32| {
+            (0..48000)
+                .flat_map(|i| {
+                    let s = (2.0 * std::f32::consts::PI * freq * i as f32 / 48000.0).sin() * 0.25;
+                    [s, s]
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut eq = Equalizer::new(2, 48000);
+        eq.set_preset(Some(EqPreset {
+            preamp: 0.0,
+            bands: vec![band(FilterKind::Peaking, 6.0)],
+        }));
+
+        let mut samples = sine(1000.0);
+        eq.process(&mut samples);
+        // +6dB is about double
+        assert_close!(steady_peak(&samples), 0.5, 0.01);
+
+        eq.reset();
+        let mut samples = sine(50.0);
+        eq.process(&mut samples);
+        assert_close!(steady_peak(&samples), 0.25, 0.01);
+    }
+
+    #[test]
+    fn off_is_identity() {
+        let mut eq = Equalizer::new(2, 44100);
+        let mut samples = vec![0.1, -0.2, 0.3, -0.4];
+        eq.process(&mut samples);
+        assert_eq!(samples, [0.1, -0.2, 0.3, -0.4]);
+    }
+}
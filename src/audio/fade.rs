@@ -0,0 +1,104 @@
+/// Ramps the output's gain up and down so that pausing, resuming, seeking and stopping don't click.
+/// This lives in the output callback, so it's only ever touched from that thread.
+#[derive(Debug)]
+pub(super) struct Fader {
+    channels: usize,
+    sample_rate: u32,
+    /// Gain applied to the samples being played, from 0 to 1.
+    gain: f32,
+    /// The last frame we output, so that it can be faded out if the audio jumps somewhere else.
+    last: Vec<f32>,
+    /// What was playing right before the last jump. This gets faded out underneath the new audio,
+    /// rather than cutting straight from it to silence.
+    tail: Vec<f32>,
+    tail_gain: f32,
+}
+
+impl Fader {
+    /// Starts out silent, so the first thing played fades in.
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        Self {
+            channels,
+            sample_rate,
+            gain: 0.0,
+            last: vec![0.0; channels],
+            tail: vec![0.0; channels],
+            tail_gain: 0.0,
+        }
+    }
+
+    /// How much the gain changes per frame for a fade lasting `millis`.
+    fn step(&self, millis: u32) -> f32 {
+        let frames = u64::from(millis) * u64::from(self.sample_rate) / 1000;
+        if frames == 0 {
+            1.0
+        } else {
+            1.0 / frames as f32
+        }
+    }
+
+    /// Call this when the audio is about to jump somewhere else, like after a seek. Whatever was
+    /// playing fades out while the new audio fades in.
+    pub fn cut(&mut self) {
+        self.tail.copy_from_slice(&self.last);
+        self.tail_gain = 1.0;
+        self.gain = 0.0;
+    }
+
+    /// How many more samples have to be played to finish fading out.
+    pub fn samples_until_silent(&self, millis: u32) -> usize {
+        (self.gain / self.step(millis)).ceil() as usize * self.channels
+    }
+
+    /// Applies the fade to `data`, ramping towards full gain if `playing` and towards silence
+    /// otherwise. A ramp from one end to the other takes `millis`.
+    pub fn apply(&mut self, data: &mut [f32], playing: bool, millis: u32) {
+        if playing && self.gain == 1.0 && self.tail_gain == 0.0 {
+            // nothing to do, which is by far the most common case
+        } else {
+            let step = self.step(millis);
+            for frame in data.chunks_exact_mut(self.channels) {
+                self.gain = if playing {
+                    (self.gain + step).min(1.0)
+                } else {
+                    (self.gain - step).max(0.0)
+                };
+                self.tail_gain = (self.tail_gain - step).max(0.0);
+                for (sample, tail) in frame.iter_mut().zip(&self.tail) {
+                    *sample = *sample * self.gain + tail * self.tail_gain;
+                }
+            }
+        }
+        if let Some(frame) = data.chunks_exact(self.channels).last() {
+            self.last.copy_from_slice(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ramps() {
+        // 4 frames per fade
+        let mut fader = Fader::new(1, 4000);
+        let mut data = [1.0; 6];
+        fader.apply(&mut data, true, 1);
+        assert_eq!(data, [0.25, 0.5, 0.75, 1.0, 1.0, 1.0]);
+
+        assert_eq!(fader.samples_until_silent(1), 4);
+        let mut data = [1.0; 4];
+        fader.apply(&mut data, false, 1);
+        assert_eq!(data, [0.75, 0.5, 0.25, 0.0]);
+        assert_eq!(fader.samples_until_silent(1), 0);
+
+        let mut fader = Fader::new(2, 4000);
+        fader.apply(&mut [1.0; 8], true, 0);
+        // the old audio fades out underneath the new
+        fader.cut();
+        let mut data = [0.5, -0.5, 0.5, -0.5, 0.5, -0.5, 0.5, -0.5, 0.5, -0.5];
+        fader.apply(&mut data, true, 1);
+        assert_eq!(data, [0.875, 0.625, 0.75, 0.25, 0.625, -0.125, 0.5, -0.5, 0.5, -0.5]);
+    }
+}
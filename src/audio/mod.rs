@@ -1,112 +1,182 @@
-use std::{iter, sync::Arc, time::Duration};
-
-use cpal::{
-    traits::{DeviceTrait, HostTrait},
-    Sample, SampleRate, Stream,
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
 };
-use educe::Educe;
-use eyre::{eyre, Result};
+
+use eyre::Result;
 use fragile::Fragile;
-use itertools::Itertools;
-use log::error;
+use log::{debug, error};
 use mpris_server::LoopStatus;
-use smol::{
-    channel::Sender,
-    lock::{Mutex, RwLock},
-};
-use symphonia::core::audio::{AudioBuffer, SampleBuffer};
+use smol::channel::Sender;
 
 use crate::{app::Message, library::Track};
 
+pub use self::{
+    device::{output_devices, DeviceId, OutputDevice},
+    eq::{Band, EqPreset, FilterKind},
+    output::{CpalBackend, NullBackend, OutputBackend, Pace, StreamFormat, WavBackend},
+    play_queue::{PlayQueue, StopAfter},
+    shuffle::{ShuffleMode, ShuffleWeight},
+    silence::SilenceSettings,
+};
+
 use self::{
-    play_queue::PlayQueue,
-    reader::{Fragment, SymphoniaReader},
+    decoder::{Decoder, FinishCallback, PlaybackState, Source},
+    fade::Fader,
+    output::OutputStream,
+    reader::SymphoniaReader,
+    ring_buffer::ring_buffer,
 };
 
+mod convert;
+mod decoder;
+mod device;
+mod eq;
+mod fade;
+mod output;
 mod play_queue;
 mod reader;
+mod ring_buffer;
+mod shuffle;
+mod silence;
+mod stretch;
+mod tap;
+mod timeline;
 
 pub struct Player {
-    /// Provides an iterator over indiviudal samples as well as access to the underlying reader.
-    source: Arc<Mutex<Option<Source>>>,
+    /// Decodes the current track on a separate thread, so that the output callback never has to.
+    decoder: Decoder,
     tx_message: Sender<Message>,
 
-    /// If true, playback is paused. If there are no songs in the queue, the value of this is not
-    /// specified.
-    paused: Arc<RwLock<bool>>,
+    /// Whether we're paused, what's being heard, plus diagnostic counters. If there are no songs
+    /// in the queue, the value of `paused` is not specified.
+    state: Arc<PlaybackState>,
 
-    // note: not set by the [`Player`] itself, but by the [`App`] on receiving a [`PlayerMessage`]
+    /// Where we are in the track when the output hasn't played anything from it yet, e.g. right
+    /// after a seek.
     timestamp: Option<Duration>,
 
     queue: PlayQueue,
-
-    /// Streams audio to the underlying OS audio library. This has a sample rate/channel count
-    /// corresponding to the currently playing song. This is wrapped in [`Fragile`] so that other
-    /// threads can read the player state; we don't make this publicly readable anywhere.
-    stream: Option<Fragile<Stream>>,
+    /// Used for [`ShuffleMode::Weighted`]. By default, every track is weighted the same.
+    shuffle_weight: ShuffleWeight,
+
+    /// Opens outputs. This is normally the OS audio library, but can be swapped out for testing.
+    backend: Box<dyn OutputBackend>,
+    /// Where the audio is currently going. This is opened the first time we play something and
+    /// then kept around across tracks, so that we don't have to reopen the device every time the
+    /// track changes. `None` if we haven't opened one yet or if the device was lost. This is
+    /// wrapped in [`Fragile`] since some backends' streams can't be sent across threads.
+    output: Option<Fragile<Box<dyn OutputStream>>>,
+    /// The device the user wants to play through. `None` means the system default.
+    output_device: Option<DeviceId>,
+    /// Playback speed as a multiple of normal speed. Always between [`Player::MIN_SPEED`] and
+    /// [`Player::MAX_SPEED`].
+    speed: f64,
+    /// Part of the current track to play over and over.
+    ab_loop: AbLoop,
+    /// How many seeks there have been.
+    seeks: u64,
 }
 
-#[derive(Educe)]
-#[educe(Debug)]
+/// Things that happen during playback that the app needs to know about. Decoded audio isn't sent
+/// this way, since there's so much of it; see [`Player::read_heard_audio`] instead.
+#[derive(Debug)]
 pub enum PlayerMessage {
-    AudioFragment {
-        #[educe(Debug(ignore))]
-        buffer: AudioBuffer<f32>,
-        timestamp: Duration,
-    },
     Finished,
+    /// The track couldn't be opened, or decoding it failed partway through. Anything decoded
+    /// before the failure has been played.
+    Error {
+        track: Arc<Track>,
+        error: eyre::Report,
+    },
+    /// The output device went away. The player needs to reopen its output.
+    OutputLost,
 }
 
 impl Player {
-    pub fn new(tx_message: Sender<Message>) -> Result<Self> {
-        let source: Arc<Mutex<Option<Source>>> = Arc::new(Mutex::new(None));
-        let paused = Arc::new(RwLock::new(true));
+    pub const MIN_SPEED: f64 = 0.5;
+    pub const MAX_SPEED: f64 = 3.0;
+
+    pub fn new(
+        tx_message: Sender<Message>,
+        backend: Box<dyn OutputBackend>,
+        output_device: Option<DeviceId>,
+    ) -> Result<Self> {
+        let state = Arc::new(PlaybackState::default());
+        state.paused.store(true, Ordering::Relaxed);
 
         Ok(Self {
-            source,
+            decoder: Decoder::spawn(Arc::clone(&state))?,
             tx_message,
-            paused,
+            state,
             timestamp: None,
             queue: PlayQueue::default(),
-            stream: None,
+            shuffle_weight: Arc::new(|_| 1.0),
+            backend,
+            output: None,
+            output_device,
+            speed: 1.0,
+            ab_loop: AbLoop::default(),
+            seeks: 0,
         })
     }
 
-    /// Build a `Stream` that can handle playback with the given channel count and sample rate.
-    fn build_stream(&self, channels: u16, sample_rate: u32) -> Result<Stream> {
-        let host = cpal::default_host();
-        let device =
-            host.default_output_device().ok_or_else(|| eyre!("no default output device"))?;
-        let config = device
-            .supported_output_configs()?
-            .find(|config| config.channels() == channels)
-            .ok_or_else(|| eyre!("unable to find config supporting {channels} channels"))?
-            .with_sample_rate(SampleRate(sample_rate))
-            .config();
-        let source_clone = Arc::clone(&self.source);
-        let paused_clone = Arc::clone(&self.paused);
-        let stream = device.build_output_stream(
-            &config,
-            move |data: &mut [f32], _| {
-                match source_clone.lock_blocking().as_mut() {
-                    Some(iter) if !*paused_clone.read_blocking() => {
-                        // copy from src to dst, zeroing the rest
-                        for (dst, src) in
-                            data.iter_mut().zip(iter.chain(iter::repeat(f32::EQUILIBRIUM)))
-                        {
-                            *dst = src
-                        }
-                    }
-                    // no data, so just zero the entire thing
-                    _ => data.fill(f32::EQUILIBRIUM),
-                }
-            },
-            |e| {
-                error!("Error while streaming audio out: {e}");
-            },
-            None,
+    /// Opens an output on the selected device (or the default, if it's not available).
+    fn build_output(&self) -> Result<Box<dyn OutputStream>> {
+        let mut output = self.backend.open(self.output_device.as_ref())?;
+        let format = output.format();
+        // Enough to ride out a hiccup of a few hundred milliseconds.
+        let capacity = format.sample_rate as usize * format.channels / 4;
+        let (producer, mut consumer) = ring_buffer(capacity);
+        let state = Arc::clone(&self.state);
+        let tx_message = self.tx_message.clone();
+        let mut fader = Fader::new(format.channels, format.sample_rate);
+        self.state.clock.reset(format.channels as f64 * f64::from(format.sample_rate));
+        output.start(
+            Box::new(move |data, latency| state.fill(&mut consumer, &mut fader, data, latency)),
+            Box::new(move || {
+                let _ = tx_message.try_send(Message::Player(PlayerMessage::OutputLost));
+            }),
         )?;
-        Ok(stream)
+        self.decoder.set_output(producer, format.channels, format.sample_rate)?;
+        Ok(output)
+    }
+
+    /// Opens the output if it isn't already.
+    fn ensure_output(&mut self) -> Result<()> {
+        if self.output.is_none() {
+            self.output = Some(Fragile::new(self.build_output()?));
+        }
+        Ok(())
+    }
+
+    /// Closes the output stream and opens a new one, e.g. because the device went away or its
+    /// configuration changed. The current track keeps playing from where it was.
+    pub fn reopen_output(&mut self) -> Result<()> {
+        self.output = None;
+        self.ensure_output()?;
+        Ok(())
+    }
+
+    /// The device the user asked to play through, if any.
+    pub fn output_device(&self) -> Option<&DeviceId> {
+        self.output_device.as_ref()
+    }
+
+    /// Switches playback to the given device, or to the system default if `None`.
+    pub fn set_output_device(&mut self, device: Option<DeviceId>) -> Result<()> {
+        self.output_device = device;
+        self.reopen_output()
+    }
+
+    /// Filters everything through the given EQ preset, or turns the EQ off if `None`.
+    pub fn set_equalizer(&self, preset: Option<EqPreset>) -> Result<()> {
+        self.decoder.set_equalizer(preset)
+    }
+
+    /// Changes which silences get skipped.
+    pub fn set_silence(&self, settings: SilenceSettings) -> Result<()> {
+        self.decoder.set_silence(settings)
     }
 
     pub fn queue(&self) -> &PlayQueue {
@@ -118,12 +188,24 @@ impl Player {
         self.queue.current_track()
     }
 
+    /// Where in the current track the audio coming out of the speakers is. This follows what the
+    /// output has actually played, so it lags behind decoding and stands still while paused.
     pub fn timestamp(&self) -> Option<Duration> {
-        self.timestamp
+        self.current()?;
+        let heard = self.state.clock.heard();
+        self.state.timeline.position(heard).or(self.timestamp)
+    }
+
+    /// How much of the current track is left to play. `None` if nothing's playing.
+    pub fn time_left(&self) -> Option<Duration> {
+        let length = Duration::from_secs_f64(self.current()?.length.0);
+        Some(length.saturating_sub(self.timestamp()?))
     }
 
-    pub fn set_timestamp(&mut self, timestamp: Option<Duration>) {
-        self.timestamp = timestamp;
+    /// Appends the audio that's been heard since the last time this was called to `out`,
+    /// downmixed to mono. Returns true if there was any.
+    pub fn read_heard_audio(&self, out: &mut Vec<f32>) -> bool {
+        self.state.tap.read_until(self.state.clock.heard(), out)
     }
 
     /// Sets the play queue to the given playlist. Also stops existing playback.
@@ -131,7 +213,9 @@ impl Player {
         self.stop().await;
         let mut queue = PlayQueue::new(tracks);
         queue.set_loop_status(self.queue.loop_status());
-        queue.set_shuffle(self.queue.shuffle());
+        queue.set_shuffle_mode(self.queue.shuffle_mode(), &*self.shuffle_weight);
+        queue.set_shuffle(self.queue.shuffle(), &*self.shuffle_weight);
+        queue.set_stop_after(self.queue.stop_after());
         self.queue = queue;
     }
 
@@ -139,6 +223,53 @@ impl Player {
         self.queue.push(track);
     }
 
+    /// Puts `track` in the queue before the current one and switches to it.
+    pub async fn go_back_to(&mut self, track: Arc<Track>) -> Result<()> {
+        let index = self.queue.current().unwrap_or(0);
+        self.queue.insert(index, track);
+        self.set_queue_index(Some(index)).await
+    }
+
+    /// Inserts a track into the queue at `index`. The current track stays current.
+    pub fn queue_insert(&mut self, index: usize, track: Arc<Track>) {
+        self.queue.insert(index, track);
+    }
+
+    /// Adds a track to the queue so that it plays after the current one.
+    pub fn queue_insert_next(&mut self, track: Arc<Track>) {
+        self.queue.insert_next(track);
+    }
+
+    /// Removes the track at `index` from the queue. If it's the current track, we move on to the
+    /// next one.
+    pub async fn queue_remove(&mut self, index: usize) -> Result<()> {
+        let was_current = self.queue.current() == Some(index);
+        self.queue.remove(index);
+        if was_current {
+            self.set_queue_index(self.queue.current()).await?;
+        }
+        Ok(())
+    }
+
+    pub fn queue_move(&mut self, from: usize, to: usize) {
+        self.queue.move_track(from, to);
+    }
+
+    /// Empties the queue, stopping playback.
+    pub async fn queue_clear(&mut self) {
+        self.stop().await;
+        self.queue.clear();
+    }
+
+    pub fn queue_clear_upcoming(&mut self) {
+        self.queue.clear_upcoming();
+    }
+
+    /// Removes repeated tracks from the queue. Returns how many were removed.
+    pub fn queue_dedupe(&mut self) -> usize {
+        self.queue.dedupe()
+    }
+
     /// Sets the current track to the one at the given position. Panics if that's out of bounds.
     pub async fn set_queue_index(&mut self, index: Option<usize>) -> Result<()> {
         if index.is_none() {
@@ -146,29 +277,47 @@ impl Player {
             return Ok(());
         }
 
+        let previous = self.current();
         self.queue.set_current(index);
         let track =
             self.queue.current_track().expect("set current index to non-None, but no track");
 
-        let reader = SymphoniaReader::from_path(&track.path)?;
-        self.stream =
-            Some(Fragile::new(self.build_stream(reader.channels() as u16, reader.sample_rate())?));
+        let reader = match SymphoniaReader::from_path(&track.path) {
+            Ok(reader) => reader,
+            Err(error) => {
+                // Leave it to the app to decide what to do, the same as for a decoding error.
+                self.decoder.stop()?;
+                let message = PlayerMessage::Error { track, error };
+                let _ = self.tx_message.try_send(Message::Player(message));
+                return Ok(());
+            }
+        };
+        self.ensure_output()?;
 
-        let reader = Arc::new(Mutex::new(reader));
+        self.timestamp = Some(Duration::ZERO);
         let tx_message = self.tx_message.clone();
-        let on_decode: DecodeCallback = Box::new(move |fragment| {
-            let _ = tx_message.send_blocking(Message::Player(PlayerMessage::AudioFragment {
-                buffer: fragment.buffer,
-                timestamp: fragment.timestamp,
-            }));
+        let failed_track = Arc::clone(&track);
+        let on_finish: FinishCallback = Box::new(move |error| {
+            let message = match error {
+                None => PlayerMessage::Finished,
+                Some(error) => PlayerMessage::Error {
+                    track: failed_track,
+                    error,
+                },
+            };
+            let _ = tx_message.send_blocking(Message::Player(message));
         });
-        let tx_message = self.tx_message.clone();
-        let on_finish: FinishCallback = Box::new(move || {
-            let _ = tx_message.send_blocking(Message::Player(PlayerMessage::Finished));
-        });
-        let source = Source::new(reader, on_decode, on_finish);
-        *self.source.lock().await = Some(source);
-        Ok(())
+        self.decoder.load(Source::new(reader, on_finish))?;
+        // Loop points only make sense for the track they were set on, but they're kept when a
+        // track repeats. Either way, the new source needs to be told about them.
+        if previous == Some(track) {
+            // Going back to the start of the same track is a jump, as far as anything showing the
+            // position is concerned.
+            self.seeks += 1;
+        } else {
+            self.ab_loop = AbLoop::default();
+        }
+        self.decoder.set_ab_loop(self.ab_loop.region())
     }
 }
 
@@ -183,31 +332,31 @@ impl Player {
         if self.queue.current_track().is_none() && !self.queue.is_empty() {
             self.set_queue_index(Some(0)).await?;
         }
-        self.set_paused(false).await;
+        self.set_paused(false);
         Ok(())
     }
 
     /// True if audio is being produced (i.e., we're not paused *and* there's a current song).
-    pub async fn playing(&self) -> bool {
-        !self.paused().await && self.current().is_some()
+    pub fn playing(&self) -> bool {
+        !self.paused() && self.current().is_some()
     }
 
     pub fn stopped(&self) -> bool {
         self.queue.current().is_none()
     }
 
-    pub async fn paused(&self) -> bool {
-        *self.paused.write().await
+    pub fn paused(&self) -> bool {
+        self.state.paused.load(Ordering::Relaxed)
     }
 
-    pub async fn pause(&mut self) {
-        if self.playing().await {
-            self.set_paused(true).await;
+    pub fn pause(&mut self) {
+        if self.playing() {
+            self.set_paused(true);
         }
     }
 
-    async fn set_paused(&mut self, paused: bool) {
-        *self.paused.write().await = paused;
+    fn set_paused(&mut self, paused: bool) {
+        self.state.paused.store(paused, Ordering::Relaxed);
     }
 
     /// Moves to the next track. If this was the last track, equivalent to stop().
@@ -215,78 +364,539 @@ impl Player {
         self.set_queue_index(self.queue.next()).await
     }
 
+    /// Moves past the current track after it couldn't be played. Unlike [`Player::next`], this
+    /// doesn't stay on the track if it's being looped.
+    pub async fn skip_unplayable(&mut self) -> Result<()> {
+        self.set_queue_index(self.queue.next_other()).await
+    }
+
+    /// Moves on after the current track finishes by itself. Unlike [`Player::next`], this honors
+    /// repeat counts and stopping after the current track or album. When stopping, the next track
+    /// is left selected but paused, so that playing picks up from there.
+    pub async fn advance(&mut self) -> Result<()> {
+        let (index, stop) = self.queue.finish();
+        self.set_queue_index(index).await?;
+        if stop {
+            self.pause();
+        }
+        Ok(())
+    }
+
     /// Stops playback. This also unsets our position in the play queue.
     pub async fn stop(&mut self) {
         self.queue.set_current(None);
-        *self.source.lock().await = None;
+        self.ab_loop = AbLoop::default();
+        if let Err(e) = self.decoder.stop() {
+            error!("Couldn't stop decoding: {e}");
+        }
+        self.timestamp = None;
+        let underruns = self.underruns();
+        debug!(
+            "Stopped playback; {} underruns so far ({} samples)",
+            underruns.count, underruns.samples
+        );
     }
 
     /// Seek to the given timestamp. Does nothing if there's no currently-playing track.
     pub async fn seek(&mut self, target: Duration) -> Result<()> {
-        let mut source = self.source.lock().await;
-        if let Some(source) = source.as_mut() {
-            source.reader.lock().await.seek(target)
-        } else {
-            Ok(())
+        self.decoder.seek(target).await?;
+        self.timestamp = Some(target);
+        self.seeks += 1;
+        Ok(())
+    }
+
+    /// How many times the position has jumped: successful calls to [`Player::seek`], restarts of
+    /// the current track, and trips back to the start of an A-B loop. Anything that shows the
+    /// position can watch this to tell when it jumped.
+    pub fn seeks(&self) -> u64 {
+        self.seeks + self.state.loops.load(Ordering::Relaxed)
+    }
+
+    /// How many times the output has run dry because decoding couldn't keep up.
+    pub fn underruns(&self) -> Underruns {
+        Underruns {
+            count: self.state.underruns.load(Ordering::Relaxed),
+            samples: self.state.underrun_samples.load(Ordering::Relaxed),
         }
     }
 
+    pub fn volume(&self) -> f64 {
+        f32::from_bits(self.state.volume.load(Ordering::Relaxed)) as f64
+    }
+
+    /// Sets how long it takes to fade in or out when pausing, resuming, seeking or stopping.
+    /// Zero turns fading off.
+    pub fn set_fade_duration(&mut self, duration: Duration) {
+        let millis = duration.as_millis().try_into().unwrap_or(u32::MAX);
+        self.state.fade_millis.store(millis, Ordering::Relaxed);
+    }
+
+    /// Sets the volume, where 1.0 is full volume. Values are clamped to [0, 1], and NaN or
+    /// infinite values are ignored.
+    pub fn set_volume(&mut self, volume: f64) {
+        if !volume.is_finite() {
+            return;
+        }
+        let volume = volume.clamp(0.0, 1.0) as f32;
+        self.state.volume.store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Replaces the play queue, e.g. when restoring a session. Also stops existing playback.
+    pub async fn set_queue(&mut self, queue: PlayQueue) {
+        self.stop().await;
+        self.queue = queue;
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Changes the playback speed without changing the pitch. The speed is clamped to
+    /// [[`Player::MIN_SPEED`], [`Player::MAX_SPEED`]]. Timestamps are still in terms of the track,
+    /// so they'll advance faster or slower than real time.
+    pub fn set_speed(&mut self, speed: f64) -> Result<()> {
+        self.speed = speed.clamp(Self::MIN_SPEED, Self::MAX_SPEED);
+        self.decoder.set_speed(self.speed)
+    }
+
     pub fn set_loop_status(&mut self, loop_status: LoopStatus) {
         self.queue.set_loop_status(loop_status)
     }
 
     pub fn set_shuffle(&mut self, shuffle: bool) {
-        self.queue.set_shuffle(shuffle)
+        self.queue.set_shuffle(shuffle, &*self.shuffle_weight)
+    }
+
+    pub fn set_stop_after(&mut self, stop_after: Option<StopAfter>) {
+        self.queue.set_stop_after(stop_after)
     }
+
+    /// Plays the current track `repeats` more times after this one before moving on.
+    pub fn set_repeats(&mut self, repeats: u32) {
+        self.queue.set_repeats(repeats)
+    }
+
+    pub fn set_shuffle_mode(&mut self, mode: ShuffleMode) {
+        self.queue.set_shuffle_mode(mode, &*self.shuffle_weight)
+    }
+
+    pub fn ab_loop(&self) -> AbLoop {
+        self.ab_loop
+    }
+
+    /// Sets one end of the A-B loop to the current position. Does nothing if there's no current
+    /// track.
+    pub fn set_loop_point(&mut self, point: LoopPoint) -> Result<()> {
+        let Some(timestamp) = self.timestamp() else {
+            return Ok(());
+        };
+        self.ab_loop.set(point, timestamp);
+        self.decoder.set_ab_loop(self.ab_loop.region())
+    }
+
+    /// Moves one end of the A-B loop by `millis` milliseconds, earlier if it's negative.
+    pub fn nudge_loop_point(&mut self, point: LoopPoint, millis: i64) -> Result<()> {
+        self.ab_loop.nudge(point, millis);
+        self.decoder.set_ab_loop(self.ab_loop.region())
+    }
+
+    pub fn clear_ab_loop(&mut self) -> Result<()> {
+        self.ab_loop = AbLoop::default();
+        self.decoder.set_ab_loop(None)
+    }
+
+    /// Sets how tracks are weighted in [`ShuffleMode::Weighted`]. This takes effect the next time
+    /// the queue is shuffled.
+    pub fn set_shuffle_weight(&mut self, weight: ShuffleWeight) {
+        self.shuffle_weight = weight;
+    }
+}
+
+/// One end of an [`AbLoop`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopPoint {
+    Start,
+    End,
 }
 
-type DecodeCallback = Box<dyn FnMut(Fragment) + Send + 'static>;
-type FinishCallback = Box<dyn FnOnce() + Send + 'static>;
+/// Points within a track to loop between. The loop is only active once both are set, with the
+/// start before the end.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AbLoop {
+    pub start: Option<Duration>,
+    pub end: Option<Duration>,
+}
+
+impl AbLoop {
+    /// The start and end of the loop, if it's active.
+    pub fn region(&self) -> Option<(Duration, Duration)> {
+        let (start, end) = (self.start?, self.end?);
+        (start < end).then_some((start, end))
+    }
+
+    pub fn set(&mut self, point: LoopPoint, at: Duration) {
+        *self.point_mut(point) = Some(at);
+    }
+
+    /// Moves a point by `millis` milliseconds, earlier if it's negative. Points can't go before
+    /// the start of the track. Does nothing if the point isn't set.
+    pub fn nudge(&mut self, point: LoopPoint, millis: i64) {
+        let Some(at) = self.point_mut(point).as_mut() else {
+            return;
+        };
+        let delta = Duration::from_millis(millis.unsigned_abs());
+        *at = if millis < 0 {
+            at.saturating_sub(delta)
+        } else {
+            *at + delta
+        };
+    }
+
+    fn point_mut(&mut self, point: LoopPoint) -> &mut Option<Duration> {
+        match point {
+            LoopPoint::Start => &mut self.start,
+            LoopPoint::End => &mut self.end,
+        }
+    }
+}
 
-/// Iterates over the samples of a reader, invoking callbacks on decode and on finish. Also
-/// provides access to the underlying reader so you can seek on it.
-struct Source {
-    reader: Arc<Mutex<SymphoniaReader>>,
-    iterator: Box<dyn Send + Iterator<Item = f32>>,
+/// Counters for diagnosing audio dropouts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Underruns {
+    /// Number of output callbacks that didn't get all the samples they asked for.
+    pub count: u64,
+    /// Total number of samples that were filled with silence as a result.
+    pub samples: u64,
 }
 
-impl Source {
-    fn new(
-        reader: Arc<Mutex<SymphoniaReader>>,
-        mut on_decode: DecodeCallback,
-        on_finish: FinishCallback,
-    ) -> Self {
-        let reader_clone = Arc::clone(&reader);
-        let mut on_finish = Some(on_finish);
-        let iterator = iter::from_fn(move || {
-            let samples = reader_clone.lock_arc_blocking().next().map(|fragment| {
-                let buffer = &fragment.buffer;
-                let mut samples = SampleBuffer::new(buffer.capacity() as u64, *buffer.spec());
-                samples.copy_interleaved_typed(buffer);
-                (on_decode)(fragment);
-                samples
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Mutex, time::Instant};
+
+    use itertools::Itertools;
+    use smol::{channel::Receiver, future::FutureExt, Timer};
+
+    use super::{
+        output::{FillCallback, LostCallback},
+        *,
+    };
+    use crate::test_data;
+
+    /// Makes a player that plays into a [`NullBackend`] as fast as it can, so that tests don't take
+    /// as long as the tracks do.
+    fn test_player() -> (Player, Receiver<Message>) {
+        paced_player(Pace::Unthrottled)
+    }
+
+    fn paced_player(pace: Pace) -> (Player, Receiver<Message>) {
+        let (tx, rx) = smol::channel::unbounded();
+        let backend = NullBackend {
+            format: StreamFormat {
+                channels: 2,
+                sample_rate: 48000,
+            },
+            pace,
+        };
+        (Player::new(tx, Box::new(backend), None).unwrap(), rx)
+    }
+
+    /// An output that only plays when the test tells it to, so that we know exactly what's been
+    /// heard.
+    #[derive(Clone, Default)]
+    struct ManualBackend {
+        fill: Arc<Mutex<Option<FillCallback>>>,
+    }
+
+    impl ManualBackend {
+        const FORMAT: StreamFormat = StreamFormat {
+            channels: 2,
+            sample_rate: 48000,
+        };
+
+        /// Plays `len` samples, the first of which gets heard after `latency`. Returns how many of
+        /// them were audio.
+        fn play(&self, len: usize, latency: Duration) -> usize {
+            let mut fill = self.fill.lock().unwrap();
+            let fill = fill.as_mut().expect("output should have been started");
+            fill(&mut vec![0.0; len], latency)
+        }
+    }
+
+    impl OutputBackend for ManualBackend {
+        fn open(&self, _device: Option<&DeviceId>) -> Result<Box<dyn OutputStream>> {
+            Ok(Box::new(self.clone()))
+        }
+    }
+
+    impl OutputStream for ManualBackend {
+        fn format(&self) -> StreamFormat {
+            Self::FORMAT
+        }
+
+        fn start(&mut self, fill: FillCallback, _on_lost: LostCallback) -> Result<()> {
+            *self.fill.lock().unwrap() = Some(fill);
+            Ok(())
+        }
+    }
+
+    fn manual_player() -> (Player, ManualBackend, Receiver<Message>) {
+        let (tx, rx) = smol::channel::unbounded();
+        let output = ManualBackend::default();
+        let player = Player::new(tx, Box::new(output.clone()), None).unwrap();
+        (player, output, rx)
+    }
+
+    /// Has `output` play `duration` worth of audio, 10ms at a time, waiting whenever decoding falls
+    /// behind. Returns the position after each bit that had audio in it.
+    async fn play_for(
+        player: &Player,
+        output: &ManualBackend,
+        duration: Duration,
+    ) -> Result<Vec<Duration>> {
+        let format = ManualBackend::FORMAT;
+        let rate = format.channels * format.sample_rate as usize;
+        let (chunk, total) = (rate / 100, (rate as f64 * duration.as_secs_f64()) as usize);
+        let start = Instant::now();
+        let (mut played, mut timestamps) = (0, vec![]);
+        while played < total {
+            if start.elapsed() > Duration::from_secs(10) {
+                return Err(eyre::eyre!("timed out waiting for audio"));
+            }
+            let count = output.play(chunk, Duration::ZERO);
+            if count == 0 {
+                Timer::after(Duration::from_millis(1)).await;
+                continue;
+            }
+            played += count;
+            timestamps.push(player.timestamp().expect("should be playing"));
+        }
+        Ok(timestamps)
+    }
+
+    fn three_seconds(id: u64) -> Arc<Track> {
+        Arc::new(Track {
+            path: test_data!("3_seconds.mp3"),
+            ..Track::test_track(id)
+        })
+    }
+
+    async fn recv(rx: &Receiver<Message>) -> Result<PlayerMessage> {
+        let timeout = async {
+            Timer::after(Duration::from_secs(10)).await;
+            Err(eyre::eyre!("timed out waiting for a message"))
+        };
+        let message = async { Ok(rx.recv().await?) };
+        match message.or(timeout).await? {
+            Message::Player(message) => Ok(message),
+            Message::Command(command) => Err(eyre::eyre!("unexpected command {command:?}")),
+        }
+    }
+
+    /// Waits for the track to finish.
+    async fn play_to_end(rx: &Receiver<Message>) -> Result<()> {
+        match recv(rx).await? {
+            PlayerMessage::Finished => Ok(()),
+            PlayerMessage::Error { error, .. } => Err(error),
+            PlayerMessage::OutputLost => Err(eyre::eyre!("lost the null output?")),
+        }
+    }
+
+    #[test]
+    fn unplayable_track() -> Result<()> {
+        smol::block_on(async {
+            let (mut player, rx) = test_player();
+            let missing = Arc::new(Track {
+                path: test_data!("nonexistent.mp3"),
+                ..Track::test_track(0)
             });
-            if samples.is_none() {
-                if let Some(f) = on_finish.take() {
-                    f()
+            player.set_play_queue(vec![missing, three_seconds(1)]).await;
+            player.play().await?;
+            let PlayerMessage::Error { track, .. } = recv(&rx).await? else {
+                return Err(eyre::eyre!("should have failed to open the track"));
+            };
+            assert_eq!(track.id, 0);
+            player.skip_unplayable().await?;
+            assert_eq!(player.current().map(|t| t.id), Some(1));
+            play_to_end(&rx).await?;
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn ab_loop_points() {
+        let mut ab_loop = AbLoop::default();
+        ab_loop.set(LoopPoint::End, Duration::from_secs(2));
+        assert_eq!(ab_loop.region(), None);
+        ab_loop.set(LoopPoint::Start, Duration::from_secs(1));
+        ab_loop.nudge(LoopPoint::Start, -1500);
+        ab_loop.nudge(LoopPoint::End, 250);
+        assert_eq!(ab_loop.region(), Some((Duration::ZERO, Duration::from_millis(2250))));
+        ab_loop.set(LoopPoint::Start, Duration::from_secs(3));
+        assert_eq!(ab_loop.region(), None, "start is after the end");
+    }
+
+    #[test]
+    fn loops_between_points() -> Result<()> {
+        smol::block_on(async {
+            let (mut player, output, rx) = manual_player();
+            player.set_play_queue(vec![three_seconds(0)]).await;
+            // Set the loop up before playing, so that we can't decode past it first.
+            player.set_queue_index(Some(0)).await?;
+            player.seek(Duration::from_millis(500)).await?;
+            player.set_loop_point(LoopPoint::Start)?;
+            player.set_loop_point(LoopPoint::End)?;
+            player.nudge_loop_point(LoopPoint::End, 500)?;
+            assert_eq!(
+                player.ab_loop().region(),
+                Some((Duration::from_millis(500), Duration::from_secs(1)))
+            );
+            player.play().await?;
+            let timestamps = play_for(&player, &output, Duration::from_millis(1600)).await?;
+            let mut loops = 0;
+            for (last, timestamp) in timestamps.iter().tuple_windows() {
+                // Resampling can smear the end of the loop by a few samples.
+                let limit = Duration::from_millis(1010);
+                assert!(*timestamp <= limit, "went past the loop: {timestamp:?}");
+                if timestamp < last {
+                    loops += 1;
+                    // about one packet after the start
+                    assert!(*timestamp < Duration::from_millis(600), "{timestamp:?}");
                 }
             }
-            samples
+            assert!(loops >= 2, "only looped {loops} times: {timestamps:?}");
+            // One for the seek to the start, plus at least one per loop.
+            assert!(player.seeks() > loops, "{} seeks", player.seeks());
+            assert!(rx.is_empty(), "loop should keep going");
+            Ok(())
         })
-        .flat_map(|samples| samples.samples().iter().copied().collect_vec())
-        .fuse();
+    }
 
-        Self {
-            reader,
-            iterator: Box::new(iterator),
-        }
+    #[test]
+    fn plays_to_end() -> Result<()> {
+        smol::block_on(async {
+            let (mut player, rx) = test_player();
+            player.set_play_queue(vec![three_seconds(0)]).await;
+            player.play().await?;
+            play_to_end(&rx).await?;
+            let last = player.timestamp().expect("nothing was heard");
+            assert!(last > Duration::from_millis(2900), "last timestamp was {last:?}");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn position_follows_output() -> Result<()> {
+        smol::block_on(async {
+            let (mut player, output, _rx) = manual_player();
+            player.set_play_queue(vec![three_seconds(0)]).await;
+            player.set_queue_index(Some(0)).await?;
+            let mut samples = vec![];
+            assert!(!player.read_heard_audio(&mut samples), "nothing heard while paused");
+            player.play().await?;
+
+            // Resampling can shift things by a little.
+            let tolerance = 0.005;
+            let format = ManualBackend::FORMAT;
+            let rate = (format.channels * format.sample_rate as usize) as f64;
+            let chunk = rate as usize / 100;
+            let start = Instant::now();
+            // Decoding runs as far ahead of this as the ring buffer lets it, but the position
+            // should only cover what the output has played.
+            let mut played = 0;
+            while played < 10 * chunk {
+                assert!(start.elapsed() < Duration::from_secs(10), "timed out waiting for audio");
+                let count = output.play(chunk, Duration::ZERO);
+                if count == 0 {
+                    Timer::after(Duration::from_millis(1)).await;
+                    continue;
+                }
+                played += count;
+                let position = player.timestamp().expect("should be playing").as_secs_f64();
+                let (from, until) = ((played - count) as f64 / rate, played as f64 / rate);
+                assert!(position <= until + tolerance, "{position} is ahead of {until}");
+                assert!(position >= from - tolerance, "{position} is behind {from}");
+            }
+            assert!(player.read_heard_audio(&mut samples));
+
+            // Audio that won't be heard for a while doesn't count yet.
+            output.play(chunk, Duration::from_secs(3600));
+            let position = player.timestamp().expect("should be playing").as_secs_f64();
+            let heard = played as f64 / rate;
+            assert!(position <= heard + tolerance, "{position} is ahead of {heard}");
+            Ok(())
+        })
     }
-}
 
-impl Iterator for Source {
-    type Item = f32;
+    #[test]
+    fn next_advances_and_stops() -> Result<()> {
+        smol::block_on(async {
+            let (mut player, rx) = test_player();
+            player.set_play_queue(vec![three_seconds(0), three_seconds(1)]).await;
+            player.play().await?;
+            play_to_end(&rx).await?;
+            player.next().await?;
+            assert_eq!(player.current().map(|t| t.id), Some(1));
+            play_to_end(&rx).await?;
+            player.next().await?;
+            assert!(player.stopped());
+            Ok(())
+        })
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.iterator.next()
+    #[test]
+    fn seek() -> Result<()> {
+        smol::block_on(async {
+            let (mut player, output, _rx) = manual_player();
+            player.set_play_queue(vec![three_seconds(0)]).await;
+            // Seek before unpausing so that nothing before the seek point gets decoded... much.
+            player.set_queue_index(Some(0)).await?;
+            // Anything decoded before the seek went through is forgotten.
+            player.seek(Duration::from_secs(2)).await?;
+            player.play().await?;
+            let timestamps = play_for(&player, &output, Duration::from_millis(800)).await?;
+            // The first packet might start a bit before the seek target.
+            let earliest = Duration::from_millis(1900);
+            assert!(timestamps.iter().all(|t| *t >= earliest), "{timestamps:?}");
+            assert!(timestamps.windows(2).all(|w| w[0] <= w[1]), "{timestamps:?}");
+            let last = timestamps.last().expect("nothing was heard");
+            assert!(*last >= Duration::from_millis(2700), "{timestamps:?}");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn restarting_counts_as_seek() -> Result<()> {
+        smol::block_on(async {
+            let (mut player, _rx) = test_player();
+            player.set_play_queue(vec![three_seconds(0), three_seconds(1)]).await;
+            player.set_queue_index(Some(0)).await?;
+            player.set_queue_index(Some(1)).await?;
+            assert_eq!(player.seeks(), 0, "changing tracks isn't a seek");
+            player.set_queue_index(Some(1)).await?;
+            assert_eq!(player.seeks(), 1);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn ignores_bad_volumes() {
+        let (mut player, _rx) = test_player();
+        player.set_volume(0.5);
+        player.set_volume(f64::NAN);
+        player.set_volume(f64::INFINITY);
+        assert_eq!(player.volume(), 0.5);
+        player.set_volume(2.0);
+        assert_eq!(player.volume(), 1.0);
+    }
+
+    #[test]
+    fn seek_past_end() -> Result<()> {
+        smol::block_on(async {
+            let (mut player, _rx) = test_player();
+            player.set_play_queue(vec![three_seconds(0)]).await;
+            player.set_queue_index(Some(0)).await?;
+            assert!(player.seek(Duration::from_secs(10)).await.is_err());
+            Ok(())
+        })
     }
 }
@@ -0,0 +1,483 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use cpal::{
+    traits::{DeviceTrait, StreamTrait},
+    Device, FromSample, SampleFormat, SampleRate, SizedSample, Stream, StreamConfig, StreamError,
+    SupportedStreamConfig, SupportedStreamConfigRange,
+};
+use eyre::{eyre, Result};
+use log::{debug, error};
+
+use super::device::{find_output_device, DeviceId};
+
+/// Called whenever the output wants more samples, along with how long it'll be until the first of
+/// them is heard. Must fill the entire buffer, and returns how many of those samples were actual
+/// audio rather than silence.
+pub type FillCallback = Box<dyn FnMut(&mut [f32], Duration) -> usize + Send + 'static>;
+/// Called if the output goes away, e.g. because the device was unplugged.
+pub type LostCallback = Box<dyn FnMut() + Send + 'static>;
+
+/// Channel count and sample rate of an output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamFormat {
+    pub channels: usize,
+    pub sample_rate: u32,
+}
+
+/// Somewhere the [`Player`](super::Player) can send audio to.
+pub trait OutputBackend: Send + Sync {
+    /// Prepares an output on the given device, or some default if `None`. Backends that don't
+    /// have a notion of devices ignore it. Nothing plays until
+    /// [`OutputStream::start`] is called.
+    fn open(&self, device: Option<&DeviceId>) -> Result<Box<dyn OutputStream>>;
+}
+
+/// An output opened by a [`OutputBackend`]. Playback stops when this is dropped.
+pub trait OutputStream {
+    fn format(&self) -> StreamFormat;
+
+    /// Starts pulling audio from `fill`. This should only be called once.
+    fn start(&mut self, fill: FillCallback, on_lost: LostCallback) -> Result<()>;
+}
+
+/// Plays through the OS's audio stack.
+#[derive(Debug, Default)]
+pub struct CpalBackend;
+
+impl OutputBackend for CpalBackend {
+    /// Uses the device's preferred channel count and sample rate; tracks are converted to match.
+    fn open(&self, device: Option<&DeviceId>) -> Result<Box<dyn OutputStream>> {
+        let device = find_output_device(device)?;
+        debug!("Opening output device {:?}", device.name());
+        let default = device.default_output_config()?;
+        let config = choose_config(
+            device.supported_output_configs()?,
+            default.channels(),
+            default.sample_rate().0,
+        )
+        .ok_or_else(|| eyre!("output device has no supported configs"))?;
+        Ok(Box::new(CpalStream {
+            device,
+            sample_format: config.sample_format(),
+            config: config.config(),
+            stream: None,
+        }))
+    }
+}
+
+struct CpalStream {
+    device: Device,
+    config: StreamConfig,
+    /// What the device wants samples as. We produce `f32`s, and convert if it wants something
+    /// else.
+    sample_format: SampleFormat,
+    /// Never read; we only need to keep it alive.
+    #[allow(dead_code)]
+    stream: Option<Stream>,
+}
+
+impl OutputStream for CpalStream {
+    fn format(&self) -> StreamFormat {
+        StreamFormat {
+            channels: self.config.channels.into(),
+            sample_rate: self.config.sample_rate.0,
+        }
+    }
+
+    fn start(&mut self, fill: FillCallback, on_lost: LostCallback) -> Result<()> {
+        let stream = match self.sample_format {
+            SampleFormat::F32 => self.build_stream::<f32>(fill, on_lost)?,
+            SampleFormat::F64 => self.build_stream::<f64>(fill, on_lost)?,
+            SampleFormat::I8 => self.build_stream::<i8>(fill, on_lost)?,
+            SampleFormat::I16 => self.build_stream::<i16>(fill, on_lost)?,
+            SampleFormat::I32 => self.build_stream::<i32>(fill, on_lost)?,
+            SampleFormat::I64 => self.build_stream::<i64>(fill, on_lost)?,
+            SampleFormat::U8 => self.build_stream::<u8>(fill, on_lost)?,
+            SampleFormat::U16 => self.build_stream::<u16>(fill, on_lost)?,
+            SampleFormat::U32 => self.build_stream::<u32>(fill, on_lost)?,
+            SampleFormat::U64 => self.build_stream::<u64>(fill, on_lost)?,
+            format => return Err(eyre!("unsupported sample format {format}")),
+        };
+        stream.play()?;
+        self.stream = Some(stream);
+        Ok(())
+    }
+}
+
+impl CpalStream {
+    /// Builds a stream that takes samples of type `T`, converting what `fill` produces.
+    fn build_stream<T>(&self, mut fill: FillCallback, mut on_lost: LostCallback) -> Result<Stream>
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        // Only allocates when the device asks for more than it has before.
+        let mut buffer = vec![];
+        let stream = self.device.build_output_stream(
+            &self.config,
+            move |data: &mut [T], info| {
+                let timestamp = info.timestamp();
+                let latency = timestamp.playback.duration_since(&timestamp.callback);
+                buffer.resize(data.len(), 0.0);
+                fill(&mut buffer, latency.unwrap_or_default());
+                for (out, sample) in data.iter_mut().zip(&buffer) {
+                    *out = T::from_sample(*sample);
+                }
+            },
+            move |e| {
+                error!("Error while streaming audio out: {e}");
+                if matches!(e, StreamError::DeviceNotAvailable) {
+                    on_lost();
+                }
+            },
+            None,
+        )?;
+        Ok(stream)
+    }
+}
+
+/// How fast a [`NullBackend`] or [`WavBackend`] consumes audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pace {
+    /// As fast as a real device would.
+    RealTime,
+    /// As fast as we can decode.
+    Unthrottled,
+}
+
+/// Throws the audio away. Useful for testing, or on machines without a sound device.
+#[derive(Debug, Clone)]
+pub struct NullBackend {
+    pub format: StreamFormat,
+    pub pace: Pace,
+}
+
+impl OutputBackend for NullBackend {
+    fn open(&self, _device: Option<&DeviceId>) -> Result<Box<dyn OutputStream>> {
+        Ok(Box::new(PullStream::new(self.format, self.pace, |_| Ok(()))))
+    }
+}
+
+/// Writes the audio to a 16-bit WAV file. Pausing or stopping with [`Pace::RealTime`] writes
+/// silence; with [`Pace::Unthrottled`], only actual audio gets written.
+#[derive(Debug)]
+pub struct WavBackend {
+    path: PathBuf,
+    format: StreamFormat,
+    pace: Pace,
+    /// Created the first time the output is opened, and shared by every stream after that, so that
+    /// reopening the output carries on writing to the same file instead of starting it over.
+    writer: Arc<Mutex<Option<WavWriter>>>,
+}
+
+impl WavBackend {
+    pub fn new(path: PathBuf, format: StreamFormat, pace: Pace) -> Self {
+        Self {
+            path,
+            format,
+            pace,
+            writer: Arc::default(),
+        }
+    }
+}
+
+impl OutputBackend for WavBackend {
+    fn open(&self, _device: Option<&DeviceId>) -> Result<Box<dyn OutputStream>> {
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        if writer.is_none() {
+            *writer = Some(WavWriter::create(&self.path, self.format)?);
+        }
+        let writer = Arc::clone(&self.writer);
+        Ok(Box::new(PullStream::new(self.format, self.pace, move |samples| {
+            match writer.lock().unwrap_or_else(PoisonError::into_inner).as_mut() {
+                Some(writer) => writer.write(samples),
+                None => Ok(()),
+            }
+        })))
+    }
+}
+
+type SinkFn = Box<dyn FnMut(&[f32]) -> Result<()> + Send + 'static>;
+
+/// An output that pulls audio on its own thread and hands it to a function.
+struct PullStream {
+    format: StreamFormat,
+    pace: Pace,
+    sink: Option<SinkFn>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PullStream {
+    /// Number of frames we pull at once.
+    const CHUNK_FRAMES: usize = 1024;
+
+    fn new(
+        format: StreamFormat,
+        pace: Pace,
+        sink: impl FnMut(&[f32]) -> Result<()> + Send + 'static,
+    ) -> Self {
+        Self {
+            format,
+            pace,
+            sink: Some(Box::new(sink)),
+            stop: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        }
+    }
+}
+
+impl OutputStream for PullStream {
+    fn format(&self) -> StreamFormat {
+        self.format
+    }
+
+    fn start(&mut self, mut fill: FillCallback, _on_lost: LostCallback) -> Result<()> {
+        let mut sink = self.sink.take().ok_or_else(|| eyre!("output already started"))?;
+        let (format, pace, stop) = (self.format, self.pace, Arc::clone(&self.stop));
+        let chunk_duration =
+            Duration::from_secs_f64(Self::CHUNK_FRAMES as f64 / format.sample_rate as f64);
+        let thread = thread::Builder::new().name("output".into()).spawn(move || {
+            let mut buffer = vec![0.0; Self::CHUNK_FRAMES * format.channels];
+            let mut deadline = Instant::now();
+            while !stop.load(Ordering::Relaxed) {
+                // With real-time pacing, this chunk goes out once we've waited for the next one.
+                let latency = match pace {
+                    Pace::RealTime => {
+                        (deadline + chunk_duration).saturating_duration_since(Instant::now())
+                    }
+                    Pace::Unthrottled => Duration::ZERO,
+                };
+                let count = fill(&mut buffer, latency);
+                let result = match pace {
+                    Pace::RealTime => {
+                        deadline += chunk_duration;
+                        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                        sink(&buffer)
+                    }
+                    // Nothing's playing, so don't spin.
+                    Pace::Unthrottled if count == 0 => {
+                        thread::sleep(Duration::from_millis(1));
+                        Ok(())
+                    }
+                    Pace::Unthrottled => sink(&buffer[..count]),
+                };
+                if let Err(e) = result {
+                    error!("Error while writing audio out: {e}");
+                    return;
+                }
+            }
+        })?;
+        self.thread = Some(thread);
+        Ok(())
+    }
+}
+
+impl Drop for PullStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Minimal writer for 16-bit PCM WAV files. The header's lengths are filled in when it's dropped.
+#[derive(Debug)]
+struct WavWriter {
+    file: BufWriter<File>,
+    /// Number of bytes of sample data written so far.
+    data_len: u32,
+}
+
+impl WavWriter {
+    fn create(path: &Path, format: StreamFormat) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let channels = format.channels as u16;
+        let block_align = channels * 2;
+        file.write_all(b"RIFF")?;
+        file.write_all(&0u32.to_le_bytes())?; // filled in later
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&format.sample_rate.to_le_bytes())?;
+        file.write_all(&(format.sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?; // bits per sample
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?; // filled in later
+        Ok(Self { file, data_len: 0 })
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        for sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len = self.data_len.saturating_add(2 * samples.len() as u32);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + self.data_len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            error!("Couldn't finish writing WAV file: {e}");
+        }
+    }
+}
+
+/// Picks the output config that's the best match for audio with the given channel count and sample
+/// rate. We prefer, in order: the right number of channels, using f32 samples (since that's what we
+/// output, so nothing needs converting), and supporting the sample rate natively. If the sample
+/// rate isn't supported, we use the closest one the config does support.
+fn choose_config(
+    configs: impl IntoIterator<Item = SupportedStreamConfigRange>,
+    channels: u16,
+    sample_rate: u32,
+) -> Option<SupportedStreamConfig> {
+    let channel_rank = |config: &SupportedStreamConfigRange| match config.channels() {
+        c if c == channels => 0,
+        // Everything can be mapped to stereo without losing much.
+        2 => 1,
+        c if c > channels => 2,
+        _ => 3,
+    };
+    let config = configs.into_iter().min_by_key(|config| {
+        let supports_rate =
+            (config.min_sample_rate().0..=config.max_sample_rate().0).contains(&sample_rate);
+        (
+            channel_rank(config),
+            config.sample_format() != SampleFormat::F32,
+            !supports_rate,
+        )
+    })?;
+    let sample_rate = sample_rate.clamp(config.min_sample_rate().0, config.max_sample_rate().0);
+    Some(config.with_sample_rate(SampleRate(sample_rate)))
+}
+
+#[cfg(test)]
+mod tests {
+    use cpal::SupportedBufferSize;
+
+    use super::*;
+
+    fn config(
+        channels: u16,
+        rates: (u32, u32),
+        format: SampleFormat,
+    ) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            SampleRate(rates.0),
+            SampleRate(rates.1),
+            SupportedBufferSize::Unknown,
+            format,
+        )
+    }
+
+    #[test]
+    fn choose_config_prefers_supported_rate() {
+        let configs = [
+            config(2, (44100, 48000), SampleFormat::F32),
+            config(2, (44100, 192000), SampleFormat::F32),
+            config(2, (44100, 192000), SampleFormat::I16),
+        ];
+        let chosen = choose_config(configs, 2, 96000).unwrap();
+        assert_eq!(chosen.sample_rate(), SampleRate(96000));
+        assert_eq!(chosen.sample_format(), SampleFormat::F32);
+    }
+
+    #[test]
+    fn choose_config_clamps_rate() {
+        let configs = [config(2, (44100, 48000), SampleFormat::F32)];
+        let chosen = choose_config(configs, 2, 88200).unwrap();
+        assert_eq!(chosen.sample_rate(), SampleRate(48000));
+    }
+
+    #[test]
+    fn choose_config_falls_back_to_stereo() {
+        let configs = || {
+            [
+                config(8, (44100, 48000), SampleFormat::F32),
+                config(2, (44100, 48000), SampleFormat::F32),
+            ]
+        };
+        assert_eq!(choose_config(configs(), 1, 44100).unwrap().channels(), 2);
+        assert_eq!(choose_config(configs(), 6, 44100).unwrap().channels(), 2);
+    }
+
+    #[test]
+    fn choose_config_without_f32() {
+        // Plenty of ALSA hardware devices only take integer samples.
+        let configs = [
+            config(2, (44100, 48000), SampleFormat::I16),
+            config(1, (44100, 48000), SampleFormat::F32),
+        ];
+        let chosen = choose_config(configs, 2, 44100).unwrap();
+        assert_eq!(chosen.channels(), 2);
+        assert_eq!(chosen.sample_format(), SampleFormat::I16);
+    }
+
+    #[test]
+    fn wav_header() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("deimos-test-{}.wav", std::process::id()));
+        let format = StreamFormat {
+            channels: 2,
+            sample_rate: 48000,
+        };
+        {
+            let mut writer = WavWriter::create(&path, format)?;
+            writer.write(&[0.0, 1.0, -1.0, 0.5])?;
+        }
+        let bytes = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(u32_at(4), 36 + 8);
+        assert_eq!(u32_at(24), 48000);
+        assert_eq!(u32_at(40), 8);
+        assert_eq!(i16::from_le_bytes([bytes[46], bytes[47]]), i16::MAX);
+        Ok(())
+    }
+
+    #[test]
+    fn wav_survives_reopening() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("deimos-reopen-{}.wav", std::process::id()));
+        let format = StreamFormat {
+            channels: 1,
+            sample_rate: 8000,
+        };
+        let backend = WavBackend::new(path.clone(), format, Pace::Unthrottled);
+        for _ in 0..2 {
+            let _stream = backend.open(None)?;
+            let mut writer = backend.writer.lock().unwrap();
+            writer.as_mut().unwrap().write(&[0.5; 3])?;
+        }
+        drop(backend);
+        let bytes = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(bytes.len(), 44 + 12, "both streams' audio is kept");
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 12);
+        Ok(())
+    }
+}
@@ -1,16 +1,52 @@
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
 use mpris_server::LoopStatus;
 
 use crate::library::Track;
 
+use super::shuffle::ShuffleMode;
+
+/// Where to stop when tracks finish on their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopAfter {
+    Track,
+    /// Stop once the next track would be from a different album.
+    Album,
+}
+
+impl StopAfter {
+    pub fn name(self) -> &'static str {
+        match self {
+            StopAfter::Track => "track",
+            StopAfter::Album => "album",
+        }
+    }
+}
+
+/// Where [`PlayQueue::revision`]s come from. Sharing one counter between all queues means that
+/// replacing a queue with a new one also changes the revision.
+static NEXT_REVISION: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug)]
 pub struct PlayQueue {
     index: Option<usize>,
     tracks: Vec<Arc<Track>>,
     loop_status: LoopStatus,
     shuffled: bool,
+    /// How we shuffle. This is remembered even when we aren't shuffled.
+    shuffle_mode: ShuffleMode,
     original_order: Vec<Arc<Track>>,
+    stop_after: Option<StopAfter>,
+    /// How many more times to play the current track once it finishes. This is reset when the
+    /// current track changes.
+    repeats_left: u32,
+    revision: u64,
 }
 
 impl PlayQueue {
@@ -21,10 +57,53 @@ impl PlayQueue {
             tracks,
             loop_status: LoopStatus::None,
             shuffled: false,
+            shuffle_mode: ShuffleMode::default(),
             original_order,
+            stop_after: None,
+            repeats_left: 0,
+            revision: next_revision(),
         }
     }
 
+    /// Recreates a queue from its parts, e.g. when restoring a session. `original_order` should
+    /// contain the same tracks as `tracks`; it's only used when unshuffling.
+    pub fn from_parts(
+        tracks: Vec<Arc<Track>>,
+        original_order: Vec<Arc<Track>>,
+        index: Option<usize>,
+        loop_status: LoopStatus,
+        shuffled: bool,
+        shuffle_mode: ShuffleMode,
+    ) -> Self {
+        Self {
+            index: index.filter(|i| *i < tracks.len()),
+            tracks,
+            loop_status,
+            shuffled,
+            shuffle_mode,
+            original_order,
+            stop_after: None,
+            repeats_left: 0,
+            revision: next_revision(),
+        }
+    }
+
+    /// Changes whenever the tracks or their order do, so that watchers can tell when to look at
+    /// them again without comparing the whole list.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// The tracks in the order they'll be played.
+    pub fn tracks(&self) -> &[Arc<Track>] {
+        &self.tracks
+    }
+
+    /// The tracks in the order they were added, regardless of shuffling.
+    pub fn original_order(&self) -> &[Arc<Track>] {
+        &self.original_order
+    }
+
     pub fn len(&self) -> usize {
         self.tracks.len()
     }
@@ -34,6 +113,9 @@ impl PlayQueue {
     }
 
     pub fn set_current(&mut self, current: Option<usize>) {
+        if current != self.index {
+            self.repeats_left = 0;
+        }
         self.index = current;
     }
 
@@ -49,16 +131,50 @@ impl PlayQueue {
         self.shuffled
     }
 
-    pub fn set_shuffle(&mut self, shuffle: bool) {
+    pub fn shuffle_mode(&self) -> ShuffleMode {
+        self.shuffle_mode
+    }
+
+    /// Turns shuffling on or off. `weight` is only used by [`ShuffleMode::Weighted`].
+    pub fn set_shuffle(&mut self, shuffle: bool, weight: &dyn Fn(&Track) -> f64) {
         if shuffle == self.shuffle() {
             return;
         }
         self.shuffled = shuffle;
+        self.reorder(weight);
+    }
+
+    /// Changes how we shuffle. If we're already shuffled, this reshuffles the queue.
+    pub fn set_shuffle_mode(&mut self, mode: ShuffleMode, weight: &dyn Fn(&Track) -> f64) {
+        if mode == self.shuffle_mode {
+            return;
+        }
+        self.shuffle_mode = mode;
+        if self.shuffled {
+            self.reorder(weight);
+        }
+    }
+
+    /// Shuffles from the original order, or goes back to it if we aren't shuffled. The current
+    /// track stays current.
+    fn reorder(&mut self, weight: &dyn Fn(&Track) -> f64) {
+        self.revision = next_revision();
         let current_track = self.current_track();
-        if shuffle {
-            fastrand::shuffle(&mut self.tracks);
-        } else {
-            self.tracks = self.original_order.clone();
+        self.tracks = self.original_order.clone();
+        if self.shuffled && self.shuffle_mode != ShuffleMode::Albums {
+            if let Some(current_track) = &current_track {
+                // The current track goes first, so shuffle everything else to come after it. That
+                // way artist spread knows which artist to avoid next.
+                let index = self.tracks.iter().position(|track| track.id == current_track.id);
+                let current = self.tracks.remove(index.expect("current track is in the queue"));
+                self.shuffle_mode.apply_after(&mut self.tracks, Some(&current), weight);
+                self.tracks.insert(0, current);
+                self.index = Some(0);
+                return;
+            }
+        }
+        if self.shuffled {
+            self.shuffle_mode.apply(&mut self.tracks, weight);
         }
         let Some(current_track) = current_track else {
             return;
@@ -68,13 +184,19 @@ impl PlayQueue {
             .iter()
             .position(|track| track.id == current_track.id)
             .expect("couldn't find track after shuffling");
-        // If we just shuffled, we need to move the current track to the front.
-        if shuffle {
-            self.tracks.swap(0, new_index);
-            self.index = Some(0);
-        } else {
+        if !self.shuffled {
             self.index = Some(new_index);
+            return;
         }
+        // With album shuffle, the current track's whole album goes to the front so that the rest
+        // of the album still follows.
+        let same_album =
+            |t: &&Arc<Track>| t.artist == current_track.artist && t.album == current_track.album;
+        let before = self.tracks[..new_index].iter().rev().take_while(same_album).count();
+        let after = self.tracks[new_index + 1..].iter().take_while(same_album).count();
+        let (start, end) = (new_index - before, new_index + 1 + after);
+        self.tracks[..end].rotate_right(end - start);
+        self.index = Some(new_index - start);
     }
 
     pub fn current_track(&self) -> Option<Arc<Track>> {
@@ -90,6 +212,51 @@ impl PlayQueue {
         }
     }
 
+    pub fn stop_after(&self) -> Option<StopAfter> {
+        self.stop_after
+    }
+
+    pub fn set_stop_after(&mut self, stop_after: Option<StopAfter>) {
+        self.stop_after = stop_after;
+    }
+
+    pub fn repeats_left(&self) -> u32 {
+        self.repeats_left
+    }
+
+    /// Plays the current track `repeats` more times after this one before moving on.
+    pub fn set_repeats(&mut self, repeats: u32) {
+        self.repeats_left = repeats;
+    }
+
+    /// Where to go when the current track finishes on its own. Unlike [`PlayQueue::next`], this
+    /// takes repeats and stopping into account, and uses them up. The second value is true if we
+    /// should stop there, in which case the track should be selected but not played.
+    pub fn finish(&mut self) -> (Option<usize>, bool) {
+        if self.repeats_left > 0 {
+            self.repeats_left -= 1;
+            return (self.index, false);
+        }
+        let next = self.next();
+        match self.stop_after {
+            None => (next, false),
+            Some(StopAfter::Track) => {
+                self.stop_after = None;
+                (next, true)
+            }
+            Some(StopAfter::Album) => {
+                let album_of =
+                    |i: Option<usize>| i.map(|i| (&self.tracks[i].artist, &self.tracks[i].album));
+                if next.is_some() && album_of(next) == album_of(self.index) {
+                    (next, false)
+                } else {
+                    self.stop_after = None;
+                    (next, true)
+                }
+            }
+        }
+    }
+
     /// Index of the next track. `None` if this would go off the end.
     pub fn next(&self) -> Option<usize> {
         match self.loop_status {
@@ -101,6 +268,14 @@ impl PlayQueue {
         }
     }
 
+    /// Like [`PlayQueue::next`], but never the current track, even if that's being looped.
+    pub fn next_other(&self) -> Option<usize> {
+        match self.loop_status {
+            LoopStatus::Track => self.index.map(|i| i + 1).filter(|i| *i < self.tracks.len()),
+            _ => self.next(),
+        }
+    }
+
     pub fn is_empty(&self) -> bool {
         self.tracks.is_empty()
     }
@@ -108,9 +283,135 @@ impl PlayQueue {
     pub fn push(&mut self, track: Arc<Track>) {
         self.original_order.push(Arc::clone(&track));
         self.tracks.push(track);
+        self.revision = next_revision();
+    }
+
+    /// Inserts a track at `index`, moving everything from there on back by one. The current track
+    /// stays current.
+    pub fn insert(&mut self, index: usize, track: Arc<Track>) {
+        let index = index.min(self.tracks.len());
+        if self.shuffled {
+            let position = self
+                .tracks
+                .get(index)
+                .and_then(|next| self.original_position(next))
+                .unwrap_or(self.original_order.len());
+            self.original_order.insert(position, Arc::clone(&track));
+        }
+        self.tracks.insert(index, track);
+        self.revision = next_revision();
+        self.index = self.index.map(|i| if i >= index { i + 1 } else { i });
+        self.sync_original_order();
+    }
+
+    /// Inserts a track so that it plays right after the current one. If nothing is playing, it
+    /// goes at the front.
+    pub fn insert_next(&mut self, track: Arc<Track>) {
+        self.insert(self.index.map_or(0, |i| i + 1), track);
+    }
+
+    /// Removes the track at `index`. If that was the current track, the one after it becomes
+    /// current, or nothing if it was the last one.
+    pub fn remove(&mut self, index: usize) -> Option<Arc<Track>> {
+        if index >= self.tracks.len() {
+            return None;
+        }
+        let track = self.tracks.remove(index);
+        self.revision = next_revision();
+        self.index = match self.index {
+            Some(i) if i > index => Some(i - 1),
+            Some(i) if i == index => Some(i).filter(|i| *i < self.tracks.len()),
+            i => i,
+        };
+        self.remove_from_original_order(std::slice::from_ref(&track));
+        Some(track)
+    }
+
+    /// Moves the track at `from` so that it ends up at `to`. The current track stays current. In a
+    /// shuffled queue, this doesn't affect the order we go back to when unshuffling.
+    pub fn move_track(&mut self, from: usize, to: usize) {
+        if from >= self.tracks.len() || to >= self.tracks.len() {
+            return;
+        }
+        let track = self.tracks.remove(from);
+        self.tracks.insert(to, track);
+        self.revision = next_revision();
+        self.index = self.index.map(|i| match i {
+            i if i == from => to,
+            i if from < i && i <= to => i - 1,
+            i if to <= i && i < from => i + 1,
+            i => i,
+        });
+        self.sync_original_order();
+    }
+
+    /// Removes every track. This also stops playback.
+    pub fn clear(&mut self) {
+        self.tracks.clear();
+        self.original_order.clear();
+        self.index = None;
+        self.revision = next_revision();
+    }
+
+    /// Removes every track after the current one. If nothing is playing, removes everything.
+    pub fn clear_upcoming(&mut self) {
+        let removed = self.tracks.split_off(self.index.map_or(0, |i| i + 1));
+        self.revision = next_revision();
+        self.remove_from_original_order(&removed);
+    }
+
+    /// Removes repeated tracks, keeping the first copy of each. The current track always stays
+    /// where it is, even if it's a repeat. Returns how many tracks were removed.
+    pub fn dedupe(&mut self) -> usize {
+        let current = self.index;
+        let mut seen: HashSet<_> = self.current_track().map(|t| t.id).into_iter().collect();
+        let len = self.tracks.len();
+        let mut kept = Vec::with_capacity(len);
+        for (i, track) in self.tracks.drain(..).enumerate() {
+            if Some(i) == current {
+                self.index = Some(kept.len());
+                kept.push(track);
+            } else if seen.insert(track.id) {
+                kept.push(track);
+            }
+        }
+        self.tracks = kept;
+        self.revision = next_revision();
+        let mut seen = HashSet::new();
+        self.original_order.retain(|t| seen.insert(t.id));
+        self.sync_original_order();
+        len - self.tracks.len()
+    }
+
+    fn original_position(&self, track: &Track) -> Option<usize> {
+        self.original_order.iter().position(|t| t.id == track.id)
+    }
+
+    /// Removes one copy of each of `tracks` from `original_order`.
+    fn remove_from_original_order(&mut self, tracks: &[Arc<Track>]) {
+        if !self.shuffled {
+            self.sync_original_order();
+            return;
+        }
+        for track in tracks {
+            if let Some(i) = self.original_position(track) {
+                self.original_order.remove(i);
+            }
+        }
+    }
+
+    /// When we aren't shuffled, the original order is just the play order.
+    fn sync_original_order(&mut self) {
+        if !self.shuffled {
+            self.original_order = self.tracks.clone();
+        }
     }
 }
 
+fn next_revision() -> u64 {
+    NEXT_REVISION.fetch_add(1, Ordering::Relaxed)
+}
+
 impl Default for PlayQueue {
     fn default() -> Self {
         Self::new(vec![])
@@ -120,6 +421,7 @@ impl Default for PlayQueue {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::library::{AlbumName, ArtistName};
 
     fn sample_queue() -> PlayQueue {
         PlayQueue::new(vec![
@@ -151,6 +453,142 @@ mod tests {
         assert_eq!(queue.previous(), Some(1));
     }
 
+    fn ids(tracks: &[Arc<Track>]) -> Vec<u64> {
+        tracks.iter().map(|t| t.id).collect()
+    }
+
+    #[test]
+    fn insert_next() {
+        let mut queue = sample_queue();
+        queue.insert_next(Arc::new(Track::test_track(3)));
+        assert_eq!(ids(queue.tracks()), [3, 0, 1, 2], "should go first when stopped");
+        queue.set_current(Some(2));
+        queue.insert_next(Arc::new(Track::test_track(4)));
+        assert_eq!(ids(queue.tracks()), [3, 0, 1, 4, 2]);
+        assert_eq!(ids(queue.original_order()), [3, 0, 1, 4, 2]);
+        assert_eq!(queue.next().map(|i| queue.tracks()[i].id), Some(4));
+        queue.insert(0, Arc::new(Track::test_track(5)));
+        assert_eq!(queue.current_track().map(|t| t.id), Some(1), "current track should stay");
+    }
+
+    #[test]
+    fn remove() {
+        let mut queue = sample_queue();
+        queue.set_current(Some(1));
+        assert_eq!(queue.remove(0).map(|t| t.id), Some(0));
+        assert_eq!(queue.current_track().map(|t| t.id), Some(1));
+        assert_eq!(queue.remove(0).map(|t| t.id), Some(1));
+        assert_eq!(
+            queue.current_track().map(|t| t.id),
+            Some(2),
+            "removing the current track should move on to the next one"
+        );
+        assert_eq!(queue.remove(0).map(|t| t.id), Some(2));
+        assert_eq!(queue.current(), None);
+        assert_eq!(queue.remove(0), None);
+        assert!(queue.original_order().is_empty());
+    }
+
+    #[test]
+    fn move_track() {
+        let mut queue = sample_queue();
+        queue.set_current(Some(1));
+        queue.move_track(0, 2);
+        assert_eq!(ids(queue.tracks()), [1, 2, 0]);
+        assert_eq!(queue.current_track().map(|t| t.id), Some(1));
+        queue.move_track(0, 1);
+        assert_eq!(ids(queue.tracks()), [2, 1, 0]);
+        assert_eq!(queue.current_track().map(|t| t.id), Some(1));
+        assert_eq!(ids(queue.original_order()), [2, 1, 0]);
+    }
+
+    #[test]
+    fn clear_upcoming_and_dedupe() {
+        let mut queue = sample_queue();
+        for id in [0, 1, 1, 3] {
+            queue.push(Arc::new(Track::test_track(id)));
+        }
+        queue.set_current(Some(4));
+        assert_eq!(queue.dedupe(), 3);
+        assert_eq!(ids(queue.tracks()), [0, 2, 1, 3]);
+        assert_eq!(queue.current_track().map(|t| t.id), Some(1));
+        queue.clear_upcoming();
+        assert_eq!(ids(queue.tracks()), [0, 2, 1]);
+        assert_eq!(ids(queue.original_order()), [0, 2, 1]);
+    }
+
+    #[test]
+    fn revision_tracks_edits() {
+        let mut queue = sample_queue();
+        let mut last = queue.revision();
+        let mut changed = |queue: &PlayQueue| {
+            let changed = queue.revision() != last;
+            last = queue.revision();
+            changed
+        };
+        queue.set_current(Some(1));
+        queue.set_loop_status(LoopStatus::Playlist);
+        assert!(!changed(&queue));
+        queue.move_track(0, 2);
+        assert!(changed(&queue));
+        queue.push(Arc::new(Track::test_track(3)));
+        assert!(changed(&queue));
+        queue.set_shuffle(true, &|_| 1.0);
+        assert!(changed(&queue));
+        assert_ne!(sample_queue().revision(), queue.revision());
+    }
+
+    #[test]
+    fn edits_while_shuffled() {
+        for _ in 0..10 {
+            let mut queue = shuffle_test_queue();
+            queue.set_current(Some(10));
+            queue.set_shuffle(true, &|_| 1.0);
+            queue.insert_next(Arc::new(Track::test_track(100)));
+            queue.remove(5);
+            queue.move_track(3, 7);
+            let mut tracks = ids(queue.tracks());
+            let mut original = ids(queue.original_order());
+            tracks.sort();
+            original.sort();
+            assert_eq!(tracks, original, "original order should have the same tracks");
+            let track = queue.current_track();
+            queue.set_shuffle(false, &|_| 1.0);
+            assert_eq!(queue.current_track(), track);
+        }
+    }
+
+    #[test]
+    fn stop_after_and_repeat() {
+        let mut queue = sample_queue();
+        queue.set_current(Some(0));
+        queue.set_repeats(2);
+        assert_eq!(queue.finish(), (Some(0), false));
+        assert_eq!(queue.finish(), (Some(0), false));
+        assert_eq!(queue.finish(), (Some(1), false));
+        queue.set_current(Some(1));
+        queue.set_repeats(5);
+        queue.set_current(Some(1));
+        assert_eq!(queue.repeats_left(), 5, "staying on the same track shouldn't reset repeats");
+        queue.set_current(Some(2));
+        assert_eq!(queue.repeats_left(), 0);
+
+        queue.set_current(Some(0));
+        queue.set_stop_after(Some(StopAfter::Track));
+        assert_eq!(queue.finish(), (Some(1), true));
+        assert_eq!(queue.stop_after(), None, "stopping should only happen once");
+
+        // the sample tracks are all from the same album
+        queue.tracks.push(Arc::new(Track {
+            album: AlbumName(Some("Other album".into())),
+            ..Track::test_track(3)
+        }));
+        queue.set_stop_after(Some(StopAfter::Album));
+        assert_eq!(queue.finish(), (Some(1), false));
+        queue.set_current(Some(2));
+        assert_eq!(queue.finish(), (Some(3), true));
+    }
+
     #[test]
     fn track_looping() {
         let mut queue = sample_queue();
@@ -183,7 +621,7 @@ mod tests {
             let mut queue = shuffle_test_queue();
             queue.set_current(Some(20));
             let track = queue.current_track();
-            queue.set_shuffle(true);
+            queue.set_shuffle(true, &|_| 1.0);
             assert_eq!(
                 queue.current_track(),
                 track,
@@ -202,8 +640,8 @@ mod tests {
         for _ in 0..10 {
             let mut queue = shuffle_test_queue();
             let original = queue.tracks.clone();
-            queue.set_shuffle(true);
-            queue.set_shuffle(false);
+            queue.set_shuffle(true, &|_| 1.0);
+            queue.set_shuffle(false, &|_| 1.0);
             assert_eq!(queue.tracks, original);
         }
     }
@@ -212,11 +650,11 @@ mod tests {
     fn order_after_unshuffle() {
         for _ in 0..10 {
             let mut queue = shuffle_test_queue();
-            queue.set_shuffle(true);
+            queue.set_shuffle(true, &|_| 1.0);
             queue.set_current(Some(20));
             let track = queue.current_track();
             dbg!(&queue);
-            queue.set_shuffle(false);
+            queue.set_shuffle(false, &|_| 1.0);
             dbg!(&queue);
             assert_eq!(
                 queue.current_track(),
@@ -225,4 +663,43 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn album_shuffle_keeps_current_album_first() {
+        for _ in 0..10 {
+            let mut queue = PlayQueue::default();
+            for i in 0..20 {
+                let mut track = Track::test_track(i);
+                track.album = AlbumName(Some(format!("album {}", i / 5)));
+                queue.push(Arc::new(track));
+            }
+            queue.set_current(Some(12));
+            queue.set_shuffle_mode(ShuffleMode::Albums, &|_| 1.0);
+            queue.set_shuffle(true, &|_| 1.0);
+            assert_eq!(ids(&queue.tracks()[..5]), [10, 11, 12, 13, 14]);
+            assert_eq!(queue.current(), Some(2));
+            queue.set_shuffle_mode(ShuffleMode::Tracks, &|_| 1.0);
+            assert_eq!(queue.current(), Some(0), "changing modes should reshuffle");
+            assert_eq!(queue.current_track().map(|t| t.id), Some(12));
+        }
+    }
+
+    #[test]
+    fn artist_spread_accounts_for_current_track() {
+        for _ in 0..10 {
+            let mut queue = PlayQueue::default();
+            for i in 0..12 {
+                let mut track = Track::test_track(i);
+                track.artist = ArtistName::Artist(format!("artist {}", i % 3));
+                queue.push(Arc::new(track));
+            }
+            queue.set_current(Some(4));
+            queue.set_shuffle_mode(ShuffleMode::ArtistSpread, &|_| 1.0);
+            queue.set_shuffle(true, &|_| 1.0);
+            assert_eq!(queue.current_track().map(|t| t.id), Some(4));
+            assert_eq!(queue.current(), Some(0));
+            let tracks = queue.tracks();
+            assert!(tracks.windows(2).all(|w| w[0].artist != w[1].artist), "{tracks:?}");
+        }
+    }
 }
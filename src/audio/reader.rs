@@ -1,10 +1,11 @@
 use std::{fs::File, path::Path, time::Duration};
 
-use eyre::{bail, eyre, Result};
+use eyre::{bail, eyre, Report, Result};
 use symphonia::{
     core::{
-        audio::AudioBuffer,
+        audio::{AudioBuffer, Signal},
         codecs::{Decoder, DecoderOptions},
+        errors::Error as SymphoniaError,
         formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
         io::MediaSourceStream,
         meta::MetadataOptions,
@@ -20,6 +21,11 @@ pub struct SymphoniaReader {
     format: Box<dyn FormatReader>,
     channels: usize,
     sample_rate: u32,
+    /// After a seek, audio from before this timestamp (in the track's time base) gets thrown
+    /// away, so that we start at exactly the right sample.
+    skip_until: u64,
+    /// Why we stopped early, if we gave up because of decode errors rather than reaching the end.
+    error: Option<Report>,
 }
 
 /// A decoded audio buffer with some extra context information.
@@ -68,6 +74,8 @@ impl SymphoniaReader {
             format: probed.format,
             channels,
             sample_rate,
+            skip_until: 0,
+            error: None,
         })
     }
 
@@ -85,25 +93,51 @@ impl SymphoniaReader {
         self.sample_rate
     }
 
+    /// If we stopped returning fragments because of errors, takes the last one.
+    pub(super) fn take_error(&mut self) -> Option<Report> {
+        self.error.take()
+    }
+
     /// Try to decode a single packet. Semantics are the same as `next`.
     fn try_decode(&mut self) -> Result<Fragment> {
-        let packet = self.format.next_packet()?;
+        loop {
+            let packet = self.format.next_packet()?;
+            let end = packet.ts + packet.dur;
+            // Packets before the seek target still need decoding, since decoders can depend on
+            // earlier packets.
+            let decoded = self.decoder.decode(&packet)?;
+            if end <= self.skip_until {
+                continue;
+            }
+            let mut buffer = decoded.make_equivalent::<f32>();
+            decoded.convert(&mut buffer);
+            if packet.ts < self.skip_until {
+                let skip = self.ts_to_frames(self.skip_until - packet.ts);
+                buffer.shift(skip.min(buffer.frames()));
+            }
+            self.skip_until = 0;
 
-        // compute timestamp
-        let time_base = self.decoder.codec_params().time_base.unwrap();
-        let timestamp = time_base.calc_time(packet.ts + packet.dur);
-        let timestamp = Duration::from_secs_f64(timestamp.seconds as f64 + timestamp.frac);
+            // compute timestamp
+            let time_base = self.decoder.codec_params().time_base.unwrap();
+            let timestamp = time_base.calc_time(end);
+            let timestamp = Duration::from_secs_f64(timestamp.seconds as f64 + timestamp.frac);
 
-        let decoded = self.decoder.decode(&packet)?;
-        let mut buffer = decoded.make_equivalent::<f32>();
-        decoded.convert(&mut buffer);
+            return Ok(Fragment { buffer, timestamp });
+        }
+    }
 
-        Ok(Fragment { buffer, timestamp })
+    /// Converts a duration in the track's time base to a number of frames.
+    fn ts_to_frames(&self, ts: u64) -> usize {
+        let time_base = self.decoder.codec_params().time_base.unwrap();
+        (ts * u64::from(time_base.numer) * u64::from(self.sample_rate) / u64::from(time_base.denom))
+            as usize
     }
 
+    /// Seeks so that the next fragment starts exactly at `target`, or as close as the format
+    /// allows.
     pub(super) fn seek(&mut self, target: Duration) -> Result<()> {
         let target = Time::new(target.as_secs(), target.as_secs_f64().fract());
-        self.format.seek(
+        let seeked = self.format.seek(
             SeekMode::Accurate,
             SeekTo::Time {
                 time: target,
@@ -112,6 +146,7 @@ impl SymphoniaReader {
         )?;
         // necessary to do this any time there's a seek
         self.decoder.reset();
+        self.skip_until = seeked.required_ts;
         Ok(())
     }
 }
@@ -119,23 +154,37 @@ impl SymphoniaReader {
 impl Iterator for SymphoniaReader {
     type Item = Fragment;
 
-    /// Decode the buffer out of the next packet. Returns None if there was a decode error and on EOF.
+    /// Decode the buffer out of the next packet. Returns None on EOF, and if there were too many
+    /// decode errors in a row; [`SymphoniaReader::take_error`] tells the two apart.
     fn next(&mut self) -> Option<Fragment> {
+        let mut last_error = None;
         for _ in 0..MAX_DECODE_ERRORS {
             match self.try_decode() {
                 Ok(out) => return Some(out),
-                Err(_) => continue,
+                Err(e) if is_eof(&e) => return None,
+                Err(e) => last_error = Some(e),
             }
         }
+        self.error = last_error;
         None
     }
 }
 
+/// True if the error is from running out of packets, which is how Symphonia signals the end of
+/// the track.
+fn is_eof(error: &Report) -> bool {
+    matches!(
+        error.downcast_ref::<SymphoniaError>(),
+        Some(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+    )
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
+    use std::{io::Cursor, path::PathBuf};
 
     use super::*;
+    use crate::test_data;
 
     #[test]
     fn test_timestamp() {
@@ -147,4 +196,18 @@ mod tests {
         let last = reader.last().unwrap();
         assert_eq!(last.timestamp, Duration::from_secs(3));
     }
+
+    #[test]
+    fn sample_accurate_seek() -> Result<()> {
+        let path = test_data!("3_seconds.mp3");
+        let reader = SymphoniaReader::from_path(&path)?;
+        let sample_rate = reader.sample_rate() as usize;
+        let total: usize = reader.map(|f| f.buffer.frames()).sum();
+
+        let mut reader = SymphoniaReader::from_path(&path)?;
+        reader.seek(Duration::from_millis(1234))?;
+        let after: usize = reader.map(|f| f.buffer.frames()).sum();
+        assert_eq!(total - after, sample_rate * 1234 / 1000);
+        Ok(())
+    }
 }
@@ -0,0 +1,185 @@
+use std::sync::{
+    atomic::{AtomicU32, AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Creates a single-producer, single-consumer ring buffer of samples that can hold `capacity`
+/// samples. Neither side ever blocks or allocates, so the consumer is safe to use from a real-time
+/// audio callback.
+pub fn ring_buffer(capacity: usize) -> (Producer, Consumer) {
+    assert!(capacity > 0, "ring buffer must have nonzero capacity");
+    let inner = Arc::new(Inner {
+        data: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+        read: AtomicUsize::new(0),
+        write: AtomicUsize::new(0),
+        discard_until: AtomicUsize::new(0),
+    });
+    (
+        Producer {
+            inner: Arc::clone(&inner),
+        },
+        Consumer { inner },
+    )
+}
+
+struct Inner {
+    /// Samples are stored as their bit patterns so that we can use atomics instead of unsafe code.
+    data: Box<[AtomicU32]>,
+    /// Total number of samples ever read. Only written by the consumer.
+    read: AtomicUsize,
+    /// Total number of samples ever written. Only written by the producer.
+    write: AtomicUsize,
+    /// If the consumer's read position is behind this, it skips forward to it. This is how the
+    /// producer throws away samples it already wrote.
+    discard_until: AtomicUsize,
+}
+
+impl Inner {
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// The writing half of a [`ring_buffer`].
+pub struct Producer {
+    inner: Arc<Inner>,
+}
+
+impl Producer {
+    /// Writes as many samples from the front of `samples` as will fit, returning how many that was.
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        let write = self.inner.write.load(Ordering::Relaxed);
+        let count = samples.len().min(self.free());
+        for (i, sample) in samples[..count].iter().enumerate() {
+            self.inner.data[(write + i) % self.inner.capacity()]
+                .store(sample.to_bits(), Ordering::Relaxed);
+        }
+        self.inner.write.store(write + count, Ordering::Release);
+        count
+    }
+
+    /// Number of samples that have been written but not yet read or discarded.
+    pub fn len(&self) -> usize {
+        let write = self.inner.write.load(Ordering::Relaxed);
+        let read = self.inner.read.load(Ordering::Acquire);
+        write - read.max(self.inner.discard_until.load(Ordering::Relaxed))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total number of samples ever written. This is also the index that the next sample written
+    /// will have, which is what [`Consumer::pop`] counts in.
+    pub fn written(&self) -> usize {
+        self.inner.write.load(Ordering::Relaxed)
+    }
+
+    /// Number of samples that can be written right now. Discarded samples keep taking up space
+    /// until the consumer gets around to skipping them.
+    pub fn free(&self) -> usize {
+        let write = self.inner.write.load(Ordering::Relaxed);
+        let read = self.inner.read.load(Ordering::Acquire);
+        self.inner.capacity() - (write - read)
+    }
+
+    /// Throws away everything that's been written but not read yet.
+    pub fn discard(&mut self) {
+        let write = self.inner.write.load(Ordering::Relaxed);
+        self.inner.discard_until.store(write, Ordering::Release);
+    }
+}
+
+/// The reading half of a [`ring_buffer`].
+pub struct Consumer {
+    inner: Arc<Inner>,
+}
+
+impl Consumer {
+    /// Reads as many samples as are available into the front of `out`, first skipping past any the
+    /// producer discarded. Returns the index of the first sample read, counting every sample ever
+    /// written, and how many samples were read. Popping into an empty `out` still frees up the
+    /// space discarded samples were taking.
+    pub fn pop(&mut self, out: &mut [f32]) -> (usize, usize) {
+        let read = self.inner.read.load(Ordering::Relaxed);
+        let write = self.inner.write.load(Ordering::Acquire);
+        // This has to be loaded after `write`. Otherwise, if the producer discards and then writes
+        // in between, we'd see the new samples without seeing that the old ones were discarded.
+        let read = read.max(self.inner.discard_until.load(Ordering::Acquire));
+        let count = out.len().min(write - read);
+        for (i, sample) in out[..count].iter_mut().enumerate() {
+            *sample = f32::from_bits(
+                self.inner.data[(read + i) % self.inner.capacity()].load(Ordering::Relaxed),
+            );
+        }
+        self.inner.read.store(read + count, Ordering::Release);
+        (read, count)
+    }
+
+    /// True if the producer discarded samples that haven't been skipped yet, meaning the audio is
+    /// about to jump.
+    pub fn has_discarded(&self) -> bool {
+        self.inner.discard_until.load(Ordering::Acquire) > self.inner.read.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_pop() {
+        let (mut producer, mut consumer) = ring_buffer(4);
+        assert_eq!(producer.push(&[1.0, 2.0, 3.0]), 3);
+        assert_eq!(producer.push(&[4.0, 5.0]), 1, "should only write until full");
+        assert_eq!(producer.len(), 4);
+
+        let mut out = [0.0; 3];
+        assert_eq!(consumer.pop(&mut out), (0, 3));
+        assert_eq!(out, [1.0, 2.0, 3.0]);
+
+        // wraps around
+        assert_eq!(producer.push(&[5.0, 6.0]), 2);
+        let mut out = [0.0; 5];
+        assert_eq!(consumer.pop(&mut out), (3, 3));
+        assert_eq!(out[..3], [4.0, 5.0, 6.0]);
+        assert!(producer.is_empty());
+    }
+
+    #[test]
+    fn discard() {
+        let (mut producer, mut consumer) = ring_buffer(4);
+        producer.push(&[1.0, 2.0]);
+        producer.discard();
+        assert!(producer.is_empty());
+        producer.push(&[3.0]);
+        let mut out = [0.0; 4];
+        assert_eq!(consumer.pop(&mut out), (2, 1), "skips the discarded samples");
+        assert_eq!(out[0], 3.0);
+        assert_eq!(producer.free(), 4);
+    }
+
+    #[test]
+    fn threaded() {
+        let (mut producer, mut consumer) = ring_buffer(64);
+        let samples = (0..10_000).map(|i| i as f32).collect::<Vec<_>>();
+        let expected = samples.clone();
+        let writer = std::thread::spawn(move || {
+            let mut rest = samples.as_slice();
+            while !rest.is_empty() {
+                let written = producer.push(&rest[..rest.len().min(17)]);
+                rest = &rest[written..];
+                std::thread::yield_now();
+            }
+        });
+        let mut received = vec![];
+        let mut out = [0.0; 13];
+        while received.len() < expected.len() {
+            let (_, count) = consumer.pop(&mut out);
+            received.extend_from_slice(&out[..count]);
+            std::thread::yield_now();
+        }
+        writer.join().unwrap();
+        assert_eq!(received, expected);
+    }
+}
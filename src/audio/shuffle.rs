@@ -0,0 +1,211 @@
+use std::{collections::HashMap, sync::Arc};
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::library::{AlbumName, ArtistName, Track};
+
+/// How the play queue gets reordered when shuffling.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShuffleMode {
+    /// Every track is shuffled independently.
+    #[default]
+    Tracks,
+    /// Albums are played in a random order, but each album's tracks stay in order.
+    Albums,
+    /// Tracks are shuffled, avoiding playing the same artist twice in a row where possible.
+    ArtistSpread,
+    /// Tracks with a higher weight tend to come earlier.
+    Weighted,
+}
+
+/// How much to favor a track in [`ShuffleMode::Weighted`]. Must be positive.
+pub type ShuffleWeight = Arc<dyn Fn(&Track) -> f64 + Send + Sync>;
+
+impl ShuffleMode {
+    pub fn next(self) -> Self {
+        match self {
+            ShuffleMode::Tracks => ShuffleMode::Albums,
+            ShuffleMode::Albums => ShuffleMode::ArtistSpread,
+            ShuffleMode::ArtistSpread => ShuffleMode::Weighted,
+            ShuffleMode::Weighted => ShuffleMode::Tracks,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ShuffleMode::Tracks => "tracks",
+            ShuffleMode::Albums => "albums",
+            ShuffleMode::ArtistSpread => "artist spread",
+            ShuffleMode::Weighted => "weighted",
+        }
+    }
+
+    /// Reorders `tracks` according to this mode.
+    pub fn apply(self, tracks: &mut Vec<Arc<Track>>, weight: &dyn Fn(&Track) -> f64) {
+        self.apply_after(tracks, None, weight);
+    }
+
+    /// Reorders `tracks` according to this mode, to be played after `previous`.
+    pub fn apply_after(
+        self,
+        tracks: &mut Vec<Arc<Track>>,
+        previous: Option<&Track>,
+        weight: &dyn Fn(&Track) -> f64,
+    ) {
+        match self {
+            ShuffleMode::Tracks => fastrand::shuffle(tracks),
+            ShuffleMode::Albums => shuffle_albums(tracks),
+            ShuffleMode::ArtistSpread => {
+                spread_artists(tracks, previous.map(|track| &track.artist));
+            }
+            ShuffleMode::Weighted => weighted_shuffle(tracks, weight),
+        }
+    }
+}
+
+/// Shuffles the order of albums, keeping each album's tracks in the order they were in. Tracks
+/// from the same album end up together even if they weren't before. Albums are told apart by
+/// their album artist (which is what [`Track::artist`] holds), so that compilations stay in one
+/// piece.
+fn shuffle_albums(tracks: &mut Vec<Arc<Track>>) {
+    let mut albums: Vec<Vec<Arc<Track>>> = vec![];
+    let mut index: HashMap<(ArtistName, AlbumName), usize> = HashMap::new();
+    for track in tracks.drain(..) {
+        let key = (track.artist.clone(), track.album.clone());
+        let i = *index.entry(key).or_insert_with(|| {
+            albums.push(vec![]);
+            albums.len() - 1
+        });
+        albums[i].push(track);
+    }
+    fastrand::shuffle(&mut albums);
+    tracks.extend(albums.into_iter().flatten());
+}
+
+/// Shuffles so that the same artist doesn't come up twice in a row, unless one artist has so many
+/// tracks that it can't be avoided. `after` is the artist of the track that plays before these.
+fn spread_artists(tracks: &mut Vec<Arc<Track>>, after: Option<&ArtistName>) {
+    let (artists, mut by_artist): (Vec<_>, Vec<_>) = tracks
+        .drain(..)
+        .into_group_map_by(|track| track.artist.clone())
+        .into_iter()
+        .unzip();
+    for artist_tracks in &mut by_artist {
+        fastrand::shuffle(artist_tracks);
+    }
+    let mut remaining: usize = by_artist.iter().map(Vec::len).sum();
+    let mut previous = after.and_then(|after| artists.iter().position(|artist| artist == after));
+    while remaining > 0 {
+        let candidates = (0..by_artist.len())
+            .filter(|i| !by_artist[*i].is_empty() && Some(*i) != previous)
+            .collect_vec();
+        let (most, most_count) = by_artist
+            .iter()
+            .map(Vec::len)
+            .enumerate()
+            .max_by_key(|(_, count)| *count)
+            .expect("there are tracks remaining");
+        let chosen = if candidates.is_empty() {
+            // Only the previous artist is left.
+            most
+        } else if 2 * most_count > remaining && Some(most) != previous {
+            // If we don't play this artist now, we'll end up with two of them together later.
+            most
+        } else {
+            // Pick an artist with probability proportional to how many tracks they have left, so
+            // that artists with lots of tracks don't all bunch up at the end.
+            let total: usize = candidates.iter().map(|i| by_artist[*i].len()).sum();
+            let mut pick = fastrand::usize(..total);
+            let mut chosen = candidates[0];
+            for i in candidates {
+                if pick < by_artist[i].len() {
+                    chosen = i;
+                    break;
+                }
+                pick -= by_artist[i].len();
+            }
+            chosen
+        };
+        tracks.push(by_artist[chosen].pop().expect("chosen artist has tracks"));
+        previous = Some(chosen);
+        remaining -= 1;
+    }
+}
+
+/// Weighted random order without replacement: each track gets a key of `u^(1/weight)` for a
+/// uniform `u`, and the highest keys go first (Efraimidis and Spirakis).
+fn weighted_shuffle(tracks: &mut [Arc<Track>], weight: &dyn Fn(&Track) -> f64) {
+    tracks.sort_by_cached_key(|track| {
+        let weight = weight(track).max(f64::MIN_POSITIVE);
+        std::cmp::Reverse(ordered_float::OrderedFloat(fastrand::f64().powf(1.0 / weight)))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: u64, artist: &str, album: &str) -> Arc<Track> {
+        let mut track = Track::test_track(id);
+        track.artist = ArtistName::Artist(artist.into());
+        track.album = AlbumName(Some(album.into()));
+        Arc::new(track)
+    }
+
+    fn sample() -> Vec<Arc<Track>> {
+        (0..30)
+            .map(|i| track(i, &format!("artist {}", i % 3), &format!("album {}", i % 6)))
+            .collect()
+    }
+
+    #[test]
+    fn albums_stay_together() {
+        for _ in 0..10 {
+            let mut tracks = sample();
+            ShuffleMode::Albums.apply(&mut tracks, &|_| 1.0);
+            assert_eq!(tracks.len(), 30);
+            let albums = tracks.iter().map(|t| &t.album).dedup().collect_vec();
+            assert_eq!(albums.len(), 6, "each album should be contiguous");
+            for (_, album) in &tracks.iter().group_by(|t| &t.album) {
+                let ids = album.map(|t| t.id).collect_vec();
+                assert!(ids.windows(2).all(|w| w[0] < w[1]), "album order changed: {ids:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn artists_spread_out() {
+        for _ in 0..10 {
+            let mut tracks = sample();
+            ShuffleMode::ArtistSpread.apply(&mut tracks, &|_| 1.0);
+            assert_eq!(tracks.len(), 30);
+            assert!(tracks.windows(2).all(|w| w[0].artist != w[1].artist), "{tracks:?}");
+        }
+        // "a" has too many tracks to avoid repeats entirely, but it still shouldn't lose any.
+        let mut tracks = (0..10).map(|i| track(i, if i < 8 { "a" } else { "b" }, "x")).collect();
+        spread_artists(&mut tracks, None);
+        assert_eq!(tracks.len(), 10);
+
+        for _ in 0..10 {
+            let mut tracks = sample();
+            let first = tracks.remove(0);
+            ShuffleMode::ArtistSpread.apply_after(&mut tracks, Some(&first), &|_| 1.0);
+            assert_ne!(tracks[0].artist, first.artist, "{tracks:?}");
+        }
+    }
+
+    #[test]
+    fn weights_favor_heavier_tracks() {
+        let mut first_heavy = 0;
+        for _ in 0..100 {
+            let mut tracks = sample();
+            ShuffleMode::Weighted.apply(&mut tracks, &|t| if t.id == 7 { 1000.0 } else { 1.0 });
+            if tracks[0].id == 7 {
+                first_heavy += 1;
+            }
+        }
+        assert!(first_heavy > 80, "heavy track was first only {first_heavy} times");
+    }
+}
@@ -0,0 +1,283 @@
+use serde::{Deserialize, Serialize};
+
+/// How many frames of held-back silence get played back per call to [`SilenceSkipper::pay`].
+const PAY_FRAMES: usize = 4096;
+
+/// Which silences to skip, and what counts as silence.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SilenceSettings {
+    /// Whether to skip silence at the start and end of tracks.
+    pub trim: bool,
+    /// Whether to shorten long silences in the middle of tracks down to `min_duration_ms`. Handy
+    /// for podcasts.
+    pub skip_within: bool,
+    /// Anything quieter than this, in decibels relative to full scale, counts as silence.
+    pub threshold_db: f32,
+    /// Silences shorter than this many milliseconds are left alone.
+    pub min_duration_ms: u64,
+}
+
+impl Default for SilenceSettings {
+    fn default() -> Self {
+        Self {
+            trim: false,
+            skip_within: false,
+            threshold_db: -50.0,
+            min_duration_ms: 1000,
+        }
+    }
+}
+
+impl SilenceSettings {
+    pub fn enabled(&self) -> bool {
+        self.trim || self.skip_within
+    }
+}
+
+/// Where we are in the current stretch of silence, if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Run {
+    /// Not in a silence.
+    Sound,
+    /// In a silence that isn't long enough to skip yet. It's being held back in case it turns out
+    /// to be.
+    Collecting,
+    /// In a long silence that's being thrown away.
+    Dropping,
+    /// In a long silence that's being played as normal.
+    Keeping,
+    /// In a long silence that's being thrown away if it lasts until the end of the track, and
+    /// played otherwise. `extra` frames have gone by since the part that's being held back.
+    Undecided { extra: usize },
+}
+
+/// Skips over silence in interleaved samples, according to its [`SilenceSettings`]. This works on
+/// decoded samples, so the timestamps of what's left are still positions in the file.
+///
+/// Since we can't tell how long a silence is until it's over, up to `min_duration_ms` of it is
+/// held back at a time.
+#[derive(Debug)]
+pub(super) struct SilenceSkipper {
+    settings: SilenceSettings,
+    channels: usize,
+    sample_rate: u32,
+    /// True until we've heard something that isn't silence.
+    at_start: bool,
+    run: Run,
+    held: Vec<f32>,
+    /// Frames of silence that turned out not to be skipped after all, and have to be played before
+    /// anything else. These are played as zeroes, since the real samples weren't kept.
+    owed: usize,
+    /// Input that came in after the owed silence, and hasn't been looked at yet.
+    queued: Vec<f32>,
+}
+
+impl SilenceSkipper {
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        Self {
+            settings: SilenceSettings::default(),
+            channels,
+            sample_rate,
+            at_start: true,
+            run: Run::Sound,
+            held: vec![],
+            owed: 0,
+            queued: vec![],
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: SilenceSettings) {
+        self.settings = settings;
+    }
+
+    pub fn set_format(&mut self, channels: usize, sample_rate: u32) {
+        self.channels = channels;
+        self.sample_rate = sample_rate;
+        self.reset(false);
+    }
+
+    /// Forgets about any silence in progress. `at_start` is true if what comes next is the start
+    /// of a track.
+    pub fn reset(&mut self, at_start: bool) {
+        self.at_start = at_start;
+        self.run = Run::Sound;
+        self.held.clear();
+        self.owed = 0;
+        self.queued.clear();
+    }
+
+    /// True if there's silence waiting to be played with [`SilenceSkipper::pay`]. Until there
+    /// isn't, [`SilenceSkipper::process`] mustn't be called.
+    pub fn owing(&self) -> bool {
+        self.owed > 0 || !self.queued.is_empty()
+    }
+
+    /// How many frames of input are being held back, either to see how long a silence is or
+    /// because they're owed.
+    pub fn held_frames(&self) -> usize {
+        (self.held.len() + self.queued.len()) / self.channels + self.owed
+    }
+
+    /// Plays some of the silence we owe, appending it to `out`. Once it's all been played, this
+    /// moves on to whatever input was queued behind it.
+    pub fn pay(&mut self, out: &mut Vec<f32>) {
+        let frames = self.owed.min(PAY_FRAMES);
+        out.resize(out.len() + frames * self.channels, 0.0);
+        self.owed -= frames;
+        if self.owed == 0 {
+            let queued = std::mem::take(&mut self.queued);
+            self.process(&queued, out);
+        }
+    }
+
+    fn min_samples(&self) -> usize {
+        let frames = self.settings.min_duration_ms * u64::from(self.sample_rate) / 1000;
+        frames as usize * self.channels
+    }
+
+    fn is_silent(&self, frame: &[f32]) -> bool {
+        let threshold = 10f32.powf(self.settings.threshold_db / 20.0);
+        frame.iter().all(|sample| sample.abs() < threshold)
+    }
+
+    /// Appends `input` to `out`, minus any silence that's being skipped.
+    pub fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        debug_assert!(!self.owing(), "process called while silence is owed");
+        if !self.settings.enabled() {
+            // In case it was turned off partway through a silence.
+            out.append(&mut self.held);
+            self.run = Run::Sound;
+            out.extend_from_slice(input);
+            return;
+        }
+        let min_samples = self.min_samples();
+        for (i, frame) in input.chunks_exact(self.channels).enumerate() {
+            if !self.is_silent(frame) {
+                match self.run {
+                    Run::Undecided { extra } if !self.settings.skip_within => {
+                        // Not at the end after all, and it's not ours to shorten.
+                        out.append(&mut self.held);
+                        self.owed = extra;
+                        self.queued = input[i * self.channels..].to_vec();
+                        self.run = Run::Sound;
+                        return;
+                    }
+                    _ => out.append(&mut self.held),
+                }
+                out.extend_from_slice(frame);
+                self.at_start = false;
+                self.run = Run::Sound;
+                continue;
+            }
+            match self.run {
+                Run::Sound | Run::Collecting if self.held.len() < min_samples => {
+                    self.held.extend_from_slice(frame);
+                    self.run = Run::Collecting;
+                }
+                Run::Sound | Run::Collecting => {
+                    // This silence is long enough to count.
+                    self.run = if self.at_start {
+                        if self.settings.trim {
+                            self.held.clear();
+                            Run::Dropping
+                        } else {
+                            out.append(&mut self.held);
+                            out.extend_from_slice(frame);
+                            Run::Keeping
+                        }
+                    } else if self.settings.trim {
+                        // Might be at the end of the track, so hold on.
+                        Run::Undecided { extra: 1 }
+                    } else {
+                        out.append(&mut self.held);
+                        Run::Dropping
+                    };
+                }
+                Run::Dropping => (),
+                Run::Keeping => out.extend_from_slice(frame),
+                Run::Undecided { ref mut extra } => *extra += 1,
+            }
+        }
+    }
+
+    /// Called once the track's run out, to get whatever's being held back that should be played.
+    pub fn finish(&mut self, out: &mut Vec<f32>) {
+        while self.owing() {
+            self.pay(out);
+        }
+        if self.run == Run::Collecting {
+            out.append(&mut self.held);
+        }
+        // Anything undecided turned out to be at the end.
+        self.reset(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One channel at 1 kHz, so that a frame is a millisecond.
+    fn skipper(trim: bool, skip_within: bool) -> SilenceSkipper {
+        let mut skipper = SilenceSkipper::new(1, 1000);
+        skipper.set_settings(SilenceSettings {
+            trim,
+            skip_within,
+            min_duration_ms: 3,
+            ..SilenceSettings::default()
+        });
+        skipper
+    }
+
+    /// Runs `input` through `skipper` in chunks of `chunk` samples.
+    fn run(skipper: &mut SilenceSkipper, input: &[f32], chunk: usize) -> Vec<f32> {
+        let mut out = vec![];
+        for chunk in input.chunks(chunk) {
+            while skipper.owing() {
+                skipper.pay(&mut out);
+            }
+            skipper.process(chunk, &mut out);
+        }
+        while skipper.owing() {
+            skipper.pay(&mut out);
+        }
+        skipper.finish(&mut out);
+        out
+    }
+
+    #[test]
+    fn skips_silence() {
+        let input = [
+            0.0, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0,
+            0.0,
+        ];
+        for chunk in [1, 4, input.len()] {
+            assert_eq!(run(&mut skipper(false, false), &input, chunk), input);
+            // The middle silence comes back as zeroes, which it already was.
+            assert_eq!(
+                run(&mut skipper(true, false), &input, chunk),
+                &input[4..14],
+                "chunk size {chunk}"
+            );
+            assert_eq!(
+                run(&mut skipper(false, true), &input, chunk),
+                [0.0, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0],
+                "chunk size {chunk}"
+            );
+            assert_eq!(
+                run(&mut skipper(true, true), &input, chunk),
+                [0.5, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.5],
+                "chunk size {chunk}"
+            );
+        }
+    }
+
+    #[test]
+    fn quiet_counts_as_silent() {
+        let mut skipper = skipper(true, false);
+        // -60 dB is under the default threshold
+        let input = [0.001, -0.001, 0.001, 0.001, 0.5];
+        assert_eq!(run(&mut skipper, &input, 2), [0.5]);
+    }
+}
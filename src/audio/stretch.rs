@@ -0,0 +1,237 @@
+use std::f32::consts::PI;
+
+/// Length of the hop between output segments. Segments are twice this long.
+const HOP_SECS: f32 = 0.02;
+/// How far from its nominal position we'll look for a good segment.
+const TOLERANCE_SECS: f32 = 0.008;
+/// Only compare every `CORRELATION_STRIDE`th sample when looking for a match, since checking all
+/// of them is slow and doesn't help much.
+const CORRELATION_STRIDE: usize = 4;
+
+/// Changes the speed of interleaved audio without changing its pitch, using WSOLA (waveform
+/// similarity overlap-add). Each output hop is a windowed segment of the input, taken from around
+/// where it'd be at the current speed, but nudged so that it lines up with the end of the previous
+/// segment.
+#[derive(Debug)]
+pub(super) struct TimeStretch {
+    channels: usize,
+    speed: f64,
+    /// In frames.
+    hop: usize,
+    /// In frames.
+    tolerance: usize,
+    /// Hann window, `2 * hop` long.
+    window: Vec<f32>,
+    /// Input that we might still need.
+    input: Vec<f32>,
+    /// Where the next segment should ideally start, in frames from the start of `input`.
+    nominal: f64,
+    /// Where the previous segment would have naturally continued from, in frames from the start of
+    /// `input`. The next segment should look like this. `None` if there was no previous segment.
+    target: Option<usize>,
+    /// The second half of the previous windowed segment, waiting to be added to the next one.
+    overlap: Vec<f32>,
+}
+
+impl TimeStretch {
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        let mut stretch = Self {
+            channels: 0,
+            speed: 1.0,
+            hop: 0,
+            tolerance: 0,
+            window: vec![],
+            input: vec![],
+            nominal: 0.0,
+            target: None,
+            overlap: vec![],
+        };
+        stretch.set_format(channels, sample_rate);
+        stretch
+    }
+
+    pub fn set_format(&mut self, channels: usize, sample_rate: u32) {
+        self.channels = channels;
+        self.hop = (sample_rate as f32 * HOP_SECS) as usize;
+        self.tolerance = (sample_rate as f32 * TOLERANCE_SECS) as usize;
+        let len = 2 * self.hop;
+        self.window = (0..len)
+            .map(|i| 0.5 * (1.0 - (2.0 * PI * i as f32 / len as f32).cos()))
+            .collect();
+        self.reset();
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        if speed != self.speed {
+            self.speed = speed;
+            self.reset();
+        }
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Forgets all buffered input. Call this when the input is discontinuous.
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.nominal = 0.0;
+        self.target = None;
+        self.overlap = vec![0.0; self.hop * self.channels];
+    }
+
+    /// How much input we've been given but haven't output yet, in frames.
+    pub fn buffered(&self) -> usize {
+        (self.input.len() / self.channels).saturating_sub(self.nominal as usize)
+    }
+
+    /// Stretches `input`, appending the result to `out`. Since we need to see a bit of what comes
+    /// next, the output lags behind the input.
+    pub fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        if self.speed == 1.0 {
+            out.extend_from_slice(input);
+            return;
+        }
+        self.input.extend_from_slice(input);
+        self.run(out, f64::INFINITY);
+    }
+
+    /// Outputs segments until we either run out of input or reach `limit` (in frames).
+    fn run(&mut self, out: &mut Vec<f32>, limit: f64) {
+        let (channels, hop) = (self.channels, self.hop);
+        let frames = self.input.len() / channels;
+        while self.nominal < limit {
+            let nominal = self.nominal.round() as usize;
+            let (start, end) = match self.target {
+                Some(_) => (nominal.saturating_sub(self.tolerance), nominal + self.tolerance),
+                None => (nominal, nominal),
+            };
+            if end + 2 * hop > frames {
+                break;
+            }
+            let chosen = match self.target {
+                Some(target) => self.best_match(target, start, end),
+                None => nominal,
+            };
+            let segment = &self.input[chosen * channels..(chosen + 2 * hop) * channels];
+            let (first, second) = segment.split_at(hop * channels);
+            for (i, (overlap, sample)) in self.overlap.iter_mut().zip(first).enumerate() {
+                out.push(*overlap + sample * self.window[i / channels]);
+                *overlap = second[i] * self.window[hop + i / channels];
+            }
+            self.target = Some(chosen + hop);
+            self.nominal += hop as f64 * self.speed;
+        }
+
+        // Throw away input that we won't look at again.
+        let needed = (self.nominal as usize).saturating_sub(self.tolerance);
+        let needed = self.target.map_or(needed, |target| needed.min(target));
+        self.input.drain(..needed * channels);
+        self.nominal -= needed as f64;
+        self.target = self.target.map(|target| target - needed);
+    }
+
+    /// Outputs whatever's left after the input has ended.
+    pub fn flush(&mut self, out: &mut Vec<f32>) {
+        if self.speed != 1.0 && !self.input.is_empty() {
+            // Pad with silence so that we have enough lookahead to use up the real input, but
+            // stop once we get there. The last hop is taken care of by the overlap.
+            let end = (self.input.len() / self.channels) as f64 - self.hop as f64 * self.speed;
+            let padding = (self.tolerance + 2 * self.hop) * self.channels;
+            self.input.resize(self.input.len() + padding, 0.0);
+            self.run(out, end);
+            out.extend_from_slice(&self.overlap);
+        }
+        self.reset();
+    }
+
+    /// Finds the start of the segment in `[start, end]` that best matches the `hop` frames
+    /// starting at `target`.
+    fn best_match(&self, target: usize, start: usize, end: usize) -> usize {
+        let mono = |frame: usize| {
+            self.input[frame * self.channels..(frame + 1) * self.channels]
+                .iter()
+                .sum::<f32>()
+        };
+        let target = (0..self.hop).step_by(CORRELATION_STRIDE).map(|i| mono(target + i));
+        let target = target.collect::<Vec<_>>();
+        let mut best = (f32::MIN, start);
+        for candidate in start..=end {
+            let (mut correlation, mut energy) = (0.0, 0.0);
+            for (i, t) in target.iter().enumerate() {
+                let sample = mono(candidate + i * CORRELATION_STRIDE);
+                correlation += t * sample;
+                energy += sample * sample;
+            }
+            // Normalize so that we don't just pick the loudest segment.
+            let score = correlation / (energy + 1e-9).sqrt();
+            if score > best.0 {
+                best = (score, candidate);
+            }
+        }
+        best.1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let s = (2.0 * PI * freq * i as f32 / 44100.0).sin() * 0.5;
+                [s, s]
+            })
+            .collect()
+    }
+
+    /// Estimates the frequency of a stereo sine by counting zero crossings in the left channel.
+    fn frequency(samples: &[f32]) -> f32 {
+        let left = samples.iter().step_by(2).collect::<Vec<_>>();
+        let crossings = left.windows(2).filter(|w| (*w[0] < 0.0) != (*w[1] < 0.0)).count();
+        crossings as f32 / 2.0 / (left.len() as f32 / 44100.0)
+    }
+
+    fn stretch(speed: f64, input: &[f32]) -> Vec<f32> {
+        let mut stretch = TimeStretch::new(2, 44100);
+        stretch.set_speed(speed);
+        let mut output = vec![];
+        for chunk in input.chunks(1152) {
+            stretch.process(chunk, &mut output);
+        }
+        stretch.flush(&mut output);
+        output
+    }
+
+    #[test]
+    fn normal_speed_is_identity() {
+        let input = sine(440.0, 10000);
+        assert_eq!(stretch(1.0, &input), input);
+    }
+
+    #[test]
+    fn changes_length() {
+        let input = sine(440.0, 44100);
+        for speed in [0.5, 1.5, 2.0, 3.0] {
+            let expected = 44100.0 / speed;
+            let frames = stretch(speed, &input).len() as f64 / 2.0;
+            // We can be off by up to a hop at the end.
+            assert!(
+                (frames - expected).abs() < (HOP_SECS * 44100.0) as f64,
+                "at {speed}x, expected {expected} frames, got {frames}"
+            );
+        }
+    }
+
+    #[test]
+    fn preserves_pitch() {
+        let input = sine(440.0, 44100);
+        for speed in [0.5, 2.0] {
+            let output = stretch(speed, &input);
+            // skip the fade-in at the start
+            let freq = frequency(&output[4410..]);
+            assert!((freq - 440.0).abs() < 10.0, "at {speed}x, got {freq}Hz");
+        }
+    }
+}
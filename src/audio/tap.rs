@@ -0,0 +1,105 @@
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+/// How many frames the tap holds on to. This needs to cover everything that's waiting in the ring
+/// buffer, plus the visualizer's biggest window.
+const CAPACITY: usize = 65536;
+
+/// Keeps recent output around, downmixed to mono, for visualizations. The decode thread writes to
+/// this as it fills the ring buffer, and the UI reads whatever's been heard since the last time it
+/// looked. If the UI falls behind, old samples get overwritten instead of piling up.
+#[derive(Debug, Default)]
+pub(super) struct AudioTap {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    samples: VecDeque<f32>,
+    channels: usize,
+    /// Frame index in the ring buffer of the end of `samples`.
+    end: usize,
+    /// Frame index of the first sample that hasn't been read yet.
+    read: usize,
+}
+
+impl AudioTap {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // Nothing we do while holding the lock can leave things inconsistent.
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds interleaved samples that were written to the ring buffer starting at `index`. These
+    /// have to pick up where the last ones left off, unless the tap was cleared in between.
+    pub fn push(&self, samples: &[f32], channels: usize, index: usize) {
+        let mut inner = self.lock();
+        for frame in samples.chunks_exact(channels) {
+            if inner.samples.len() == CAPACITY {
+                inner.samples.pop_front();
+            }
+            // downmix to mono if it's 2-channel or more
+            let sample = if channels == 1 {
+                frame[0]
+            } else {
+                (frame[0] + frame[1]) / 2.0
+            };
+            inner.samples.push_back(sample);
+        }
+        inner.channels = channels;
+        inner.end = (index + samples.len()) / channels;
+    }
+
+    /// Forgets everything, for when what's in the ring buffer isn't going to be played.
+    pub fn clear(&self) {
+        *self.lock() = Inner::default();
+    }
+
+    /// Appends whatever's been heard since the last time this was called to `out`, or as much of
+    /// it as we still have. `heard` is the ring buffer index of what's being heard now. Returns
+    /// true if there was anything new.
+    pub fn read_until(&self, heard: f64, out: &mut Vec<f32>) -> bool {
+        let mut inner = self.lock();
+        if inner.channels == 0 {
+            return false;
+        }
+        let start = inner.end - inner.samples.len();
+        let from = inner.read.max(start);
+        let until = ((heard / inner.channels as f64) as usize).min(inner.end);
+        if until <= from {
+            return false;
+        }
+        out.extend(inner.samples.range(from - start..until - start));
+        inner.read = until;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_what_was_heard() {
+        let tap = AudioTap::default();
+        let mut out = vec![];
+        assert!(!tap.read_until(100.0, &mut out));
+
+        tap.push(&[1.0, 0.0].repeat(10), 2, 0);
+        assert!(tap.read_until(10.0, &mut out));
+        assert_eq!(out, [0.5; 5]);
+        assert!(!tap.read_until(11.0, &mut out), "not a whole frame");
+        assert!(tap.read_until(100.0, &mut out), "only as far as was pushed");
+        assert_eq!(out, [0.5; 10]);
+
+        // Falling behind just loses the oldest audio.
+        tap.push(&vec![0.0; CAPACITY * 4], 2, 20);
+        out.clear();
+        assert!(tap.read_until(1e9, &mut out));
+        assert_eq!(out.len(), CAPACITY);
+
+        tap.clear();
+        assert!(!tap.read_until(1e9, &mut out));
+    }
+}
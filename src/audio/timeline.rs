@@ -0,0 +1,192 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{fence, AtomicU64, AtomicUsize, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+/// Where a run of samples in the ring buffer came from in the track.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Marker {
+    /// Index in the ring buffer of the first sample, counting every sample ever written.
+    index: usize,
+    /// Where that sample is in the track.
+    timestamp: Duration,
+    /// How many samples make up a second of the track. This depends on the output format and the
+    /// playback speed.
+    samples_per_second: f64,
+}
+
+/// Maps samples in the ring buffer back to where they are in the track, so that we can tell what's
+/// being heard rather than what was just decoded. The decode thread adds a marker whenever it
+/// produces samples, and the [`Player`](super::Player) looks them up by what the output has
+/// played.
+#[derive(Debug, Default)]
+pub(super) struct Timeline {
+    /// Oldest first.
+    markers: Mutex<VecDeque<Marker>>,
+}
+
+impl Timeline {
+    fn lock(&self) -> MutexGuard<'_, VecDeque<Marker>> {
+        self.markers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Records that the samples from `index` on start at `timestamp` in the track.
+    pub fn mark(&self, index: usize, timestamp: Duration, samples_per_second: f64) {
+        self.lock().push_back(Marker {
+            index,
+            timestamp,
+            samples_per_second,
+        });
+    }
+
+    /// Forgets everything, for when the samples we know about aren't going to be played.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// Where in the track the sample at `index` is. This can be fractional, for a position in
+    /// between samples. `None` if `index` is before everything we have a marker for.
+    ///
+    /// Markers that `index` is past are thrown away, so this should only be called with indexes
+    /// that never go down, like the ones from [`OutputClock::heard`].
+    pub fn position(&self, index: f64) -> Option<Duration> {
+        let mut markers = self.lock();
+        while markers.get(1).map_or(false, |next| next.index as f64 <= index) {
+            markers.pop_front();
+        }
+        let marker = markers.front().filter(|marker| marker.index as f64 <= index)?;
+        let offset = (index - marker.index as f64) / marker.samples_per_second;
+        Some(marker.timestamp + Duration::from_secs_f64(offset))
+    }
+}
+
+/// Keeps track of which sample in the ring buffer is coming out of the speakers. The output
+/// callback updates this every time it runs, and we interpolate in between, so that the position
+/// moves smoothly instead of jumping a buffer at a time.
+#[derive(Debug)]
+pub(super) struct OutputClock {
+    /// What `heard_at` is relative to.
+    epoch: Instant,
+    /// Odd while the output callback is partway through an update.
+    sequence: AtomicU64,
+    /// Index of the first sample the output callback played the last time it ran.
+    from: AtomicUsize,
+    /// How many samples it played; the rest was silence.
+    count: AtomicUsize,
+    /// When the sample at `from` gets heard, in nanoseconds since `epoch`.
+    heard_at: AtomicU64,
+    /// Bits of an `f64`: how many samples the output plays per second.
+    rate: AtomicU64,
+    /// Bits of an `f64`: the index we last reported, so that we never go backwards.
+    last: AtomicU64,
+}
+
+impl Default for OutputClock {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            sequence: AtomicU64::new(0),
+            from: AtomicUsize::new(0),
+            count: AtomicUsize::new(0),
+            heard_at: AtomicU64::new(0),
+            rate: AtomicU64::new(0f64.to_bits()),
+            last: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+}
+
+impl OutputClock {
+    /// Starts over for a new ring buffer, whose output plays `rate` samples per second. This
+    /// mustn't be called while an output callback is running.
+    pub fn reset(&self, rate: f64) {
+        self.rate.store(rate.to_bits(), Ordering::Relaxed);
+        self.last.store(0f64.to_bits(), Ordering::Relaxed);
+        self.update(0, 0, Duration::ZERO);
+    }
+
+    /// Called by the output callback after it plays `count` samples starting from `from`, the
+    /// first of which will be heard after `latency`. Never blocks.
+    pub fn update(&self, from: usize, count: usize, latency: Duration) {
+        let heard_at = (self.epoch.elapsed() + latency).as_nanos().try_into().unwrap_or(u64::MAX);
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.from.store(from, Ordering::Relaxed);
+        self.count.store(count, Ordering::Relaxed);
+        self.heard_at.store(heard_at, Ordering::Relaxed);
+        self.sequence.store(sequence.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Index of the sample being heard right now. This is fractional, since we're usually partway
+    /// between samples, and never goes down until the next [`OutputClock::reset`].
+    pub fn heard(&self) -> f64 {
+        let (from, count, heard_at) = loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            let values = (
+                self.from.load(Ordering::Relaxed),
+                self.count.load(Ordering::Relaxed),
+                self.heard_at.load(Ordering::Relaxed),
+            );
+            fence(Ordering::Acquire);
+            if sequence & 1 == 0 && self.sequence.load(Ordering::Relaxed) == sequence {
+                break values;
+            }
+            std::hint::spin_loop();
+        };
+        let rate = f64::from_bits(self.rate.load(Ordering::Relaxed));
+        let elapsed =
+            self.epoch.elapsed().as_secs_f64() - Duration::from_nanos(heard_at).as_secs_f64();
+        // Before `heard_at`, we're still hearing what the previous callback played.
+        let index = (from as f64 + elapsed * rate).clamp(0.0, (from + count) as f64);
+        let index = index.max(f64::from_bits(self.last.load(Ordering::Relaxed)));
+        self.last.store(index.to_bits(), Ordering::Relaxed);
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_samples_to_positions() {
+        let timeline = Timeline::default();
+        assert_eq!(timeline.position(0.0), None);
+        timeline.mark(100, Duration::from_secs(5), 10.0);
+        assert_eq!(timeline.position(50.0), None, "before the first marker");
+        assert_eq!(timeline.position(100.0), Some(Duration::from_secs(5)));
+        assert_eq!(timeline.position(105.0), Some(Duration::from_millis(5500)));
+
+        // A seek backwards, at double speed.
+        timeline.mark(200, Duration::from_secs(1), 5.0);
+        assert_eq!(timeline.position(150.0), Some(Duration::from_secs(10)));
+        assert_eq!(timeline.position(210.0), Some(Duration::from_secs(3)));
+
+        timeline.clear();
+        assert_eq!(timeline.position(300.0), None);
+    }
+
+    #[test]
+    fn clock_stays_within_what_was_played() {
+        let clock = OutputClock::default();
+        // So fast that everything's been heard once any time at all has gone by.
+        clock.reset(1e12);
+        assert_eq!(clock.heard(), 0.0);
+        clock.update(100, 50, Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(clock.heard(), 150.0);
+        // Paused, so nothing new gets played.
+        clock.update(150, 0, Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(clock.heard(), 150.0);
+        // Still hearing the old samples, but that doesn't send us backwards.
+        clock.update(200, 10, Duration::from_secs(3600));
+        assert_eq!(clock.heard(), 150.0);
+        clock.reset(1e12);
+        assert_eq!(clock.heard(), 0.0);
+    }
+}
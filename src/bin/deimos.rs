@@ -2,16 +2,24 @@ use std::{
     fs::{self, File},
     io,
     ops::{Deref, DerefMut},
+    path::PathBuf,
+    time::Duration,
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use crossterm::{
     event::{DisableMouseCapture, EnableMouseCapture, EventStream},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use deimos::app::App;
+use deimos::app::{App, PlaybackOptions, SavedState};
+use deimos::audio::{
+    output_devices, CpalBackend, DeviceId, NullBackend, OutputBackend, Pace, StopAfter,
+    StreamFormat, WavBackend,
+};
+use deimos::config::Config;
 use deimos::library::Library;
+use deimos::sleep::{SleepAt, SleepTimer};
 use directories::{ProjectDirs, UserDirs};
 use eyre::{eyre, Result};
 use log::debug;
@@ -24,6 +32,121 @@ struct Args {
     /// Causes deimos to rescan the library from disk, overwriting the existing one.
     #[arg(long)]
     rescan_library: bool,
+
+    /// Name of the audio output device to use, overriding the config file. Run `deimos devices` to
+    /// see what's available.
+    #[arg(long)]
+    device: Option<String>,
+
+    /// Audio API that `--device` belongs to, as listed by `deimos devices`. Without this, the
+    /// device is looked for on every API.
+    #[arg(long, requires = "device")]
+    host: Option<String>,
+
+    /// Where to send audio.
+    #[arg(long, value_enum, default_value_t = Output::Device)]
+    output: Output,
+
+    /// File to write to when using `--output wav`.
+    #[arg(long, required_if_eq("output", "wav"))]
+    output_file: Option<PathBuf>,
+
+    /// With `--output null` or `--output wav`, consume audio as fast as it can be decoded instead
+    /// of in real time.
+    #[arg(long)]
+    unthrottled: bool,
+
+    /// Stop once the current track or album finishes.
+    #[arg(long, value_enum)]
+    stop_after: Option<StopAfterArg>,
+
+    /// Play the current track this many more times before moving on.
+    #[arg(long, default_value_t = 0)]
+    repeat: u32,
+
+    /// Stop playing after this many minutes, up to a week.
+    #[arg(
+        long,
+        conflicts_with = "sleep_tracks",
+        value_parser = clap::value_parser!(u64).range(1..=7 * 24 * 60)
+    )]
+    sleep: Option<u64>,
+
+    /// Stop playing once this many tracks have finished.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    sleep_tracks: Option<u32>,
+
+    /// Fade out before the sleep timer stops playback, overriding the config file.
+    #[arg(long)]
+    sleep_fade_out: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Lists the available audio output devices and exits.
+    Devices,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum StopAfterArg {
+    Track,
+    Album,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    /// The system's audio device.
+    Device,
+    /// Discard the audio.
+    Null,
+    /// Write the audio to a WAV file.
+    Wav,
+}
+
+impl Args {
+    fn backend(&self) -> Box<dyn OutputBackend> {
+        // Matches what most devices default to.
+        let format = StreamFormat {
+            channels: 2,
+            sample_rate: 44100,
+        };
+        let pace = if self.unthrottled {
+            Pace::Unthrottled
+        } else {
+            Pace::RealTime
+        };
+        match self.output {
+            Output::Device => Box::new(CpalBackend),
+            Output::Null => Box::new(NullBackend { format, pace }),
+            Output::Wav => Box::new(WavBackend::new(
+                self.output_file.clone().expect("clap should require --output-file"),
+                format,
+                pace,
+            )),
+        }
+    }
+
+    fn playback_options(&self, config: &Config) -> PlaybackOptions {
+        let fade_out = self.sleep_fade_out || config.sleep_fade_out;
+        let sleep_timer = match (self.sleep, self.sleep_tracks) {
+            (Some(minutes), _) => {
+                Some(SleepTimer::after(Duration::from_secs(minutes * 60), fade_out))
+            }
+            (None, Some(tracks)) => Some(SleepTimer::new(SleepAt::Tracks(tracks), fade_out)),
+            (None, None) => None,
+        };
+        PlaybackOptions {
+            stop_after: self.stop_after.map(|stop_after| match stop_after {
+                StopAfterArg::Track => StopAfter::Track,
+                StopAfterArg::Album => StopAfter::Album,
+            }),
+            repeats: self.repeat,
+            sleep_timer,
+        }
+    }
 }
 
 fn main() -> Result<()> {
@@ -42,6 +165,23 @@ fn main() -> Result<()> {
         .target(env_logger::Target::Pipe(Box::new(File::create(log_target)?)))
         .init();
 
+    if let Some(Command::Devices) = args.command {
+        for device in output_devices() {
+            let default = if device.default { " (default)" } else { "" };
+            println!("{}: {}{default}", device.host, device.name);
+        }
+        return Ok(());
+    }
+
+    let backend = args.backend();
+    let mut config = Config::load(project_dirs.config_dir().join("config.json"))?;
+    if let Some(name) = &args.device {
+        config.set_output_device(Some(DeviceId {
+            host: args.host.clone(),
+            name: name.clone(),
+        }));
+    }
+
     // load library
     let cache_path = project_dirs.cache_dir().join("library.json");
     let library = if args.rescan_library {
@@ -62,7 +202,10 @@ fn main() -> Result<()> {
         eyre::Ok(library)
     })?;
 
-    let app = App::new(library);
+    let saved = SavedState::load(project_dirs.cache_dir());
+    let options = args.playback_options(&config);
+    let mut app = App::new(library, config, backend, saved);
+    app.set_playback_options(options);
 
     let mut terminal = AppTerminal::new()?;
     smol::block_on(async {
@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{library::Track, persist};
+
+/// If a track is stopped within this long of the end, we consider it finished and forget its
+/// position.
+const FINISHED_MARGIN: Duration = Duration::from_secs(10);
+
+/// A named position in a track.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Bookmark {
+    pub name: String,
+    /// Path of the track. Paths are stable across library rescans, unlike track IDs.
+    pub path: PathBuf,
+    pub position: Duration,
+}
+
+/// Remembered positions in long tracks (audiobooks, podcasts, etc), plus user-created bookmarks.
+/// Tracks are identified by their path. This is saved next to the library cache.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Bookmarks {
+    /// Where the user left off in each track that's long enough to resume.
+    positions: HashMap<PathBuf, Duration>,
+    bookmarks: Vec<Bookmark>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl Bookmarks {
+    /// Loads bookmarks from the given path. A missing file means there aren't any.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut bookmarks: Self = persist::load_json(path, "bookmarks")?;
+        bookmarks.path = Some(path.to_owned());
+        Ok(bookmarks)
+    }
+
+    /// Writes the bookmarks back to where they were loaded from. Does nothing if they weren't
+    /// loaded from a file.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(());
+        };
+        persist::save_json(path, self, "bookmarks")
+    }
+
+    /// Remembers that the user stopped listening to `track` at `position`. Tracks shorter than
+    /// `threshold` aren't remembered, and neither are tracks that were played to the end.
+    pub fn record_position(&mut self, track: &Track, position: Duration, threshold: Duration) {
+        let length = Duration::from_secs_f64(track.length.0);
+        if length < threshold {
+            return;
+        }
+        if position + FINISHED_MARGIN >= length || position.is_zero() {
+            self.positions.remove(&track.path);
+        } else {
+            self.positions.insert(track.path.clone(), position);
+        }
+    }
+
+    /// Where to resume `track` from, if it's at least `threshold` long and we remember a position.
+    pub fn resume_position(&self, track: &Track, threshold: Duration) -> Option<Duration> {
+        if Duration::from_secs_f64(track.length.0) < threshold {
+            return None;
+        }
+        self.positions.get(&track.path).copied()
+    }
+
+    pub fn bookmarks(&self) -> &[Bookmark] {
+        &self.bookmarks
+    }
+
+    /// Adds a bookmark, keeping them sorted by track and then position. Returns its index.
+    pub fn add(&mut self, bookmark: Bookmark) -> usize {
+        let index = self
+            .bookmarks
+            .partition_point(|b| (&b.path, b.position) <= (&bookmark.path, bookmark.position));
+        self.bookmarks.insert(index, bookmark);
+        index
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Bookmark> {
+        (index < self.bookmarks.len()).then(|| self.bookmarks.remove(index))
+    }
+
+    pub fn rename(&mut self, index: usize, name: String) {
+        if let Some(bookmark) = self.bookmarks.get_mut(index) {
+            bookmark.name = name;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use ordered_float::OrderedFloat;
+
+    use super::*;
+
+    const THRESHOLD: Duration = Duration::from_secs(100);
+
+    #[test]
+    fn resume_position() {
+        let mut bookmarks = Bookmarks::default();
+        let long = Track::test_track(0);
+        let short = Track {
+            length: OrderedFloat(50.0),
+            ..Track::test_track(1)
+        };
+        bookmarks.record_position(&long, Duration::from_secs(30), THRESHOLD);
+        bookmarks.record_position(&short, Duration::from_secs(30), THRESHOLD);
+        assert_eq!(bookmarks.resume_position(&long, THRESHOLD), Some(Duration::from_secs(30)));
+        assert_eq!(bookmarks.resume_position(&short, THRESHOLD), None);
+
+        // finishing the track forgets the position
+        bookmarks.record_position(&long, Duration::from_secs(195), THRESHOLD);
+        assert_eq!(bookmarks.resume_position(&long, THRESHOLD), None);
+    }
+
+    #[test]
+    fn bookmarks_are_sorted() {
+        let mut bookmarks = Bookmarks::default();
+        let bookmark = |path: &str, secs| Bookmark {
+            name: format!("{path} {secs}"),
+            path: path.into(),
+            position: Duration::from_secs(secs),
+        };
+        bookmarks.add(bookmark("/b", 10));
+        bookmarks.add(bookmark("/a", 20));
+        assert_eq!(bookmarks.add(bookmark("/a", 5)), 0);
+        let names = bookmarks.bookmarks().iter().map(|b| b.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["/a 5", "/a 20", "/b 10"]);
+
+        bookmarks.rename(1, "renamed".into());
+        assert_eq!(bookmarks.remove(1).unwrap().name, "renamed");
+        assert_eq!(bookmarks.remove(5), None);
+    }
+
+    #[test]
+    fn save_and_load() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("deimos-bookmarks-{}.json", std::process::id()));
+        let mut bookmarks = Bookmarks::load(&path)?;
+        bookmarks.record_position(&Track::test_track(0), Duration::from_secs(30), THRESHOLD);
+        bookmarks.save()?;
+        let loaded = Bookmarks::load(&path)?;
+        fs::remove_file(&path)?;
+        assert_eq!(
+            loaded.resume_position(&Track::test_track(0), THRESHOLD),
+            Some(Duration::from_secs(30))
+        );
+        Ok(())
+    }
+}
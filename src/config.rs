@@ -0,0 +1,203 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audio::{DeviceId, EqPreset, SilenceSettings},
+    library::Track,
+    persist,
+};
+
+/// User configuration, read from `config.json` in the config directory. Every field is optional.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// Name of the device to play audio through, as listed by `deimos devices`. If unset or if the
+    /// device can't be found, we use the system default.
+    pub output_device: Option<String>,
+    /// Audio API that `output_device` belongs to, e.g. "ALSA". If unset, we look for the device on
+    /// every host, starting with the default one.
+    pub output_host: Option<String>,
+    pub equalizer: EqualizerConfig,
+    /// Tracks at least this many seconds long remember where they were stopped, and pick up from
+    /// there when played again.
+    pub resume_threshold: u64,
+    /// How much of a track has to be played for it to count towards its play count, from 0 to 1.
+    /// Tracks that are stopped before that count as skipped.
+    pub play_count_fraction: f64,
+    /// Whether to read and write ratings in tracks' tags (POPM or FMPS_RATING), so that other
+    /// players can see them. Otherwise they're only kept in deimos's cache.
+    pub rating_tags: bool,
+    /// Whether the sleep timer fades out over its last 30 seconds, rather than stopping abruptly.
+    pub sleep_fade_out: bool,
+    /// How many milliseconds to fade in or out over when pausing, resuming, seeking or stopping,
+    /// so that the audio doesn't click. 0 turns this off.
+    pub fade_ms: u64,
+    /// How many times a second to redraw while audio is playing.
+    pub fps: u32,
+    /// Skipping silence at the ends of or within tracks.
+    pub silence: SilenceSettings,
+    pub radio: RadioConfig,
+    /// The file this was loaded from, which `save` writes back to.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+/// Named EQ presets and the rules for choosing between them.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EqualizerConfig {
+    pub presets: BTreeMap<String, EqPreset>,
+    /// The preset to use when none of the rules below match. If unset, the EQ is off.
+    pub preset: Option<String>,
+    /// Maps genre names (case-insensitively) to presets.
+    pub genres: HashMap<String, String>,
+    /// Maps album names to presets. Takes priority over `genres`.
+    pub albums: HashMap<String, String>,
+}
+
+/// Settings for radio mode, which adds tracks like the ones played recently whenever the queue is
+/// about to run out.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RadioConfig {
+    /// Whether radio mode starts out on. It can be toggled while running.
+    pub enabled: bool,
+    /// How adventurous the picks are, from 0 to 1. At 0, we always pick the most similar tracks.
+    pub randomness: f64,
+    /// Tracks from this many of the most recent plays aren't picked.
+    pub exclude_recent: usize,
+    /// How many tracks to add at a time.
+    pub batch_size: usize,
+}
+
+impl Default for RadioConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            randomness: 0.5,
+            exclude_recent: 100,
+            batch_size: 5,
+        }
+    }
+}
+
+impl EqualizerConfig {
+    /// The name of the preset that should be used for `track`, ignoring `preset`. Rules that refer
+    /// to presets that don't exist are ignored.
+    pub fn preset_for(&self, track: &Track) -> Option<&str> {
+        let by_album = track.album.0.as_ref().and_then(|album| self.albums.get(album));
+        let by_genre = track.genre.as_ref().and_then(|genre| {
+            self.genres
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(genre))
+                .map(|(_, preset)| preset)
+        });
+        [by_album, by_genre]
+            .into_iter()
+            .flatten()
+            .find(|preset| self.presets.contains_key(*preset))
+            .map(String::as_str)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            output_device: None,
+            output_host: None,
+            equalizer: EqualizerConfig::default(),
+            resume_threshold: 20 * 60,
+            play_count_fraction: 0.5,
+            rating_tags: false,
+            sleep_fade_out: false,
+            fade_ms: 15,
+            fps: 30,
+            silence: SilenceSettings::default(),
+            radio: RadioConfig::default(),
+            path: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn resume_threshold(&self) -> Duration {
+        Duration::from_secs(self.resume_threshold)
+    }
+
+    /// The device to play audio through, or `None` for the system default.
+    pub fn output_device(&self) -> Option<DeviceId> {
+        self.output_device.clone().map(|name| DeviceId {
+            host: self.output_host.clone(),
+            name,
+        })
+    }
+
+    /// Remembers `device` as the one to play audio through.
+    pub fn set_output_device(&mut self, device: Option<DeviceId>) {
+        (self.output_host, self.output_device) =
+            device.map_or((None, None), |device| (device.host, Some(device.name)));
+    }
+
+    /// Loads the config from the given path. A missing file is the same as an empty one.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut config: Self = persist::load_json(path, "config")?;
+        config.path = Some(path.to_owned());
+        Ok(config)
+    }
+
+    /// Writes the config back to where it was loaded from.
+    pub fn save(&self) -> Result<()> {
+        let path = self.path.as_ref().ok_or_else(|| eyre!("config wasn't loaded from a file"))?;
+        persist::save_json_pretty(path, self, "config")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::library::AlbumName;
+
+    use super::*;
+
+    #[test]
+    fn preset_for_track() {
+        let config = EqualizerConfig {
+            presets: BTreeMap::from([
+                ("bassy".to_owned(), EqPreset::default()),
+                ("bright".to_owned(), EqPreset::default()),
+            ]),
+            preset: Some("bright".into()),
+            genres: HashMap::from([("test GENRE".to_owned(), "bassy".to_owned())]),
+            albums: HashMap::from([
+                ("Bright album".to_owned(), "bright".to_owned()),
+                ("Broken album".to_owned(), "nonexistent".to_owned()),
+            ]),
+        };
+        let track = Track::test_track(0);
+        assert_eq!(config.preset_for(&track), Some("bassy"));
+
+        let bright = Track {
+            album: AlbumName(Some("Bright album".into())),
+            ..Track::test_track(0)
+        };
+        assert_eq!(config.preset_for(&bright), Some("bright"));
+
+        let broken = Track {
+            album: AlbumName(Some("Broken album".into())),
+            ..Track::test_track(0)
+        };
+        assert_eq!(config.preset_for(&broken), Some("bassy"), "should fall back to genre");
+
+        let other = Track {
+            genre: None,
+            ..Track::test_track(0)
+        };
+        assert_eq!(config.preset_for(&other), None);
+    }
+}
@@ -0,0 +1,83 @@
+use std::{collections::VecDeque, sync::Arc, time::SystemTime};
+
+use eyre::Report;
+
+use crate::library::Track;
+
+/// How many errors we keep around.
+const MAX_ERRORS: usize = 100;
+
+/// Something that went wrong that the user should know about.
+#[derive(Debug, Clone)]
+pub struct LoggedError {
+    pub time: SystemTime,
+    /// The track that couldn't be played, if that's what went wrong.
+    pub track: Option<Arc<Track>>,
+    /// The error, along with everything that caused it.
+    pub message: String,
+}
+
+impl LoggedError {
+    /// A one-line description, suitable for a status bar.
+    pub fn summary(&self) -> String {
+        match &self.track {
+            Some(track) => {
+                let title = track.title.as_deref().unwrap_or("<unknown>");
+                format!("Couldn't play {} - {title}: {}", track.artist, self.message)
+            }
+            None => self.message.clone(),
+        }
+    }
+}
+
+/// The most recent errors from this run. These aren't saved anywhere.
+#[derive(Debug, Default)]
+pub struct ErrorLog {
+    /// Oldest first.
+    errors: VecDeque<LoggedError>,
+}
+
+impl ErrorLog {
+    /// Logs an error, forgetting the oldest one if there are too many.
+    pub fn push(&mut self, track: Option<Arc<Track>>, error: &Report) -> &LoggedError {
+        if self.errors.len() == MAX_ERRORS {
+            self.errors.pop_front();
+        }
+        self.errors.push_back(LoggedError {
+            time: SystemTime::now(),
+            track,
+            message: format!("{error:#}"),
+        });
+        self.errors.back().expect("just pushed an error")
+    }
+
+    /// Newest first.
+    pub fn recent(&self) -> impl Iterator<Item = &LoggedError> + '_ {
+        self.errors.iter().rev()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::{eyre, WrapErr};
+
+    use super::*;
+
+    #[test]
+    fn keeps_recent_errors() {
+        let mut log = ErrorLog::default();
+        for i in 0..MAX_ERRORS + 5 {
+            log.push(None, &eyre!("error {i}"));
+        }
+        let messages = log.recent().map(|e| e.message.as_str()).collect::<Vec<_>>();
+        assert_eq!(messages.len(), MAX_ERRORS);
+        assert_eq!(messages[0], format!("error {}", MAX_ERRORS + 4));
+        assert_eq!(messages[MAX_ERRORS - 1], "error 5");
+
+        let track = Arc::new(Track::test_track(0));
+        let error = Err::<(), _>(eyre!("bad packet")).wrap_err("decoding failed").unwrap_err();
+        let logged = log.push(Some(track), &error);
+        assert_eq!(logged.message, "decoding failed: bad packet");
+        assert!(logged.summary().starts_with("Couldn't play "), "{}", logged.summary());
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::persist;
+
+/// We forget the oldest plays past this many, so that the file doesn't grow forever.
+const MAX_PLAYS: usize = 10_000;
+
+/// How a play of a track ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayEnd {
+    /// The track played to the end.
+    Completed,
+    /// The user moved on before the end, or stopped playback.
+    Skipped,
+}
+
+/// A single time a track was played.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Play {
+    /// Path of the track. Paths are stable across library rescans, unlike track IDs.
+    pub path: PathBuf,
+    pub started: SystemTime,
+    /// How far into the track we got.
+    pub listened: Duration,
+    pub end: PlayEnd,
+}
+
+/// Every track that's been played, oldest first. This is saved next to the library cache.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct History {
+    plays: Vec<Play>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// Whether anything's been played since the last save.
+    #[serde(skip)]
+    changed: bool,
+}
+
+impl History {
+    /// Loads the history from the given path. A missing file means nothing has been played.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut history: Self = persist::load_json(path, "history")?;
+        history.path = Some(path.to_owned());
+        Ok(history)
+    }
+
+    /// Writes the history back to where it was loaded from. Does nothing if it wasn't loaded from
+    /// a file, or if nothing's changed since the last save.
+    pub fn save(&mut self) -> Result<()> {
+        let Some(path) = self.path.as_ref().filter(|_| self.changed) else {
+            return Ok(());
+        };
+        persist::save_json(path, self, "history")?;
+        self.changed = false;
+        Ok(())
+    }
+
+    pub fn record(&mut self, play: Play) {
+        self.changed = true;
+        self.plays.push(play);
+        if self.plays.len() > MAX_PLAYS {
+            self.plays.drain(..self.plays.len() - MAX_PLAYS);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.plays.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plays.is_empty()
+    }
+
+    /// Plays from newest to oldest.
+    pub fn recent(&self) -> impl Iterator<Item = &Play> + '_ {
+        self.plays.iter().rev()
+    }
+
+    /// The `n`th most recent play, starting from 0.
+    pub fn get(&self, n: usize) -> Option<&Play> {
+        self.recent().nth(n)
+    }
+
+    /// Plays of the track at `path`, from newest to oldest.
+    pub fn plays_of<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a Play> + 'a {
+        self.recent().filter(move |play| play.path == path)
+    }
+
+    /// When the track at `path` was last played, if ever.
+    pub fn last_played(&self, path: &Path) -> Option<SystemTime> {
+        self.plays_of(path).next().map(|play| play.started)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn play(path: &str, minutes: u64, end: PlayEnd) -> Play {
+        Play {
+            path: path.into(),
+            started: SystemTime::UNIX_EPOCH + Duration::from_secs(minutes * 60),
+            listened: Duration::from_secs(30),
+            end,
+        }
+    }
+
+    #[test]
+    fn queries() {
+        let mut history = History::default();
+        history.record(play("/a", 1, PlayEnd::Completed));
+        history.record(play("/b", 2, PlayEnd::Skipped));
+        history.record(play("/a", 3, PlayEnd::Skipped));
+        assert_eq!(history.get(0), Some(&play("/a", 3, PlayEnd::Skipped)));
+        assert_eq!(history.plays_of(Path::new("/a")).count(), 2);
+        assert_eq!(
+            history.last_played(Path::new("/b")),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(120))
+        );
+        assert_eq!(history.last_played(Path::new("/c")), None);
+    }
+
+    #[test]
+    fn forgets_oldest() {
+        let mut history = History::default();
+        for i in 0..MAX_PLAYS as u64 + 5 {
+            history.record(play("/a", i, PlayEnd::Completed));
+        }
+        assert_eq!(history.len(), MAX_PLAYS);
+        assert_eq!(history.recent().last(), Some(&play("/a", 5, PlayEnd::Completed)));
+    }
+
+    #[test]
+    fn save_and_load() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("deimos-history-{}.json", std::process::id()));
+        let mut history = History::load(&path)?;
+        history.save()?;
+        assert!(!path.exists(), "nothing to save yet");
+        history.record(play("/a", 1, PlayEnd::Skipped));
+        history.save()?;
+        let loaded = History::load(&path)?;
+        fs::remove_file(&path)?;
+        assert_eq!(loaded.get(0), Some(&play("/a", 1, PlayEnd::Skipped)));
+        Ok(())
+    }
+}
@@ -1,8 +1,18 @@
 pub mod app;
-mod audio;
+pub mod audio;
+pub mod bookmarks;
+pub mod config;
+pub mod errors;
+pub mod history;
 pub mod library;
 mod library_panel;
 mod mpris;
+mod persist;
+pub mod radio;
+pub mod ratings;
+pub mod session;
+pub mod sleep;
+pub mod stats;
 pub mod ui;
 
 #[cfg(test)]
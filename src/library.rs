@@ -73,8 +73,15 @@ pub struct Track {
     pub path: PathBuf,
     pub title: Option<String>,
     pub album: AlbumName,
+    /// The album artist if the track has one, or the track artist otherwise.
     pub artist: ArtistName,
     pub length: OrderedFloat<f64>,
+    /// Missing from libraries cached before we started reading it.
+    #[serde(default)]
+    pub genre: Option<String>,
+    /// Missing from libraries cached before we started reading it.
+    #[serde(default)]
+    pub year: Option<u32>,
 }
 
 impl Track {
@@ -110,6 +117,8 @@ impl Track {
             album: AlbumName(Some("Test album".into())),
             artist: ArtistName::Artist("Test artist".into()),
             length: OrderedFloat(200.0),
+            genre: Some("Test genre".into()),
+            year: Some(2000),
         }
     }
 }
@@ -150,7 +159,7 @@ impl Library {
         Ok(library)
     }
 
-    fn insert_track(&mut self, track: Track) -> Result<()> {
+    pub(crate) fn insert_track(&mut self, track: Track) -> Result<()> {
         let tracks = &mut self
             .artists
             .entry(track.artist.clone())
@@ -183,6 +192,27 @@ impl Library {
     pub fn tracks(&self) -> impl Iterator<Item = Arc<Track>> + '_ {
         self.albums().flat_map(|album| album.tracks.iter()).cloned()
     }
+
+    /// The tracks on an album, in track number order.
+    pub fn album_tracks(&self, artist: &ArtistName, album: &AlbumName) -> Vec<Arc<Track>> {
+        self.artists
+            .get(artist)
+            .and_then(|artist| artist.albums.get(album))
+            .map_or_else(Vec::new, |album| album.tracks.clone())
+    }
+
+    /// All of an artist's tracks, album by album in the same order as the artist/album list.
+    pub fn artist_tracks(&self, artist: &ArtistName) -> Vec<Arc<Track>> {
+        let Some(artist) = self.artists.get(artist) else {
+            return vec![];
+        };
+        artist
+            .albums
+            .iter()
+            .sorted_unstable_by_key(|(name, _)| *name)
+            .flat_map(|(_, album)| album.tracks.iter().cloned())
+            .collect()
+    }
 }
 
 impl Track {
@@ -218,6 +248,8 @@ impl Track {
             album: tag.album().map(normalize).into(),
             artist: artist.map(normalize).into(),
             length: duration.into(),
+            genre: tag.genre().map(normalize),
+            year: tag.year(),
         })
     }
 }
@@ -282,6 +314,23 @@ mod tests {
         assert_ne!(Track::test_track(0), Track::test_track(1));
     }
 
+    #[test]
+    fn artist_tracks_are_in_album_order() -> Result<()> {
+        let mut library = Library::default();
+        for (id, album) in [(0, "B"), (1, "A"), (2, "B"), (3, "A")] {
+            library.insert_track(Track {
+                album: AlbumName(Some(album.into())),
+                ..Track::test_track(id)
+            })?;
+        }
+        let artist = ArtistName::Artist("Test artist".into());
+        let ids = |tracks: Vec<Arc<Track>>| tracks.iter().map(|t| t.id).collect::<Vec<_>>();
+        assert_eq!(ids(library.artist_tracks(&artist)), [1, 3, 0, 2]);
+        assert_eq!(ids(library.album_tracks(&artist, &AlbumName(Some("B".into())))), [0, 2]);
+        assert!(library.artist_tracks(&ArtistName::Unknown).is_empty());
+        Ok(())
+    }
+
     #[test]
     fn no_album_art() -> Result<()> {
         let track = Track::from_path(&test_data!("3_seconds.mp3"), 0)?;
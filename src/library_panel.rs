@@ -9,7 +9,7 @@ use crate::{
     ui::{
         artist_album_list::ArtistAlbumList,
         search::SearchItem,
-        track_list::{TrackList, TrackListItem},
+        track_list::{TrackInfo, TrackList, TrackListItem, TrackSort},
         ActiveState, Ui,
     },
 };
@@ -35,14 +35,21 @@ pub struct LibraryPanel {
     pub focus: PanelItem,
     pub artist_album_list: ArtistAlbumList,
     pub track_list: TrackList,
+    /// How the track list is sorted. This sticks as the selected artist or album changes.
+    pub sort: TrackSort,
 }
 
 impl LibraryPanel {
-    pub(crate) fn select_entity(&mut self, library: &Library, result: &SearchItem) -> Result<()> {
+    pub(crate) fn select_entity(
+        &mut self,
+        library: &Library,
+        info: TrackInfo,
+        result: &SearchItem,
+    ) -> Result<()> {
         let artist = result.album_artist();
         let album = result.album();
         self.artist_album_list.select(artist, album)?;
-        self.update_track_list(library)?;
+        self.update_track_list(library, info)?;
         if let Some(title) = result.track_title() {
             self.track_list.select(title);
             self.focus = PanelItem::TrackList;
@@ -52,11 +59,16 @@ impl LibraryPanel {
         Ok(())
     }
 
-    pub fn move_selection(&mut self, library: &Library, amount: isize) -> Result<()> {
+    pub fn move_selection(
+        &mut self,
+        library: &Library,
+        info: TrackInfo,
+        amount: isize,
+    ) -> Result<()> {
         match self.focus {
             PanelItem::ArtistAlbumList => {
                 self.artist_album_list.move_selection(amount);
-                self.update_track_list(library)
+                self.update_track_list(library, info)
             }
             PanelItem::TrackList => {
                 self.track_list.move_selection(amount);
@@ -65,11 +77,31 @@ impl LibraryPanel {
         }
     }
 
-    fn update_track_list(&mut self, library: &Library) -> Result<()> {
+    /// Switches to the next way of sorting the track list, keeping the same track selected.
+    pub fn cycle_sort(&mut self, library: &Library, info: TrackInfo) -> Result<()> {
+        self.sort = self.sort.next();
+        let selected = self.track_list.selected();
+        self.update_track_list(library, info)?;
+        if let Some(track) = selected {
+            self.track_list.select_track(&track);
+        }
+        Ok(())
+    }
+
+    fn update_track_list(&mut self, library: &Library, info: TrackInfo) -> Result<()> {
         let Some(artist) = self.artist_album_list.artist() else {
             return Ok(());
         };
 
+        if self.sort != TrackSort::Album {
+            let tracks = match self.artist_album_list.album() {
+                Some(album) => library.album_tracks(&artist, &album),
+                None => library.artist_tracks(&artist),
+            };
+            self.track_list = TrackList::sorted(tracks, self.sort, info);
+            return Ok(());
+        }
+
         self.track_list = match self.artist_album_list.album() {
             Some(album) => {
                 let tracks = &library.artists[&artist].albums[&album].tracks;
@@ -102,6 +134,7 @@ impl LibraryPanel {
         frame: &mut ratatui::Frame,
         area: Rect,
         current_track: Option<Arc<Track>>,
+        info: TrackInfo,
     ) -> eyre::Result<()> {
         let layout = Layout::default()
             .direction(Direction::Horizontal)
@@ -112,6 +145,7 @@ impl LibraryPanel {
             ui,
             frame,
             layout[0],
+            info.ratings,
         )?;
         self.track_list.draw(
             ActiveState::focused_if(self.focus == PanelItem::TrackList),
@@ -119,6 +153,7 @@ impl LibraryPanel {
             frame,
             layout[1],
             current_track,
+            info,
         )?;
         Ok(())
     }
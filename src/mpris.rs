@@ -1,27 +1,105 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashSet, path::PathBuf, sync::Arc, time::Duration};
 
+use log::warn;
 use mpris_server::{
     zbus::{self, fdo},
-    LoopStatus, PlaybackStatus, PlayerInterface, RootInterface, TrackId,
+    LoopStatus, Metadata, PlaybackStatus, PlayerInterface, Property, RootInterface, Server, Signal,
+    TrackId, TrackListInterface, TrackListSignal, Uri,
 };
 use smol::{channel::Sender, lock::RwLock};
 
 use crate::{
     app::{Command, Message},
     audio::Player,
-    library::ArtistName,
+    library::{ArtistName, Track},
+    ratings::{Ratings, MAX_STARS},
+    stats::Stats,
 };
 
-/// Mediates between the `App` struct and the [`RootInterface`] and [`PlayerInterface`] that we
-/// need to implement.
+/// Mediates between the `App` struct and the [`RootInterface`], [`PlayerInterface`] and
+/// [`TrackListInterface`] that we need to implement.
+///
+/// The track list is the play queue. Track IDs come from the library, so a track that's queued
+/// more than once has the same ID each time; anything that takes an ID acts on the first of
+/// them.
 pub(crate) struct MprisAdapter {
     tx: Sender<Message>,
     player: Arc<RwLock<Player>>,
+    stats: Arc<RwLock<Stats>>,
+    ratings: Arc<RwLock<Ratings>>,
+    /// Paths of every track in the library, so that clients can only add tracks from it.
+    library_paths: HashSet<PathBuf>,
 }
 
 impl MprisAdapter {
-    pub fn new(tx: Sender<Message>, player: Arc<RwLock<Player>>) -> Self {
-        Self { tx, player }
+    pub fn new(
+        tx: Sender<Message>,
+        player: Arc<RwLock<Player>>,
+        stats: Arc<RwLock<Stats>>,
+        ratings: Arc<RwLock<Ratings>>,
+        library_paths: HashSet<PathBuf>,
+    ) -> Self {
+        Self {
+            tx,
+            player,
+            stats,
+            ratings,
+            library_paths,
+        }
+    }
+
+    /// Takes a snapshot of what clients can see.
+    async fn state(&self) -> MprisState {
+        let player = self.player.read().await;
+        let track = player.current();
+        let stars = match &track {
+            Some(track) => self.ratings.read().await.track(&track.path).stars,
+            None => None,
+        };
+        MprisState {
+            status: playback_status(&player),
+            loop_status: player.queue().loop_status(),
+            shuffle: player.queue().shuffle(),
+            volume: player.volume(),
+            rate: player.speed(),
+            track: track.map(|track| track.mpris_id()),
+            stars,
+            seeks: player.seeks(),
+            queue_revision: player.queue().revision(),
+        }
+    }
+
+    async fn queue(&self) -> Vec<Arc<Track>> {
+        self.player.read().await.queue().tracks().to_vec()
+    }
+
+    async fn track_metadata(&self, track: &Track) -> Metadata {
+        let mut builder = Metadata::builder().trackid(track.mpris_id());
+        if let Some(title) = track.title.as_ref() {
+            builder = builder.title(title)
+        }
+        if let ArtistName::Artist(artist) = &track.artist {
+            builder = builder.artist(vec![artist.clone()]);
+        }
+        if let Some(album) = track.album.0.as_ref() {
+            builder = builder.album(album);
+        }
+        if let Some(track_number) = track.number {
+            builder = builder.track_number(track_number as i32);
+        }
+        let stats = self.stats.read().await.get(&track.path);
+        let mut builder = builder
+            .length(mpris_server::Time::from_micros((track.length.0 * 1_000_000.0) as i64))
+            .use_count(stats.plays as i32);
+        if let Some(stars) = self.ratings.read().await.track(&track.path).stars {
+            builder = builder.user_rating(f64::from(stars) / f64::from(MAX_STARS));
+        }
+        // How often the track gets played through rather than skipped.
+        if stats.plays + stats.skips > 0 {
+            let listens = f64::from(stats.plays) / f64::from(stats.plays + stats.skips);
+            builder = builder.auto_rating(listens);
+        }
+        builder.build()
     }
 
     /// Sends a command to the main task. This should never fail, but in case it does we return an
@@ -33,6 +111,159 @@ impl MprisAdapter {
     }
 }
 
+/// Everything that clients get told about when it changes, other than the position (which they're
+/// expected to work out for themselves, unless there's a seek).
+#[derive(Debug, Clone, PartialEq)]
+struct MprisState {
+    status: PlaybackStatus,
+    loop_status: LoopStatus,
+    shuffle: bool,
+    volume: f64,
+    rate: f64,
+    /// The current track and its rating, since the metadata is about those.
+    track: Option<TrackId>,
+    stars: Option<u8>,
+    /// See [`Player::seeks`].
+    seeks: u64,
+    /// See [`crate::audio::PlayQueue::revision`]. The tracks themselves are only fetched when this changes.
+    queue_revision: u64,
+}
+
+/// How the queue went from one [`MprisState`] to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QueueChange {
+    Unchanged,
+    /// A track was inserted at this index.
+    Added(usize),
+    /// The track at this index was taken out.
+    Removed(usize),
+    /// Anything else, like reordering or replacing the whole thing.
+    Replaced,
+}
+
+impl QueueChange {
+    fn between<T: PartialEq>(old: &[T], new: &[T]) -> Self {
+        if old == new {
+            return Self::Unchanged;
+        }
+        let common = old.iter().zip(new).take_while(|(old, new)| old == new).count();
+        let (shorter, longer, change) = if new.len() == old.len() + 1 {
+            (old, new, Self::Added(common))
+        } else if old.len() == new.len() + 1 {
+            (new, old, Self::Removed(common))
+        } else {
+            return Self::Replaced;
+        };
+        if shorter[common..] == longer[common + 1..] {
+            change
+        } else {
+            Self::Replaced
+        }
+    }
+}
+
+/// Tells clients about changes through the server's signals, so that they don't have to keep
+/// asking. Rather than tracking down everything that could change what they see, we compare
+/// snapshots after anything happens.
+pub(crate) struct MprisPublisher {
+    server: Server<MprisAdapter>,
+    last: MprisState,
+    /// The queue as of `last`, to work out what changed when its revision does.
+    queue: Vec<Arc<Track>>,
+}
+
+impl MprisPublisher {
+    pub async fn new(server: Server<MprisAdapter>) -> Self {
+        let last = server.imp().state().await;
+        let queue = server.imp().queue().await;
+        Self {
+            server,
+            last,
+            queue,
+        }
+    }
+
+    /// Emits `PropertiesChanged` for whatever's different since the last call, and `Seeked` if
+    /// there was a seek.
+    pub async fn publish(&mut self) {
+        if let Err(e) = self.try_publish().await {
+            warn!("Couldn't tell MPRIS clients what changed: {e}");
+        }
+    }
+
+    async fn try_publish(&mut self) -> zbus::Result<()> {
+        let imp = self.server.imp();
+        let state = imp.state().await;
+        if state == self.last {
+            return Ok(());
+        }
+        let last = std::mem::replace(&mut self.last, state.clone());
+        let mut changed = vec![];
+        if state.status != last.status {
+            changed.push(Property::PlaybackStatus(state.status));
+        }
+        if state.loop_status != last.loop_status {
+            changed.push(Property::LoopStatus(state.loop_status));
+        }
+        if state.shuffle != last.shuffle {
+            changed.push(Property::Shuffle(state.shuffle));
+        }
+        if state.volume != last.volume {
+            changed.push(Property::Volume(state.volume));
+        }
+        if state.rate != last.rate {
+            changed.push(Property::Rate(state.rate));
+        }
+        if (&state.track, state.stars) != (&last.track, last.stars) {
+            // With no track, this is empty.
+            changed
+                .push(Property::Metadata(imp.metadata().await.unwrap_or_else(|_| Metadata::new())));
+        }
+        if !changed.is_empty() {
+            self.server.properties_changed(changed).await?;
+        }
+        if state.seeks != last.seeks {
+            if let Ok(position) = imp.position().await {
+                self.server.emit(Signal::Seeked { position }).await?;
+            }
+        }
+        if state.queue_revision == last.queue_revision {
+            return Ok(());
+        }
+        let queue = imp.queue().await;
+        let last_queue = std::mem::replace(&mut self.queue, queue.clone());
+        let signal = match QueueChange::between(&last_queue, &queue) {
+            QueueChange::Unchanged => return Ok(()),
+            QueueChange::Added(index) => TrackListSignal::TrackAdded {
+                metadata: imp.track_metadata(&queue[index]).await,
+                after_track: match index.checked_sub(1) {
+                    Some(before) => queue[before].mpris_id(),
+                    None => TrackId::NO_TRACK,
+                },
+            },
+            QueueChange::Removed(index) => TrackListSignal::TrackRemoved {
+                track_id: last_queue[index].mpris_id(),
+            },
+            QueueChange::Replaced => TrackListSignal::TrackListReplaced {
+                tracks: queue.iter().map(|track| track.mpris_id()).collect(),
+                current_track: state.track.clone().unwrap_or(TrackId::NO_TRACK),
+            },
+        };
+        self.server.track_list_emit(signal).await?;
+        Ok(())
+    }
+}
+
+fn playback_status(player: &Player) -> PlaybackStatus {
+    if player.stopped() {
+        PlaybackStatus::Stopped
+    } else if player.playing() {
+        PlaybackStatus::Playing
+    } else {
+        PlaybackStatus::Paused
+    }
+}
+
 /// Declares a method that sends `Command::$command`.
 macro_rules! sends_command {
     ($method:ident, $command:ident) => {
@@ -55,13 +286,14 @@ impl RootInterface for MprisAdapter {
     returns!(identity, String, "deimos".into());
     returns!(desktop_entry, String, "".into());
     returns!(supported_mime_types, Vec<String>, vec![]);
-    returns!(supported_uri_schemes, Vec<String>, vec![]);
+    // Only for adding tracks; we can't open URIs.
+    returns!(supported_uri_schemes, Vec<String>, vec!["file".into()]);
     returns!(raise, (), ());
     returns!(can_raise, bool, false);
     returns!(can_quit, bool, true);
     returns!(fullscreen, bool, false);
     returns!(can_set_fullscreen, bool, false);
-    returns!(has_track_list, bool, false);
+    returns!(has_track_list, bool, true);
     sends_command!(quit, Quit);
 
     async fn set_fullscreen(&self, _fullscreen: bool) -> zbus::Result<()> {
@@ -85,14 +317,7 @@ impl PlayerInterface for MprisAdapter {
     returns!(can_go_previous, bool, true);
 
     async fn playback_status(&self) -> fdo::Result<PlaybackStatus> {
-        let player = self.player.read().await;
-        Ok(if player.stopped() {
-            PlaybackStatus::Stopped
-        } else if player.playing().await {
-            PlaybackStatus::Playing
-        } else {
-            PlaybackStatus::Paused
-        })
+        Ok(playback_status(&*self.player.read().await))
     }
 
     async fn loop_status(&self) -> fdo::Result<LoopStatus> {
@@ -140,21 +365,38 @@ impl PlayerInterface for MprisAdapter {
 
     // rate
 
-    returns!(rate, f64, 1.0);
-    returns!(minimum_rate, f64, 1.0);
-    returns!(maximum_rate, f64, 1.0);
-    async fn set_rate(&self, _rate: f64) -> zbus::Result<()> {
-        Err(fdo::Error::NotSupported("can't set rate".into()).into())
+    async fn rate(&self) -> fdo::Result<f64> {
+        Ok(self.player.read().await.speed())
+    }
+
+    returns!(minimum_rate, f64, Player::MIN_SPEED);
+    returns!(maximum_rate, f64, Player::MAX_SPEED);
+
+    async fn set_rate(&self, rate: f64) -> zbus::Result<()> {
+        if !rate.is_finite() {
+            return Err(fdo::Error::InvalidArgs(format!("invalid rate: {rate}")).into());
+        }
+        // The spec says a rate of 0 should act like pausing.
+        if rate == 0.0 {
+            self.send_command(Command::Pause)?;
+        } else {
+            self.send_command(Command::SetSpeed(rate))?;
+        }
+        Ok(())
     }
 
     // misc
 
     async fn volume(&self) -> fdo::Result<mpris_server::Volume> {
-        Ok(1.0)
+        Ok(self.player.read().await.volume())
     }
 
-    async fn set_volume(&self, _volume: f64) -> zbus::Result<()> {
-        todo!()
+    async fn set_volume(&self, volume: f64) -> zbus::Result<()> {
+        if !volume.is_finite() {
+            return Err(fdo::Error::InvalidArgs(format!("invalid volume: {volume}")).into());
+        }
+        self.send_command(Command::SetVolume(volume))?;
+        Ok(())
     }
 
     async fn metadata(&self) -> fdo::Result<mpris_server::Metadata> {
@@ -164,25 +406,118 @@ impl PlayerInterface for MprisAdapter {
             .await
             .current()
             .ok_or(fdo::Error::Failed("no current song".into()))?;
-        let mut builder = mpris_server::Metadata::builder().trackid(track.mpris_id());
-        if let Some(title) = track.title.as_ref() {
-            builder = builder.title(title)
-        }
-        if let ArtistName::Artist(artist) = &track.artist {
-            builder = builder.artist(vec![artist.clone()]);
+        Ok(self.track_metadata(&track).await)
+    }
+
+    async fn open_uri(&self, _uri: String) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported("can't open URIs".into()))
+    }
+}
+
+impl TrackListInterface for MprisAdapter {
+    async fn get_tracks_metadata(&self, track_ids: Vec<TrackId>) -> fdo::Result<Vec<Metadata>> {
+        let queue = self.queue().await;
+        let mut metadata = vec![];
+        // The spec says to leave out IDs that aren't in the track list.
+        for id in track_ids {
+            if let Some(track) = queue.iter().find(|track| track.mpris_id() == id) {
+                metadata.push(self.track_metadata(track).await);
+            }
         }
-        if let Some(album) = track.album.0.as_ref() {
-            builder = builder.album(album);
+        Ok(metadata)
+    }
+
+    async fn add_track(
+        &self,
+        uri: Uri,
+        after_track: TrackId,
+        set_as_current: bool,
+    ) -> fdo::Result<()> {
+        let path = uri_to_path(&uri)
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("not a file URI: {uri}")))?;
+        if !self.library_paths.contains(&path) {
+            let message = format!("{} isn't in the library", path.display());
+            return Err(fdo::Error::InvalidArgs(message));
         }
-        if let Some(track_number) = track.number {
-            builder = builder.track_number(track_number as i32);
+        self.send_command(Command::AddTrackAfter {
+            path,
+            after: after_track,
+            play: set_as_current,
+        })
+    }
+
+    async fn remove_track(&self, track_id: TrackId) -> fdo::Result<()> {
+        self.send_command(Command::RemoveTrackById(track_id))
+    }
+
+    async fn go_to(&self, track_id: TrackId) -> fdo::Result<()> {
+        self.send_command(Command::GoToTrack(track_id))
+    }
+
+    async fn tracks(&self) -> fdo::Result<Vec<TrackId>> {
+        let player = self.player.read().await;
+        Ok(player.queue().tracks().iter().map(|track| track.mpris_id()).collect())
+    }
+
+    returns!(can_edit_tracks, bool, true);
+}
+
+/// Turns a `file://` URI, or a plain absolute path, into a path.
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = match uri.strip_prefix("file://") {
+        Some(rest) => rest.strip_prefix("localhost").unwrap_or(rest),
+        None if uri.starts_with('/') => return Some(PathBuf::from(uri)),
+        None => return None,
+    };
+    if !path.starts_with('/') {
+        // Files on other hosts are no use to us.
+        return None;
+    }
+    // Undo percent-encoding, e.g. %20 for spaces.
+    let mut bytes = vec![];
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = tail
+            .get(..2)
+            .filter(|_| byte == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
         }
-        let builder =
-            builder.length(mpris_server::Time::from_micros((track.length.0 * 1_000_000.0) as i64));
-        Ok(builder.build())
     }
+    Some(PathBuf::from(String::from_utf8(bytes).ok()?))
+}
 
-    async fn open_uri(&self, _uri: String) -> fdo::Result<()> {
-        Err(fdo::Error::NotSupported("can't open URIs".into()))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_changes() {
+        use QueueChange::*;
+        assert_eq!(QueueChange::between(&[1, 2, 3], &[1, 2, 3]), Unchanged);
+        assert_eq!(QueueChange::between(&[1, 2, 3], &[1, 4, 2, 3]), Added(1));
+        assert_eq!(QueueChange::between(&[], &[1]), Added(0));
+        assert_eq!(QueueChange::between(&[1, 2, 3], &[1, 2]), Removed(2));
+        assert_eq!(QueueChange::between(&[1, 2, 3], &[2, 1, 3]), Replaced);
+        assert_eq!(QueueChange::between(&[1, 2, 3], &[1, 4, 3, 5]), Replaced);
+        assert_eq!(QueueChange::between(&[1, 2, 3], &[]), Replaced);
+    }
+
+    #[test]
+    fn uris_to_paths() {
+        let path = |uri| uri_to_path(uri).map(|p| p.display().to_string());
+        assert_eq!(path("file:///music/a%20b.flac").as_deref(), Some("/music/a b.flac"));
+        assert_eq!(path("file://localhost/music/x.mp3").as_deref(), Some("/music/x.mp3"));
+        assert_eq!(path("/music/100%.mp3").as_deref(), Some("/music/100%.mp3"));
+        assert_eq!(path("http://example.com/a.mp3"), None);
+        assert_eq!(path("file://host/a.mp3"), None);
     }
 }
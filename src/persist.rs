@@ -0,0 +1,86 @@
+//! Loading and saving the small JSON files we keep next to the library cache.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Reads the JSON file at `path`, or returns the default if there isn't one. `what` names the
+/// contents for error messages.
+pub fn load_json<T: DeserializeOwned + Default>(path: &Path, what: &str) -> Result<T> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(&fs::read(path)?)
+        .wrap_err_with(|| format!("couldn't parse {what} at {}", path.display()))
+}
+
+/// Writes `value` to `path` as compact JSON.
+pub fn save_json(path: &Path, value: &impl Serialize, what: &str) -> Result<()> {
+    write_atomically(path, &serde_json::to_vec(value)?)
+        .wrap_err_with(|| format!("couldn't write {what} to {}", path.display()))
+}
+
+/// Writes `value` to `path` as indented JSON, for files people are expected to edit.
+pub fn save_json_pretty(path: &Path, value: &impl Serialize, what: &str) -> Result<()> {
+    write_atomically(path, &serde_json::to_vec_pretty(value)?)
+        .wrap_err_with(|| format!("couldn't write {what} to {}", path.display()))
+}
+
+/// Replaces the contents of `path` with `bytes`, creating its directory if needed. The bytes go to
+/// a temporary file that's then renamed over `path`, so if we crash partway through, the old file
+/// is left intact rather than truncated.
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = temp_path(path);
+    let mut file = File::create(&temp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".tmp");
+    name.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn save_and_load() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("deimos-persist-{}", std::process::id()));
+        let path = dir.join("nested").join("counts.json");
+        let missing: HashMap<String, u32> = load_json(&path, "counts")?;
+        assert!(missing.is_empty());
+
+        let counts = HashMap::from([("a".to_owned(), 1), ("b".to_owned(), 2)]);
+        save_json(&path, &counts, "counts")?;
+        assert_eq!(load_json::<HashMap<String, u32>>(&path, "counts")?, counts);
+
+        // Saving again replaces the file, and doesn't leave the temporary one behind.
+        save_json_pretty(&path, &HashMap::from([("c".to_owned(), 3)]), "counts")?;
+        let loaded: HashMap<String, u32> = load_json(&path, "counts")?;
+        let temp_left = temp_path(&path).exists();
+        fs::write(&path, "{")?;
+        let error = load_json::<HashMap<String, u32>>(&path, "counts").unwrap_err();
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(loaded, HashMap::from([("c".to_owned(), 3)]));
+        assert!(!temp_left);
+        assert!(error.to_string().starts_with("couldn't parse counts at"));
+        Ok(())
+    }
+}
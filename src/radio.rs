@@ -0,0 +1,169 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use itertools::Itertools;
+use ordered_float::OrderedFloat;
+
+use crate::{
+    config::RadioConfig,
+    history::History,
+    library::{Library, Track},
+};
+
+/// How many recently played tracks we look at to decide what to play next.
+const SEEDS: usize = 10;
+/// How much less each seed counts than the one played after it.
+const SEED_DECAY: f64 = 0.8;
+/// Tracks released this many years apart count as being from the same time.
+const YEAR_WINDOW: u32 = 3;
+
+/// Picks tracks to add to the end of the queue in radio mode. They're chosen from `library` to
+/// be like the end of `queue` and the most recent plays in `history`, and don't include anything
+/// already in the queue or played recently. Returns fewer than `config.batch_size` tracks if
+/// there aren't enough left to choose from.
+pub fn pick_tracks(
+    library: &Library,
+    history: &History,
+    queue: &[Arc<Track>],
+    config: &RadioConfig,
+) -> Vec<Arc<Track>> {
+    let by_path: HashMap<PathBuf, Arc<Track>> =
+        library.tracks().map(|track| (track.path.clone(), track)).collect();
+    // Note that the most recent track might be in both the queue and the history.
+    let seeds = queue
+        .iter()
+        .rev()
+        .map(|track| track.path.as_path())
+        .chain(history.recent().map(|play| play.path.as_path()))
+        .unique()
+        .filter_map(|path| by_path.get(path).cloned())
+        .take(SEEDS)
+        .collect_vec();
+    let excluded: HashSet<PathBuf> = queue
+        .iter()
+        .map(|track| track.path.clone())
+        .chain(history.recent().take(config.exclude_recent).map(|play| play.path.clone()))
+        .collect();
+
+    let scored = library
+        .tracks()
+        .filter(|track| !excluded.contains(&track.path))
+        .map(|track| {
+            let score = seeds
+                .iter()
+                .zip(std::iter::successors(Some(1.0), |w| Some(w * SEED_DECAY)))
+                .map(|(seed, weight)| weight * similarity(seed, &track))
+                .sum::<f64>();
+            (track, score)
+        })
+        .collect_vec();
+    let best = scored.iter().map(|(_, score)| *score).fold(0.0, f64::max);
+    // Softmax with `randomness` as the temperature, sampled without replacement using keys of
+    // ln(u) / weight. Subtracting the best score keeps the exponent from overflowing.
+    let temperature = config.randomness.max(0.01);
+    scored
+        .into_iter()
+        .map(|(track, score)| {
+            let weight = ((score - best) / temperature).exp();
+            let key = fastrand::f64().ln() / weight;
+            (track, key)
+        })
+        .sorted_by_key(|(_, key)| std::cmp::Reverse(OrderedFloat(*key)))
+        .take(config.batch_size)
+        .map(|(track, _)| track)
+        .collect()
+}
+
+/// How alike two tracks are, from 0 up.
+fn similarity(a: &Track, b: &Track) -> f64 {
+    let mut score = 0.0;
+    if a.artist == b.artist {
+        score += 1.0;
+    }
+    if a.genre.is_some() && a.genre == b.genre {
+        score += 0.6;
+    }
+    if let (Some(a), Some(b)) = (a.year, b.year) {
+        if a.abs_diff(b) <= YEAR_WINDOW {
+            score += 0.3;
+        }
+    }
+    score
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::{
+        history::{Play, PlayEnd},
+        library::ArtistName,
+    };
+
+    fn library() -> Library {
+        let mut library = Library::default();
+        for id in 0..20 {
+            library
+                .insert_track(Track {
+                    artist: ArtistName::Artist(format!("Artist {}", id % 4)),
+                    genre: Some(format!("Genre {}", id % 2)),
+                    year: Some(1990 + id as u32 / 2),
+                    ..Track::test_track(id)
+                })
+                .unwrap();
+        }
+        library
+    }
+
+    fn config(randomness: f64) -> RadioConfig {
+        RadioConfig {
+            randomness,
+            exclude_recent: 10,
+            batch_size: 3,
+            ..RadioConfig::default()
+        }
+    }
+
+    #[test]
+    fn picks_similar_tracks() {
+        let library = library();
+        let queue = library.tracks().filter(|t| t.id == 5).collect_vec();
+        let config = RadioConfig {
+            batch_size: 2,
+            ..config(0.0)
+        };
+        let picked = pick_tracks(&library, &History::default(), &queue, &config);
+        let ids = picked.iter().map(|t| t.id).sorted().collect_vec();
+        // the same artist and genre, and the only ones of those from around the same time
+        assert_eq!(ids, [1, 9]);
+    }
+
+    #[test]
+    fn excludes_queue_and_recent_plays() {
+        let library = library();
+        let mut history = History::default();
+        for id in 0..10 {
+            history.record(Play {
+                path: Track::test_track(id).path,
+                started: SystemTime::UNIX_EPOCH + Duration::from_secs(id),
+                listened: Duration::ZERO,
+                end: PlayEnd::Completed,
+            });
+        }
+        let queue = library.tracks().filter(|t| t.id >= 15).collect_vec();
+        for randomness in [0.0, 0.5, 1.0] {
+            let picked = pick_tracks(&library, &history, &queue, &config(randomness));
+            assert_eq!(picked.len(), 3);
+            assert!(picked.iter().all(|t| (10..15).contains(&t.id)), "{picked:?}");
+        }
+        let config = RadioConfig {
+            batch_size: 10,
+            ..config(1.0)
+        };
+        assert_eq!(pick_tracks(&library, &history, &queue, &config).len(), 5);
+    }
+}
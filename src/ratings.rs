@@ -0,0 +1,289 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use eyre::{eyre, Result};
+use lofty::{
+    id3::v2::{Frame, FrameFlags, FrameValue, Id3v2Tag, Popularimeter},
+    mpeg::MpegFile,
+    AudioFile, FileType, ItemKey, ParseOptions, TagExt, TagType, TaggedFileExt,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    library::{AlbumName, ArtistName},
+    persist,
+};
+
+/// The most stars a track or album can have.
+pub const MAX_STARS: u8 = 5;
+
+/// Email that we write into POPM frames. Players use this to tell whose rating is whose.
+const POPM_EMAIL: &str = "deimos";
+/// Where FMPS-style tags store the rating, as a number from 0 to 1.
+const FMPS_RATING: &str = "FMPS_RATING";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TrackRating {
+    /// From 1 to [`MAX_STARS`]. `None` if the track hasn't been rated.
+    pub stars: Option<u8>,
+    pub loved: bool,
+}
+
+/// Star ratings for tracks and albums, plus which tracks are loved. Tracks are identified by their
+/// path, and albums by their artist and name. This is saved next to the library cache.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Ratings {
+    tracks: HashMap<PathBuf, TrackRating>,
+    /// Maps artist to album to stars.
+    albums: HashMap<String, HashMap<String, u8>>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl Ratings {
+    /// Loads ratings from the given path. A missing file means nothing has been rated.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut ratings: Self = persist::load_json(path, "ratings")?;
+        ratings.path = Some(path.to_owned());
+        Ok(ratings)
+    }
+
+    /// Writes the ratings back to where they were loaded from. Does nothing if they weren't loaded
+    /// from a file.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(());
+        };
+        persist::save_json(path, self, "ratings")
+    }
+
+    pub fn track(&self, path: &Path) -> TrackRating {
+        self.tracks.get(path).copied().unwrap_or_default()
+    }
+
+    /// Sets the track's stars, clamped to [`MAX_STARS`]. `None` or 0 clears the rating.
+    pub fn set_stars(&mut self, path: &Path, stars: Option<u8>) {
+        let stars = stars.filter(|s| *s > 0).map(|s| s.min(MAX_STARS));
+        self.update(path, |rating| rating.stars = stars);
+    }
+
+    /// Toggles whether the track is loved, returning the new state.
+    pub fn toggle_loved(&mut self, path: &Path) -> bool {
+        self.update(path, |rating| rating.loved = !rating.loved).loved
+    }
+
+    fn update(&mut self, path: &Path, f: impl FnOnce(&mut TrackRating)) -> TrackRating {
+        let mut rating = self.track(path);
+        f(&mut rating);
+        // Don't keep around entries for tracks that aren't rated anymore.
+        if rating == TrackRating::default() {
+            self.tracks.remove(path);
+        } else {
+            self.tracks.insert(path.to_owned(), rating);
+        }
+        rating
+    }
+
+    /// Paths of every loved track, in no particular order.
+    pub fn loved(&self) -> impl Iterator<Item = &Path> + '_ {
+        self.tracks
+            .iter()
+            .filter(|(_, rating)| rating.loved)
+            .map(|(path, _)| path.as_path())
+    }
+
+    pub fn album_stars(&self, artist: &ArtistName, album: &AlbumName) -> Option<u8> {
+        self.albums.get(&artist.to_string())?.get(&album.to_string()).copied()
+    }
+
+    /// Sets the album's stars, clamped to [`MAX_STARS`]. `None` or 0 clears the rating.
+    pub fn set_album_stars(&mut self, artist: &ArtistName, album: &AlbumName, stars: Option<u8>) {
+        let albums = self.albums.entry(artist.to_string()).or_default();
+        match stars.filter(|s| *s > 0) {
+            Some(stars) => albums.insert(album.to_string(), stars.min(MAX_STARS)),
+            None => albums.remove(&album.to_string()),
+        };
+        if albums.is_empty() {
+            self.albums.remove(&artist.to_string());
+        }
+    }
+}
+
+/// Reads a star rating from the file's tags: a POPM frame for MP3s, or `FMPS_RATING` for Vorbis
+/// comments and APE tags. If other players have left POPM frames too, ours wins.
+pub fn read_tag_stars(path: &Path) -> Result<Option<u8>> {
+    if lofty::read_from_path(path)?.file_type() == FileType::Mpeg {
+        let Some(tag) = read_id3v2(path)? else {
+            return Ok(None);
+        };
+        let popms = (&tag).into_iter().filter_map(|frame| match frame.content() {
+            FrameValue::Popularimeter(popm) => Some(popm),
+            _ => None,
+        });
+        let (ours, theirs): (Vec<_>, Vec<_>) = popms.partition(|popm| popm.email == POPM_EMAIL);
+        return Ok(ours
+            .into_iter()
+            .chain(theirs)
+            .next()
+            .and_then(|popm| popm_to_stars(popm.rating)));
+    }
+    let tagged_file = lofty::read_from_path(path)?;
+    let Some(tag) = tagged_file.primary_tag().filter(|tag| uses_fmps(tag.tag_type())) else {
+        return Ok(None);
+    };
+    Ok(tag
+        .get_string(&ItemKey::Unknown(FMPS_RATING.into()))
+        .and_then(|value| value.trim().parse().ok())
+        .and_then(fmps_to_stars))
+}
+
+/// Writes a star rating to the file's tags, in the format that [`read_tag_stars`] reads for it.
+/// Other players' POPM frames are left alone.
+pub fn write_tag_stars(path: &Path, stars: Option<u8>) -> Result<()> {
+    let stars = stars.unwrap_or(0).min(MAX_STARS);
+    let mut tagged_file = lofty::read_from_path(path)?;
+    if tagged_file.file_type() == FileType::Mpeg {
+        // Going through lofty's generic `Tag` would lose any frames it can't represent, so edit
+        // the ID3v2 tag itself.
+        let mut tag = read_id3v2(path)?.unwrap_or_default();
+        let popm = Popularimeter {
+            email: POPM_EMAIL.to_owned(),
+            rating: stars_to_popm(stars),
+            counter: 0,
+        };
+        // POPM frames with different emails count as different frames, so this only replaces
+        // ours.
+        tag.insert(Frame::new("POPM", FrameValue::Popularimeter(popm), FrameFlags::default())?);
+        tag.save_to_path(path)?;
+        return Ok(());
+    }
+    let tag_type = tagged_file.primary_tag_type();
+    if !uses_fmps(tag_type) {
+        return Err(eyre!("can't write a rating to {tag_type:?} tags"));
+    }
+    let tag = tagged_file
+        .primary_tag_mut()
+        .ok_or_else(|| eyre!("{} has no tags to write a rating to", path.display()))?;
+    let fmps = f32::from(stars) / f32::from(MAX_STARS);
+    tag.insert_text(ItemKey::Unknown(FMPS_RATING.into()), fmps.to_string());
+    tag.save_to_path(path)?;
+    Ok(())
+}
+
+/// Reads an MP3's ID3v2 tag as it is, rather than converted to lofty's generic `Tag`.
+fn read_id3v2(path: &Path) -> Result<Option<Id3v2Tag>> {
+    let options = ParseOptions::new().read_properties(false);
+    let file = MpegFile::read_from(&mut File::open(path)?, options)?;
+    Ok(file.id3v2().cloned())
+}
+
+/// Whether ratings in this kind of tag go in `FMPS_RATING`.
+fn uses_fmps(tag_type: TagType) -> bool {
+    matches!(tag_type, TagType::VorbisComments | TagType::Ape)
+}
+
+/// Converts a POPM rating byte to stars, using the same ranges as Windows Media Player.
+fn popm_to_stars(rating: u8) -> Option<u8> {
+    match rating {
+        0 => None,
+        1..=31 => Some(1),
+        32..=95 => Some(2),
+        96..=159 => Some(3),
+        160..=223 => Some(4),
+        _ => Some(5),
+    }
+}
+
+fn stars_to_popm(stars: u8) -> u8 {
+    match stars {
+        0 => 0,
+        1 => 1,
+        2 => 64,
+        3 => 128,
+        4 => 196,
+        _ => 255,
+    }
+}
+
+fn fmps_to_stars(value: f32) -> Option<u8> {
+    let stars = (value.clamp(0.0, 1.0) * f32::from(MAX_STARS)).round() as u8;
+    (stars > 0).then_some(stars)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn track_ratings() {
+        let mut ratings = Ratings::default();
+        let path = Path::new("/a.mp3");
+        ratings.set_stars(path, Some(9));
+        assert_eq!(ratings.track(path).stars, Some(MAX_STARS));
+        assert!(ratings.toggle_loved(path));
+        assert_eq!(ratings.loved().collect::<Vec<_>>(), [path]);
+        ratings.set_stars(path, Some(0));
+        assert!(!ratings.toggle_loved(path));
+        assert!(ratings.tracks.is_empty(), "unrated tracks shouldn't be stored");
+    }
+
+    #[test]
+    fn album_ratings() {
+        let mut ratings = Ratings::default();
+        let artist = ArtistName::Artist("Artist".into());
+        let album = AlbumName(Some("Album".into()));
+        ratings.set_album_stars(&artist, &album, Some(3));
+        assert_eq!(ratings.album_stars(&artist, &album), Some(3));
+        assert_eq!(ratings.album_stars(&ArtistName::Unknown, &album), None);
+        ratings.set_album_stars(&artist, &album, None);
+        assert!(ratings.albums.is_empty());
+    }
+
+    #[test]
+    fn tag_conversions() {
+        for stars in 0..=MAX_STARS {
+            assert_eq!(popm_to_stars(stars_to_popm(stars)), Some(stars).filter(|s| *s > 0));
+            let fmps = f32::from(stars) / f32::from(MAX_STARS);
+            assert_eq!(fmps_to_stars(fmps), Some(stars).filter(|s| *s > 0));
+        }
+    }
+
+    #[test]
+    fn tag_round_trip() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("deimos-rating-{}.mp3", std::process::id()));
+        fs::copy(crate::test_data!("3_seconds.mp3"), &path)?;
+        let result = (|| {
+            // Someone else's rating, which should be kept.
+            let mut tag = read_id3v2(&path)?.unwrap_or_default();
+            let theirs = Popularimeter {
+                email: "someone@else".to_owned(),
+                rating: 255,
+                counter: 3,
+            };
+            let theirs =
+                Frame::new("POPM", FrameValue::Popularimeter(theirs), FrameFlags::default())?;
+            tag.insert(theirs.clone());
+            tag.save_to_path(&path)?;
+            assert_eq!(read_tag_stars(&path)?, Some(5));
+
+            for stars in [Some(4), Some(1)] {
+                write_tag_stars(&path, stars)?;
+                assert_eq!(read_tag_stars(&path)?, stars);
+            }
+            let tag = read_id3v2(&path)?.expect("tag was written");
+            let kept = (&tag).into_iter().any(|frame| *frame == theirs);
+            assert!(kept, "other ratings were lost");
+            Ok(())
+        })();
+        fs::remove_file(&path)?;
+        result
+    }
+}
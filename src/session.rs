@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use eyre::Result;
+use log::warn;
+use mpris_server::LoopStatus;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audio::{PlayQueue, Player, ShuffleMode},
+    library::{Library, Track},
+    persist,
+};
+
+#[derive(Deserialize, Serialize)]
+#[serde(remote = "LoopStatus")]
+enum LoopStatusDef {
+    None,
+    Track,
+    Playlist,
+}
+
+/// Everything needed to pick up where the user left off: the play queue, the position in it, and
+/// playback modes. Tracks are identified by their path, since IDs change when the library is
+/// rescanned. This is saved next to the library cache.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Session {
+    /// The play queue, in play order.
+    queue: Vec<PathBuf>,
+    /// The play queue in the order it was added, for unshuffling.
+    original_order: Vec<PathBuf>,
+    index: Option<usize>,
+    timestamp: Option<Duration>,
+    #[serde(with = "LoopStatusDef")]
+    loop_status: LoopStatus,
+    shuffle: bool,
+    shuffle_mode: ShuffleMode,
+    volume: f64,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            queue: vec![],
+            original_order: vec![],
+            index: None,
+            timestamp: None,
+            loop_status: LoopStatus::None,
+            shuffle: false,
+            shuffle_mode: ShuffleMode::default(),
+            volume: 1.0,
+            path: None,
+        }
+    }
+}
+
+impl Session {
+    /// Loads the session from the given path. A missing file is the same as an empty session.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut session: Self = persist::load_json(path, "session")?;
+        session.path = Some(path.to_owned());
+        Ok(session)
+    }
+
+    /// Writes the session back to where it was loaded from. Does nothing if it wasn't loaded from
+    /// a file.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(());
+        };
+        persist::save_json(path, self, "session")
+    }
+
+    /// Updates the session to match the player's current state.
+    pub fn update(&mut self, player: &Player) {
+        let paths = |tracks: &[Arc<Track>]| tracks.iter().map(|t| t.path.clone()).collect();
+        let queue = player.queue();
+        self.queue = paths(queue.tracks());
+        self.original_order = paths(queue.original_order());
+        self.index = queue.current();
+        self.timestamp = player.timestamp();
+        self.loop_status = queue.loop_status();
+        self.shuffle = queue.shuffle();
+        self.shuffle_mode = queue.shuffle_mode();
+        self.volume = player.volume();
+    }
+
+    /// Rebuilds the play queue, looking up tracks in `library`. Tracks that aren't in the library
+    /// anymore are dropped. If that includes the current track, nothing is selected.
+    pub fn queue(&self, library: &Library) -> PlayQueue {
+        let by_path: HashMap<_, _> = library.tracks().map(|t| (t.path.clone(), t)).collect();
+        let lookup = |paths: &[PathBuf]| {
+            paths.iter().filter_map(|path| by_path.get(path).cloned()).collect::<Vec<_>>()
+        };
+        let tracks = lookup(&self.queue);
+        let original_order = lookup(&self.original_order);
+        if tracks.len() != self.queue.len() {
+            warn!(
+                "{} tracks in the saved queue are no longer in the library",
+                self.queue.len() - tracks.len()
+            );
+        }
+        let current = self.index.and_then(|i| self.queue.get(i));
+        let index = current.and_then(|path| tracks.iter().position(|t| t.path == *path));
+        PlayQueue::from_parts(
+            tracks,
+            original_order,
+            index,
+            self.loop_status,
+            self.shuffle,
+            self.shuffle_mode,
+        )
+    }
+
+    /// Where to resume the current track from. `None` if there's no current track.
+    pub fn timestamp(&self) -> Option<Duration> {
+        self.timestamp.filter(|_| self.index.is_some())
+    }
+
+    pub fn volume(&self) -> f64 {
+        self.volume
+    }
+
+    /// Overrides the volume taken from the player, e.g. to save the volume from before a fade.
+    pub fn set_volume(&mut self, volume: f64) {
+        self.volume = volume;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library(ids: impl IntoIterator<Item = u64>) -> Library {
+        let mut library = Library::default();
+        for id in ids {
+            library.insert_track(Track::test_track(id)).unwrap();
+        }
+        library
+    }
+
+    fn session(index: Option<usize>) -> Session {
+        let paths = |ids: &[u64]| ids.iter().map(|id| Track::test_track(*id).path).collect();
+        Session {
+            queue: paths(&[2, 0, 1]),
+            original_order: paths(&[0, 1, 2]),
+            index,
+            timestamp: Some(Duration::from_secs(10)),
+            loop_status: LoopStatus::Playlist,
+            shuffle: true,
+            shuffle_mode: ShuffleMode::Albums,
+            ..Session::default()
+        }
+    }
+
+    #[test]
+    fn restores_queue() {
+        let queue = session(Some(1)).queue(&library(0..3));
+        let ids = |tracks: &[Arc<Track>]| tracks.iter().map(|t| t.id).collect::<Vec<_>>();
+        assert_eq!(ids(queue.tracks()), [2, 0, 1]);
+        assert_eq!(ids(queue.original_order()), [0, 1, 2]);
+        assert_eq!(queue.current_track().map(|t| t.id), Some(0));
+        assert_eq!(queue.loop_status(), LoopStatus::Playlist);
+        assert!(queue.shuffle());
+        assert_eq!(queue.shuffle_mode(), ShuffleMode::Albums);
+    }
+
+    #[test]
+    fn missing_tracks() {
+        // track 2 was deleted, so the current track moves
+        let queue = session(Some(1)).queue(&library([0, 1]));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.current_track().map(|t| t.id), Some(0));
+
+        // the current track was deleted
+        let queue = session(Some(1)).queue(&library([1, 2]));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.current(), None);
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let json = serde_json::to_string(&session(Some(0)))?;
+        let session: Session = serde_json::from_str(&json)?;
+        assert_eq!(session.loop_status, LoopStatus::Playlist);
+        assert_eq!(session.timestamp(), Some(Duration::from_secs(10)));
+        Ok(())
+    }
+}
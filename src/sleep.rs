@@ -0,0 +1,132 @@
+use std::time::{Duration, Instant};
+
+/// How long fading out takes, ending when the timer goes off.
+const FADE_DURATION: Duration = Duration::from_secs(30);
+
+/// When a [`SleepTimer`] goes off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepAt {
+    Time(Instant),
+    /// Once this many more tracks have finished, counting the current one.
+    Tracks(u32),
+}
+
+/// Stops playback at a given time or after some number of tracks, optionally fading out first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SleepTimer {
+    pub at: SleepAt,
+    pub fade_out: bool,
+    /// The volume from before we started fading, so that it can be put back afterwards.
+    volume: Option<f64>,
+}
+
+impl SleepTimer {
+    pub fn new(at: SleepAt, fade_out: bool) -> Self {
+        Self {
+            at,
+            fade_out,
+            volume: None,
+        }
+    }
+
+    /// A timer that goes off `duration` from now.
+    pub fn after(duration: Duration, fade_out: bool) -> Self {
+        Self::new(SleepAt::Time(Instant::now() + duration), fade_out)
+    }
+
+    /// How long until the timer goes off. `track_left` is how much of the current track is left,
+    /// if anything's playing. `None` if we can't tell yet, because it's more than one track away.
+    pub fn remaining(&self, now: Instant, track_left: Option<Duration>) -> Option<Duration> {
+        match self.at {
+            SleepAt::Time(at) => Some(at.saturating_duration_since(now)),
+            SleepAt::Tracks(1) => track_left,
+            SleepAt::Tracks(_) => None,
+        }
+    }
+
+    /// True if this is a timed timer and its time is up. Timers counting tracks go off through
+    /// [`SleepTimer::track_finished`] instead.
+    pub fn expired(&self, now: Instant) -> bool {
+        matches!(self.at, SleepAt::Time(at) if at <= now)
+    }
+
+    /// Counts off a finished track. Returns true if that was the last one.
+    pub fn track_finished(&mut self) -> bool {
+        match &mut self.at {
+            SleepAt::Time(_) => false,
+            SleepAt::Tracks(n) => {
+                *n = n.saturating_sub(1);
+                *n == 0
+            }
+        }
+    }
+
+    /// The volume to play at when `remaining` is left, if we should be fading out. `volume` is the
+    /// current volume, which is remembered as the volume to fade from the first time this
+    /// returns something.
+    pub fn faded_volume(&mut self, remaining: Option<Duration>, volume: f64) -> Option<f64> {
+        let remaining = remaining.filter(|r| self.fade_out && *r < FADE_DURATION)?;
+        let from = *self.volume.get_or_insert(volume);
+        Some(from * remaining.as_secs_f64() / FADE_DURATION.as_secs_f64())
+    }
+
+    /// The volume from before fading started, if it has. This should be restored once the timer's
+    /// done or cancelled.
+    pub fn original_volume(&self) -> Option<f64> {
+        self.volume
+    }
+
+    /// Stops fading out if we've started to, for when the user changes the volume themselves. The
+    /// timer still goes off, but their volume is left alone.
+    pub fn cancel_fade(&mut self) {
+        if self.volume.take().is_some() {
+            self.fade_out = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timed() {
+        let start = Instant::now();
+        let mut timer = SleepTimer::new(SleepAt::Time(start + Duration::from_secs(60)), true);
+        assert_eq!(timer.remaining(start, None), Some(Duration::from_secs(60)));
+        assert!(!timer.expired(start));
+        assert!(!timer.track_finished());
+        assert!(timer.expired(start + Duration::from_secs(61)));
+
+        assert_eq!(timer.faded_volume(Some(Duration::from_secs(40)), 0.8), None);
+        assert_eq!(timer.faded_volume(Some(Duration::from_secs(15)), 0.8), Some(0.4));
+        // we keep fading from the volume we started at
+        assert_eq!(timer.faded_volume(Some(Duration::ZERO), 0.4), Some(0.0));
+        assert_eq!(timer.original_volume(), Some(0.8));
+
+        timer.cancel_fade();
+        assert_eq!(timer.original_volume(), None);
+        assert_eq!(timer.faded_volume(Some(Duration::ZERO), 0.5), None);
+    }
+
+    #[test]
+    fn cancelling_before_fading() {
+        let mut timer = SleepTimer::after(Duration::from_secs(60), true);
+        timer.cancel_fade();
+        let volume = timer.faded_volume(Some(Duration::from_secs(15)), 0.8);
+        assert_eq!(volume, Some(0.4), "should still fade, since it hadn't started yet");
+    }
+
+    #[test]
+    fn counting_tracks() {
+        let now = Instant::now();
+        let mut timer = SleepTimer::new(SleepAt::Tracks(2), false);
+        let left = Some(Duration::from_secs(10));
+        assert_eq!(timer.remaining(now, left), None);
+        assert!(!timer.track_finished());
+        assert_eq!(timer.remaining(now, left), left);
+        assert_eq!(timer.faded_volume(left, 1.0), None, "fading is off");
+        assert!(!timer.expired(now + Duration::from_secs(3600)));
+        assert!(timer.track_finished());
+    }
+}
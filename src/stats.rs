@@ -0,0 +1,118 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{history::PlayEnd, library::Track, persist};
+
+/// How often a track has been played and skipped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TrackStats {
+    pub plays: u32,
+    pub skips: u32,
+    /// When the track was last played far enough to count as a play.
+    pub last_played: Option<SystemTime>,
+}
+
+/// Per-track statistics, keyed by path since IDs change when the library is rescanned. This is
+/// saved next to the library cache.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Stats {
+    tracks: HashMap<PathBuf, TrackStats>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// Whether anything's been recorded since the last save.
+    #[serde(skip)]
+    changed: bool,
+}
+
+impl Stats {
+    /// Loads statistics from the given path. A missing file means nothing has been played.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut stats: Self = persist::load_json(path, "stats")?;
+        stats.path = Some(path.to_owned());
+        Ok(stats)
+    }
+
+    /// Writes the statistics back to where they were loaded from. Does nothing if they weren't
+    /// loaded from a file, or if nothing's changed since the last save.
+    pub fn save(&mut self) -> Result<()> {
+        let Some(path) = self.path.as_ref().filter(|_| self.changed) else {
+            return Ok(());
+        };
+        persist::save_json(path, self, "stats")?;
+        self.changed = false;
+        Ok(())
+    }
+
+    pub fn get(&self, path: &Path) -> TrackStats {
+        self.tracks.get(path).copied().unwrap_or_default()
+    }
+
+    /// Records that `track` was played up to `listened`, starting at `started`. It counts as a
+    /// play if it was completed or at least `play_fraction` of it was heard, and as a skip
+    /// otherwise. Tracks whose length we don't know only count as played if they were completed.
+    pub fn record(
+        &mut self,
+        track: &Track,
+        listened: Duration,
+        end: PlayEnd,
+        started: SystemTime,
+        play_fraction: f64,
+    ) {
+        self.changed = true;
+        let stats = self.tracks.entry(track.path.clone()).or_default();
+        let heard_enough =
+            track.length.0 > 0.0 && listened.as_secs_f64() >= track.length.0 * play_fraction;
+        if end == PlayEnd::Completed || heard_enough {
+            stats.plays += 1;
+            stats.last_played = Some(started);
+        } else {
+            stats.skips += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_plays_and_skips() {
+        let mut stats = Stats::default();
+        let track = Track::test_track(0);
+        let started = SystemTime::UNIX_EPOCH + Duration::from_secs(60);
+        stats.record(&track, Duration::from_secs(150), PlayEnd::Skipped, started, 0.5);
+        stats.record(&track, Duration::from_secs(10), PlayEnd::Skipped, SystemTime::now(), 0.5);
+        stats.record(&track, Duration::from_secs(0), PlayEnd::Completed, started, 0.5);
+        assert_eq!(
+            stats.get(&track.path),
+            TrackStats {
+                plays: 2,
+                skips: 1,
+                last_played: Some(started),
+            }
+        );
+        assert_eq!(stats.get(Path::new("/nonexistent.mp3")), TrackStats::default());
+    }
+
+    #[test]
+    fn unknown_length() {
+        let mut stats = Stats::default();
+        let track = Track {
+            length: 0.0.into(),
+            ..Track::test_track(0)
+        };
+        stats.record(&track, Duration::ZERO, PlayEnd::Skipped, SystemTime::now(), 0.5);
+        stats.record(&track, Duration::from_secs(5), PlayEnd::Completed, SystemTime::now(), 0.5);
+        assert_eq!(stats.get(&track.path).plays, 1);
+        assert_eq!(stats.get(&track.path).skips, 1);
+    }
+}
@@ -11,7 +11,8 @@ use ratatui::{
 
 use crate::{
     library::{AlbumName, ArtistName, Library},
-    ui::Ui,
+    ratings::Ratings,
+    ui::{track_list::format_stars, Ui},
 };
 
 use super::ActiveState;
@@ -160,16 +161,29 @@ impl ArtistAlbumList {
 
 /// Drawing code
 impl ArtistAlbumList {
-    /// Text to use when drawing the given row.
-    fn text(&self, row: RowIndex) -> String {
+    /// Text to use when drawing the given row. Rated albums have their stars after the name.
+    fn text(&self, row: RowIndex, ratings: &Ratings) -> String {
         let artist = &self.artists[row.artist];
         match row.album {
-            Some(album) => format!("    {}", artist.albums[album]),
+            Some(album) => {
+                let album = &artist.albums[album];
+                match ratings.album_stars(&artist.artist, album) {
+                    Some(stars) => format!("    {album} {}", format_stars(stars)),
+                    None => format!("    {album}"),
+                }
+            }
             None => format!("{}", artist.artist),
         }
     }
 
-    pub fn draw(&self, state: ActiveState, ui: &Ui, frame: &mut Frame, area: Rect) -> Result<()> {
+    pub fn draw(
+        &self,
+        state: ActiveState,
+        ui: &Ui,
+        frame: &mut Frame,
+        area: Rect,
+        ratings: &Ratings,
+    ) -> Result<()> {
         let block = Block::default()
             .title("Artist / Album")
             .borders(Borders::ALL)
@@ -200,9 +214,10 @@ impl ArtistAlbumList {
                 Style::default()
             };
             let y = index - self.offset.get();
-            let mut text = self.text(*row);
-            // need to manually truncate; setting the wrap to `trim: true` will also trim leading whitespace
-            text.truncate(inner.width as usize);
+            let text = self.text(*row, ratings);
+            // need to manually truncate; setting the wrap to `trim: true` will also trim leading
+            // whitespace. Stars are multibyte, so count characters rather than bytes.
+            let text: String = text.chars().take(inner.width as usize).collect();
             frame.render_widget(
                 Paragraph::new(text).style(style),
                 Rect::new(inner.left(), inner.top() + y as u16, inner.width, 1),
@@ -0,0 +1,125 @@
+use std::{cell::RefCell, time::Duration};
+
+use eyre::Result;
+use itertools::Itertools;
+use ratatui::{
+    layout::Rect,
+    style::{Color, Style},
+    widgets::{Block, Borders, List, ListItem, ListState},
+    Frame,
+};
+
+use crate::{bookmarks::Bookmark, ui::Ui};
+
+use super::ActiveState;
+
+/// Shows the user's bookmarks and lets them jump to, rename, or delete them.
+#[derive(Debug, Default)]
+pub struct BookmarkList {
+    state: RefCell<ListState>,
+    /// The new name being typed for the selected bookmark, if the user is renaming it.
+    renaming: Option<String>,
+}
+
+impl BookmarkList {
+    /// Creates a list with the cursor on the bookmark at `selected`, or the first one if `None`.
+    pub fn new(len: usize, selected: Option<usize>) -> Self {
+        let selected = selected.or((len > 0).then_some(0));
+        Self {
+            state: RefCell::new(ListState::default().with_selected(selected)),
+            renaming: None,
+        }
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.state.borrow().selected()
+    }
+
+    pub fn move_cursor(&mut self, len: usize, delta: isize) {
+        if let Some(s) = self.state.get_mut().selected_mut().as_mut() {
+            *s = s.saturating_add_signed(delta).min(len.saturating_sub(1));
+        }
+    }
+
+    /// Call this after removing the selected bookmark, so the cursor stays in bounds.
+    pub fn on_remove(&mut self, len: usize) {
+        let state = self.state.get_mut();
+        match state.selected() {
+            Some(_) if len == 0 => state.select(None),
+            Some(s) => state.select(Some(s.min(len - 1))),
+            None => (),
+        }
+    }
+
+    pub fn renaming(&self) -> bool {
+        self.renaming.is_some()
+    }
+
+    /// Starts renaming the selected bookmark, starting from its current name.
+    pub fn start_rename(&mut self, current: &str) {
+        self.renaming = Some(current.to_owned());
+    }
+
+    /// Edits the new name. `None` deletes the last character.
+    pub fn rename_input(&mut self, c: Option<char>) {
+        if let Some(name) = self.renaming.as_mut() {
+            match c {
+                Some(c) => name.push(c),
+                None => {
+                    name.pop();
+                }
+            }
+        }
+    }
+
+    /// Stops renaming, returning the new name.
+    pub fn finish_rename(&mut self) -> Option<String> {
+        self.renaming.take()
+    }
+
+    pub fn draw(
+        &self,
+        ui: &Ui,
+        frame: &mut Frame,
+        area: Rect,
+        bookmarks: &[Bookmark],
+    ) -> Result<()> {
+        let title = if self.renaming() {
+            "Bookmarks (Enter to rename, Esc to cancel)"
+        } else {
+            "Bookmarks (r to rename, d to delete)"
+        };
+        let block = Block::default()
+            .title(title)
+            .borders(Borders::ALL)
+            .border_style(ui.border(ActiveState::Focused));
+        let selected = self.selected();
+        let items = bookmarks
+            .iter()
+            .enumerate()
+            .map(|(i, bookmark)| {
+                let name = match &self.renaming {
+                    Some(name) if Some(i) == selected => format!("{name}_"),
+                    _ => bookmark.name.clone(),
+                };
+                ListItem::new(format!("{} {name}", format_position(bookmark.position)))
+            })
+            .collect_vec();
+        let list = List::new(items)
+            .highlight_style(Style::default().fg(Color::Cyan).bg(Color::Rgb(30, 30, 30)))
+            .block(block);
+        frame.render_stateful_widget(list, area, &mut self.state.borrow_mut());
+        Ok(())
+    }
+}
+
+/// Formats a position as `h:mm:ss`, or `mm:ss` if it's less than an hour.
+pub fn format_position(position: Duration) -> String {
+    let secs = position.as_secs();
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}:{mins:0>2}:{secs:0>2}")
+    } else {
+        format!("{mins:0>2}:{secs:0>2}")
+    }
+}
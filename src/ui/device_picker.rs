@@ -0,0 +1,81 @@
+use std::cell::RefCell;
+
+use eyre::Result;
+use itertools::Itertools;
+use ratatui::{
+    layout::Rect,
+    style::{Color, Style},
+    widgets::{Block, Borders, List, ListItem, ListState},
+    Frame,
+};
+
+use crate::{
+    audio::{DeviceId, OutputDevice},
+    ui::Ui,
+};
+
+use super::ActiveState;
+
+/// Lets the user pick which device to play audio through.
+#[derive(Debug, Default)]
+pub struct DevicePicker {
+    devices: Vec<OutputDevice>,
+    /// The device currently in use, if the user picked one.
+    current: Option<DeviceId>,
+    state: RefCell<ListState>,
+}
+
+impl DevicePicker {
+    /// Creates a picker with the cursor on `current`, or on the default device if that's `None`.
+    pub fn new(devices: Vec<OutputDevice>, current: Option<&DeviceId>) -> Self {
+        let selected = devices
+            .iter()
+            .position(|device| match current {
+                Some(id) => id.matches(device),
+                None => device.id().is_none(),
+            })
+            .or((!devices.is_empty()).then_some(0));
+        Self {
+            devices,
+            current: current.cloned(),
+            state: RefCell::new(ListState::default().with_selected(selected)),
+        }
+    }
+
+    pub fn selected(&self) -> Option<OutputDevice> {
+        self.state.borrow().selected().map(|i| self.devices[i].clone())
+    }
+
+    pub fn move_cursor(&mut self, delta: isize) {
+        if let Some(s) = self.state.get_mut().selected_mut().as_mut() {
+            *s = s.saturating_add_signed(delta).min(self.devices.len() - 1);
+        }
+    }
+
+    fn as_list_item(&self, ui: &Ui, device: &OutputDevice) -> ListItem<'static> {
+        let mut text = format!("{} ({})", device.name, device.host);
+        if device.default {
+            text.push_str(" [default]");
+        }
+        let item = ListItem::new(text);
+        if self.current.as_ref().map_or(false, |id| id.matches(device)) {
+            item.style(ui.theme.now_playing_track)
+        } else {
+            item
+        }
+    }
+
+    pub fn draw(&self, ui: &Ui, frame: &mut Frame, area: Rect) -> Result<()> {
+        let block = Block::default()
+            .title("Output device")
+            .borders(Borders::ALL)
+            .border_style(ui.border(ActiveState::Focused));
+        let list = List::new(
+            self.devices.iter().map(|device| self.as_list_item(ui, device)).collect_vec(),
+        )
+        .highlight_style(Style::default().fg(Color::Cyan).bg(Color::Rgb(30, 30, 30)))
+        .block(block);
+        frame.render_stateful_widget(list, area, &mut self.state.borrow_mut());
+        Ok(())
+    }
+}
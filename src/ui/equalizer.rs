@@ -0,0 +1,219 @@
+use eyre::Result;
+use itertools::Itertools;
+use ratatui::{
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Style},
+    symbols::Marker,
+    text::Line,
+    widgets::{Axis, Block, Borders, Chart, Dataset, GraphType, Paragraph},
+    Frame,
+};
+
+use crate::{
+    audio::{Band, EqPreset, FilterKind},
+    ui::Ui,
+};
+
+use super::{spectrogram::VisualizerOptions, ActiveState};
+
+/// The curve doesn't depend much on the sample rate below ~15kHz, so we don't bother using the
+/// output's real one.
+const DISPLAY_SAMPLE_RATE: u32 = 48000;
+/// The curve always shows at least this many decibels of boost and cut.
+const MIN_DISPLAY_GAIN: f64 = 12.0;
+const HELP: &str =
+    "←→ band  ↑↓ gain  [] frequency  {} Q  t type  a add  d delete  Tab preset  w save";
+
+/// A change the user made in the EQ editor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EqEdit {
+    /// Moves the band cursor.
+    SelectBand(isize),
+    /// Adds this many decibels to the selected band's gain.
+    Gain(f32),
+    /// Multiplies the selected band's frequency by this.
+    Frequency(f32),
+    /// Multiplies the selected band's Q by this.
+    Q(f32),
+    /// Changes the selected band's filter type.
+    CycleKind,
+    /// Adds a new band after the selected one.
+    AddBand,
+    RemoveBand,
+    /// Switches to the next preset, or turns the EQ off after the last one.
+    NextPreset,
+}
+
+/// Shows an EQ preset's frequency response and lets the user tweak its bands.
+#[derive(Debug, Default)]
+pub struct EqEditor {
+    /// Index of the selected band.
+    band: usize,
+}
+
+impl EqEditor {
+    /// Applies `edit` to `preset`. [`EqEdit::NextPreset`] is ignored, since that's not about any
+    /// one preset.
+    pub fn edit(&mut self, preset: &mut EqPreset, edit: EqEdit) {
+        // The preset might have changed out from under us, e.g. on a track change.
+        self.band = self.band.min(preset.bands.len().saturating_sub(1));
+        if let EqEdit::AddBand = edit {
+            let index = if preset.bands.is_empty() {
+                0
+            } else {
+                self.band + 1
+            };
+            preset.bands.insert(
+                index,
+                Band {
+                    kind: FilterKind::Peaking,
+                    frequency: 1000.0,
+                    gain: 0.0,
+                    q: Band::default_q(),
+                },
+            );
+            self.band = index;
+            return;
+        }
+        let Some(band) = preset.bands.get_mut(self.band) else {
+            return;
+        };
+        match edit {
+            EqEdit::SelectBand(delta) => {
+                self.band = self.band.saturating_add_signed(delta).min(preset.bands.len() - 1);
+            }
+            EqEdit::Gain(delta) => band.gain = (band.gain + delta).clamp(-24.0, 24.0),
+            EqEdit::Frequency(factor) => {
+                band.frequency = (band.frequency * factor).clamp(20.0, 20000.0)
+            }
+            EqEdit::Q(factor) => band.q = (band.q * factor).clamp(0.1, 10.0),
+            EqEdit::CycleKind => band.kind = band.kind.next(),
+            EqEdit::RemoveBand => {
+                preset.bands.remove(self.band);
+                self.band = self.band.min(preset.bands.len().saturating_sub(1));
+            }
+            EqEdit::AddBand | EqEdit::NextPreset => (),
+        }
+    }
+
+    /// Draws the editor. `name` is the name of the preset being edited, and `auto` is true if it
+    /// was picked automatically for the current track.
+    pub fn draw(
+        &self,
+        ui: &Ui,
+        frame: &mut Frame,
+        area: Rect,
+        name: Option<&str>,
+        preset: Option<&EqPreset>,
+        auto: bool,
+    ) -> Result<()> {
+        let title = match name {
+            Some(name) if auto => format!("Equalizer: {name} (auto)"),
+            Some(name) => format!("Equalizer: {name}"),
+            None => "Equalizer: off".to_owned(),
+        };
+        let block = Block::default()
+            .title(title)
+            .borders(Borders::ALL)
+            .border_style(ui.border(ActiveState::Focused));
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        let bands = preset.map_or(&[][..], |p| p.bands.as_slice());
+        let root = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(4),
+                Constraint::Length(bands.len().max(1) as u16),
+                Constraint::Length(1),
+            ])
+            .split(inner);
+
+        if let Some(preset) = preset {
+            self.draw_curve(frame, root[0], preset);
+        }
+        let lines = if bands.is_empty() {
+            vec![Line::from("No bands. Press 'a' to add one.")]
+        } else {
+            bands
+                .iter()
+                .enumerate()
+                .map(|(i, band)| {
+                    let text = format!(
+                        "{:<10} {:>7.0} Hz {:>+6.1} dB  Q {:.2}",
+                        format!("{:?}", band.kind),
+                        band.frequency,
+                        band.gain,
+                        band.q
+                    );
+                    if i == self.band {
+                        Line::styled(
+                            text,
+                            Style::default().fg(Color::Cyan).bg(Color::Rgb(30, 30, 30)),
+                        )
+                    } else {
+                        Line::from(text)
+                    }
+                })
+                .collect_vec()
+        };
+        frame.render_widget(Paragraph::new(lines), root[1]);
+        frame.render_widget(Paragraph::new(HELP).style(ui.theme.section_header), root[2]);
+        Ok(())
+    }
+
+    fn draw_curve(&self, frame: &mut Frame, area: Rect, preset: &EqPreset) {
+        let options = VisualizerOptions {
+            min_freq: 20.0,
+            max_freq: 20000.0,
+            ..Default::default()
+        };
+        // Braille markers give us two points per cell.
+        let n = (area.width as usize * 2).max(2);
+        let curve = options
+            .frequencies(n)
+            .enumerate()
+            .map(|(i, freq)| (i as f64, preset.response(freq, DISPLAY_SAMPLE_RATE) as f64))
+            .collect_vec();
+        // Where each band is centered, so the user can see which one they're moving.
+        let scale = (n - 1) as f32 / (options.max_freq / options.min_freq).ln();
+        let markers = preset
+            .bands
+            .iter()
+            .map(|band| {
+                let x = ((band.frequency / options.min_freq).ln() * scale) as f64;
+                (x, preset.response(band.frequency, DISPLAY_SAMPLE_RATE) as f64)
+            })
+            .collect_vec();
+        let limit = curve
+            .iter()
+            .chain(&markers)
+            .map(|(_, gain)| gain.abs().ceil())
+            .fold(MIN_DISPLAY_GAIN, f64::max);
+
+        let datasets = vec![
+            Dataset::default()
+                .marker(Marker::Braille)
+                .graph_type(GraphType::Line)
+                .style(Style::default().fg(Color::Cyan))
+                .data(&curve),
+            Dataset::default()
+                .marker(Marker::Dot)
+                .graph_type(GraphType::Scatter)
+                .style(Style::default().fg(Color::Yellow))
+                .data(&markers),
+        ];
+        let chart = Chart::new(datasets)
+            .x_axis(
+                Axis::default()
+                    .bounds([0.0, (n - 1) as f64])
+                    .labels(["20", "200", "2k", "20k"].into_iter().map(Into::into).collect()),
+            )
+            .y_axis(Axis::default().bounds([-limit, limit]).labels(vec![
+                format!("{:+}", -limit).into(),
+                "0".into(),
+                format!("{:+}", limit).into(),
+            ]));
+        frame.render_widget(chart, area);
+    }
+}
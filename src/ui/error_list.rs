@@ -0,0 +1,71 @@
+use std::{cell::RefCell, sync::Arc, time::SystemTime};
+
+use eyre::Result;
+use itertools::Itertools;
+use ratatui::{
+    layout::Rect,
+    style::{Color, Style},
+    widgets::{Block, Borders, List, ListItem, ListState},
+    Frame,
+};
+
+use crate::{
+    errors::{ErrorLog, LoggedError},
+    library::Track,
+    ui::{history_list::format_age, Ui},
+};
+
+use super::ActiveState;
+
+/// Shows recent errors, newest first. This is a snapshot of the log from when it was opened.
+#[derive(Debug, Default)]
+pub struct ErrorList {
+    errors: Vec<LoggedError>,
+    state: RefCell<ListState>,
+}
+
+impl ErrorList {
+    pub fn new(log: &ErrorLog) -> Self {
+        let errors = log.recent().cloned().collect_vec();
+        let selected = (!errors.is_empty()).then_some(0);
+        Self {
+            errors,
+            state: RefCell::new(ListState::default().with_selected(selected)),
+        }
+    }
+
+    /// The track that the selected error was about, if any.
+    pub fn selected(&self) -> Option<Arc<Track>> {
+        let selected = self.state.borrow().selected()?;
+        self.errors[selected].track.clone()
+    }
+
+    pub fn move_cursor(&mut self, delta: isize) {
+        if let Some(s) = self.state.get_mut().selected_mut().as_mut() {
+            *s = s.saturating_add_signed(delta).min(self.errors.len().saturating_sub(1));
+        }
+    }
+
+    fn as_list_item(error: &LoggedError, now: SystemTime) -> ListItem<'static> {
+        let age = now.duration_since(error.time).unwrap_or_default();
+        let mut text = format!("{:>8} {}", format_age(age), error.summary());
+        if let Some(track) = &error.track {
+            text.push_str(&format!("\n         {}", track.path.display()));
+        }
+        ListItem::new(text)
+    }
+
+    pub fn draw(&self, ui: &Ui, frame: &mut Frame, area: Rect) -> Result<()> {
+        let block = Block::default()
+            .title("Recent errors")
+            .borders(Borders::ALL)
+            .border_style(ui.border(ActiveState::Focused));
+        let now = SystemTime::now();
+        let items = self.errors.iter().map(|error| Self::as_list_item(error, now)).collect_vec();
+        let list = List::new(items)
+            .highlight_style(Style::default().fg(Color::Cyan).bg(Color::Rgb(30, 30, 30)))
+            .block(block);
+        frame.render_stateful_widget(list, area, &mut self.state.borrow_mut());
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use eyre::Result;
+use itertools::Itertools;
+use ratatui::{
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    widgets::{Block, Borders, List, ListItem, ListState},
+    Frame,
+};
+
+use crate::{
+    history::{History, Play, PlayEnd},
+    library::{Library, Track},
+    ui::{bookmark_list::format_position, Ui},
+};
+
+use super::ActiveState;
+
+#[derive(Debug)]
+struct HistoryEntry {
+    play: Play,
+    /// `None` if the track isn't in the library anymore.
+    track: Option<Arc<Track>>,
+}
+
+/// Shows recently played tracks, newest first. This is a snapshot of the history from when it was
+/// opened.
+#[derive(Debug, Default)]
+pub struct HistoryList {
+    entries: Vec<HistoryEntry>,
+    state: RefCell<ListState>,
+}
+
+impl HistoryList {
+    pub fn new(history: &History, library: &Library) -> Self {
+        let tracks: HashMap<PathBuf, Arc<Track>> =
+            library.tracks().map(|track| (track.path.clone(), track)).collect();
+        let entries = history
+            .recent()
+            .map(|play| HistoryEntry {
+                track: tracks.get(&play.path).cloned(),
+                play: play.clone(),
+            })
+            .collect_vec();
+        let selected = (!entries.is_empty()).then_some(0);
+        Self {
+            entries,
+            state: RefCell::new(ListState::default().with_selected(selected)),
+        }
+    }
+
+    /// The selected track, if it's still in the library.
+    pub fn selected(&self) -> Option<Arc<Track>> {
+        let selected = self.state.borrow().selected()?;
+        self.entries[selected].track.clone()
+    }
+
+    pub fn move_cursor(&mut self, delta: isize) {
+        if let Some(s) = self.state.get_mut().selected_mut().as_mut() {
+            *s = s.saturating_add_signed(delta).min(self.entries.len().saturating_sub(1));
+        }
+    }
+
+    fn as_list_item(entry: &HistoryEntry, now: SystemTime) -> ListItem<'static> {
+        let age = now.duration_since(entry.play.started).unwrap_or_default();
+        let name = match &entry.track {
+            Some(track) => {
+                let title = track.title.as_deref().unwrap_or("<unknown>");
+                format!("{} - {title}", track.artist)
+            }
+            None => entry.play.path.display().to_string(),
+        };
+        let mut text =
+            format!("{:>8} {name} ({})", format_age(age), format_position(entry.play.listened));
+        if entry.play.end == PlayEnd::Skipped {
+            text.push_str(" [skipped]");
+        }
+        let item = ListItem::new(text);
+        if entry.track.is_none() {
+            item.style(Style::default().add_modifier(Modifier::DIM))
+        } else {
+            item
+        }
+    }
+
+    pub fn draw(&self, ui: &Ui, frame: &mut Frame, area: Rect) -> Result<()> {
+        let block = Block::default()
+            .title("History")
+            .borders(Borders::ALL)
+            .border_style(ui.border(ActiveState::Focused));
+        let now = SystemTime::now();
+        let items = self.entries.iter().map(|entry| Self::as_list_item(entry, now)).collect_vec();
+        let list = List::new(items)
+            .highlight_style(Style::default().fg(Color::Cyan).bg(Color::Rgb(30, 30, 30)))
+            .block(block);
+        frame.render_stateful_widget(list, area, &mut self.state.borrow_mut());
+        Ok(())
+    }
+}
+
+/// Formats how long ago something happened, roughly.
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..=59 => "just now".to_owned(),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
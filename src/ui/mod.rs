@@ -1,6 +1,12 @@
 pub(crate) mod album_art;
 pub(crate) mod artist_album_list;
+pub(crate) mod bookmark_list;
+pub(crate) mod device_picker;
+pub(crate) mod equalizer;
+pub(crate) mod error_list;
+pub(crate) mod history_list;
 pub(crate) mod now_playing;
+pub(crate) mod queue_list;
 pub(crate) mod search;
 pub(crate) mod spectrogram;
 pub(crate) mod track_list;
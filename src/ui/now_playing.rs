@@ -1,14 +1,53 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use ratatui::{style::Stylize, widgets::Paragraph};
+use ratatui::{
+    style::{Color, Style, Stylize},
+    text::{Line, Span, Text},
+    widgets::Paragraph,
+};
 
-use crate::library::Track;
+use crate::{
+    audio::{AbLoop, ShuffleMode, StopAfter},
+    library::Track,
+    sleep::{SleepAt, SleepTimer},
+    ui::bookmark_list::format_position,
+};
 
 /// Widget that displays the current song and timestamp within that song.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct NowPlaying {
+    /// Position in the track. This is unaffected by the playback speed.
     pub timestamp: Option<Duration>,
     pub track: Option<Arc<Track>>,
+    pub speed: f64,
+    /// `None` if we aren't shuffling.
+    pub shuffle: Option<ShuffleMode>,
+    pub stop_after: Option<StopAfter>,
+    /// How many more times the current track will be played.
+    pub repeats_left: u32,
+    pub sleep_timer: Option<SleepTimer>,
+    /// Whether radio mode is on.
+    pub radio: bool,
+    pub ab_loop: AbLoop,
+}
+
+impl Default for NowPlaying {
+    fn default() -> Self {
+        Self {
+            timestamp: None,
+            track: None,
+            speed: 1.0,
+            shuffle: None,
+            stop_after: None,
+            repeats_left: 0,
+            sleep_timer: None,
+            radio: false,
+            ab_loop: AbLoop::default(),
+        }
+    }
 }
 
 /// Drawing code
@@ -31,16 +70,84 @@ impl NowPlaying {
 
         let total_mins = (track.length / 60.0).floor() as u64;
         let total_secs = (track.length % 60.0).ceil() as u64;
+        let speed = if self.speed == 1.0 {
+            String::new()
+        } else {
+            format!(" ({}x)", self.speed)
+        };
+        let mut modes = vec![];
+        if let Some(mode) = self.shuffle {
+            modes.push(format!("shuffle: {}", mode.name()));
+        }
+        if let Some(stop_after) = self.stop_after {
+            modes.push(format!("stop after {}", stop_after.name()));
+        }
+        if self.repeats_left > 0 {
+            modes.push(format!("repeat {}x", self.repeats_left));
+        }
+        if self.radio {
+            modes.push("radio".to_owned());
+        }
+        if let Some(timer) = self.sleep_timer {
+            let track_left = Duration::from_secs_f64(track.length.0).saturating_sub(*timestamp);
+            modes.push(match (timer.at, timer.remaining(Instant::now(), Some(track_left))) {
+                (SleepAt::Tracks(n), None) => format!("sleep after {n} tracks"),
+                (_, remaining) => {
+                    format!("sleep in {}", format_position(remaining.unwrap_or_default()))
+                }
+            });
+        }
+        let modes = modes.into_iter().map(|mode| format!(" [{mode}]")).collect::<String>();
 
-        frame.render_widget(
-            Paragraph::new(format!(
-                "{artist}\n{album}\n{title}\n\
-                    {mins:0>2}:{secs:0>2} / {total_mins:0>2}:{total_secs:0>2}"
-            ))
-            .bold(),
-            area,
-        );
+        let length = Duration::from_secs_f64(track.length.0);
+        let text = Text::from(vec![
+            Line::from(artist.to_string()),
+            Line::from(album.to_string()),
+            Line::from(title),
+            Line::from(format!(
+                "{mins:0>2}:{secs:0>2} / {total_mins:0>2}:{total_secs:0>2}{speed}{modes}"
+            )),
+            progress_bar(area.width.into(), length, *timestamp, self.ab_loop),
+        ]);
+        frame.render_widget(Paragraph::new(text).bold(), area);
 
         Ok(())
     }
 }
+
+/// A bar showing how far into the track we are, with the A-B loop highlighted.
+fn progress_bar(
+    width: usize,
+    length: Duration,
+    position: Duration,
+    ab_loop: AbLoop,
+) -> Line<'static> {
+    if width == 0 {
+        return Line::default();
+    }
+    let cell = |at: Duration| {
+        let fraction = at.as_secs_f64() / length.as_secs_f64().max(f64::EPSILON);
+        ((fraction * width as f64) as usize).min(width - 1)
+    };
+    let current = cell(position);
+    // If only one point is set, we just mark that.
+    let marked = match (ab_loop.start, ab_loop.end) {
+        (Some(start), Some(end)) => Some((cell(start.min(end)), cell(start.max(end)))),
+        (Some(point), None) | (None, Some(point)) => Some((cell(point), cell(point))),
+        (None, None) => None,
+    };
+    let spans = (0..width).map(|i| {
+        let symbol = match i.cmp(&current) {
+            std::cmp::Ordering::Less => "━",
+            std::cmp::Ordering::Equal => "●",
+            std::cmp::Ordering::Greater => "─",
+        };
+        match marked {
+            Some((start, end)) if (start..=end).contains(&i) => {
+                Span::styled(symbol, Style::default().fg(Color::Yellow))
+            }
+            _ => Span::raw(symbol),
+        }
+    });
+    Line::from(spans.collect::<Vec<_>>())
+}
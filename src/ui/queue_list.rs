@@ -0,0 +1,84 @@
+use std::cell::RefCell;
+
+use eyre::Result;
+use itertools::Itertools;
+use ratatui::{
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    widgets::{Block, Borders, List, ListItem, ListState},
+    Frame,
+};
+
+use crate::{audio::PlayQueue, ui::Ui};
+
+use super::ActiveState;
+
+/// Shows the play queue, with tracks that have already been played dimmed, and lets the user edit
+/// it.
+#[derive(Debug, Default)]
+pub struct QueueList {
+    state: RefCell<ListState>,
+}
+
+impl QueueList {
+    /// Creates a list with the cursor on the track at `selected`, or the first one if `None`.
+    pub fn new(len: usize, selected: Option<usize>) -> Self {
+        let selected = selected.or((len > 0).then_some(0));
+        Self {
+            state: RefCell::new(ListState::default().with_selected(selected)),
+        }
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.state.borrow().selected()
+    }
+
+    pub fn select(&mut self, index: usize) {
+        self.state.get_mut().select(Some(index));
+    }
+
+    pub fn move_cursor(&mut self, len: usize, delta: isize) {
+        if let Some(s) = self.state.get_mut().selected_mut().as_mut() {
+            *s = s.saturating_add_signed(delta).min(len.saturating_sub(1));
+        }
+    }
+
+    /// Call this after the queue changes length, so the cursor stays in bounds.
+    pub fn on_resize(&mut self, len: usize) {
+        let state = self.state.get_mut();
+        match state.selected() {
+            _ if len == 0 => state.select(None),
+            Some(s) => state.select(Some(s.min(len - 1))),
+            None => state.select(Some(0)),
+        }
+    }
+
+    pub fn draw(&self, ui: &Ui, frame: &mut Frame, area: Rect, queue: &PlayQueue) -> Result<()> {
+        let block = Block::default()
+            .title("Queue (d remove, J/K move, n clear upcoming, C clear, D dedupe)")
+            .borders(Borders::ALL)
+            .border_style(ui.border(ActiveState::Focused));
+        let current = queue.current();
+        let items = queue
+            .tracks()
+            .iter()
+            .enumerate()
+            .map(|(i, track)| {
+                let title = track.title.as_deref().unwrap_or("<unknown>");
+                let item = ListItem::new(format!("{} - {title}", track.artist));
+                match current {
+                    Some(current) if i == current => item.style(ui.theme.now_playing_track),
+                    Some(current) if i < current => {
+                        item.style(Style::default().add_modifier(Modifier::DIM))
+                    }
+                    _ => item,
+                }
+            })
+            .collect_vec();
+        let list = List::new(items)
+            .highlight_style(Style::default().fg(Color::Cyan).bg(Color::Rgb(30, 30, 30)))
+            .block(block);
+        frame.render_stateful_widget(list, area, &mut self.state.borrow_mut());
+        Ok(())
+    }
+}
@@ -1,4 +1,10 @@
-use std::{cell::RefCell, cmp::Reverse, collections::HashSet, ops::DerefMut, sync::Arc};
+use std::{
+    cell::RefCell,
+    cmp::{Ordering, Reverse},
+    collections::HashSet,
+    ops::DerefMut,
+    sync::Arc,
+};
 
 use eyre::Result;
 use itertools::Itertools;
@@ -16,7 +22,10 @@ use ratatui::{
 };
 use std::sync::Mutex;
 
-use crate::library::{AlbumName, ArtistName, Library, Track};
+use crate::{
+    library::{AlbumName, ArtistName, Library, Track},
+    stats::{Stats, TrackStats},
+};
 
 use super::ActiveState;
 
@@ -49,6 +58,15 @@ impl SearchItem {
         }
     }
 
+    /// The tracks this refers to: the track itself, or everything on the album or by the artist.
+    pub fn tracks(&self, library: &Library) -> Vec<Arc<Track>> {
+        match self {
+            SearchItem::Artist(artist) => library.artist_tracks(artist),
+            SearchItem::Album(album, artist) => library.album_tracks(artist, album),
+            SearchItem::Track(track) => vec![Arc::clone(track)],
+        }
+    }
+
     pub fn track_title(&self) -> Option<&str> {
         match self {
             SearchItem::Track(track) => track.title.as_deref(),
@@ -96,6 +114,42 @@ impl SearchItem {
     }
 }
 
+/// A condition on a track's statistics, written like `plays>10` or `skips=0` in a search query.
+/// If a query has any of these, only tracks are shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StatFilter {
+    skips: bool,
+    ordering: Ordering,
+    value: u32,
+}
+
+impl StatFilter {
+    fn parse(token: &str) -> Option<Self> {
+        let (skips, rest) = if let Some(rest) = token.strip_prefix("plays") {
+            (false, rest)
+        } else {
+            (true, token.strip_prefix("skips")?)
+        };
+        let ordering = match rest.chars().next()? {
+            '<' => Ordering::Less,
+            '=' => Ordering::Equal,
+            '>' => Ordering::Greater,
+            _ => return None,
+        };
+        let value = rest[1..].parse().ok()?;
+        Some(Self {
+            skips,
+            ordering,
+            value,
+        })
+    }
+
+    fn matches(&self, stats: &TrackStats) -> bool {
+        let actual = if self.skips { stats.skips } else { stats.plays };
+        actual.cmp(&self.value) == self.ordering
+    }
+}
+
 /// A slice of text used to display a search result.
 #[derive(Debug)]
 struct SearchTextSegment {
@@ -126,11 +180,19 @@ impl Search {
         self.state.borrow().selected().map(|i| self.results[i].item.clone())
     }
 
-    pub fn run_query(&mut self, library: &Library, query: impl AsRef<str>) -> Result<()> {
+    pub fn run_query(
+        &mut self,
+        library: &Library,
+        stats: &Stats,
+        query: impl AsRef<str>,
+    ) -> Result<()> {
         let query = query.as_ref();
         self.query = query.to_owned();
 
-        let pattern = Pattern::parse(query, CaseMatching::Ignore, Normalization::Smart);
+        let (filters, words): (Vec<_>, Vec<_>) =
+            query.split(' ').partition(|word| StatFilter::parse(word).is_some());
+        let filters = filters.into_iter().filter_map(StatFilter::parse).collect_vec();
+        let pattern = Pattern::parse(&words.join(" "), CaseMatching::Ignore, Normalization::Smart);
 
         let artists = library.artists().map(|a| a.name.clone()).map(SearchItem::Artist);
 
@@ -140,11 +202,24 @@ impl Search {
 
         let tracks = library.tracks().map(SearchItem::Track);
 
-        let mut results = artists
-            .chain(albums)
-            .chain(tracks)
-            .filter_map(|item| item.match_against(&pattern))
-            .collect_vec();
+        let mut results = if !filters.is_empty() {
+            tracks
+                .filter(|item| {
+                    let SearchItem::Track(track) = item else {
+                        return false;
+                    };
+                    let stats = stats.get(&track.path);
+                    filters.iter().all(|filter| filter.matches(&stats))
+                })
+                .filter_map(|item| item.match_against(&pattern))
+                .collect_vec()
+        } else {
+            artists
+                .chain(albums)
+                .chain(tracks)
+                .filter_map(|item| item.match_against(&pattern))
+                .collect_vec()
+        };
         results.sort_by_key(|result| Reverse(result.score));
 
         self.results = results;
@@ -220,3 +295,24 @@ impl Search {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stat_filters() {
+        let filter = StatFilter::parse("plays>2").unwrap();
+        let stats = |plays, skips| TrackStats {
+            plays,
+            skips,
+            last_played: None,
+        };
+        assert!(filter.matches(&stats(3, 0)));
+        assert!(!filter.matches(&stats(2, 0)));
+        assert!(StatFilter::parse("skips=0").unwrap().matches(&stats(5, 0)));
+        assert_eq!(StatFilter::parse("plays"), None);
+        assert_eq!(StatFilter::parse("skips>lots"), None);
+        assert_eq!(StatFilter::parse("display>3"), None);
+    }
+}
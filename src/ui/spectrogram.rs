@@ -5,8 +5,6 @@ use itertools::Itertools;
 use ratatui::widgets::Sparkline;
 use spectrum_analyzer::{samples_fft_to_spectrum, Frequency, FrequencyLimit, FrequencyValue};
 
-use symphonia::core::audio::{AudioBuffer, Signal};
-
 #[derive(Debug, Clone)]
 pub struct VisualizerOptions {
     /// Number of samples to perform the FFT on. Must be a power of two. Keep
@@ -21,6 +19,16 @@ pub struct VisualizerOptions {
     pub max_freq: f32,
 }
 
+impl VisualizerOptions {
+    /// Picks `n` logarithmically-spaced frequencies between `min_freq` and `max_freq` to display
+    /// things at.
+    pub fn frequencies(&self, n: usize) -> impl Iterator<Item = f32> {
+        let step = (self.max_freq / self.min_freq).powf(1.0 / (n as f32 - 1.0));
+        let min_freq = self.min_freq;
+        (0..n).map(move |i| min_freq * step.powi(i as i32))
+    }
+}
+
 impl Default for VisualizerOptions {
     fn default() -> Self {
         Self {
@@ -86,15 +94,10 @@ impl Visualizer {
         Ok(())
     }
 
-    /// Appends the buffer to the internal buffer. Then recomputes the spectrum accordingly.
-    pub fn update_spectrum(&mut self, buffer: AudioBuffer<f32>) -> Result<()> {
-        if buffer.spec().channels.count() == 1 {
-            self.buffer.extend(buffer.chan(0));
-        } else {
-            // downmix to mono if it's 2-channel or more
-            self.buffer
-                .extend(buffer.chan(0).iter().zip(buffer.chan(1)).map(|(a, b)| (a + b) / 2.0))
-        }
+    /// Appends the (mono) samples to the internal buffer. Then recomputes the spectrum
+    /// accordingly.
+    pub fn update_spectrum(&mut self, samples: &[f32]) -> Result<()> {
+        self.buffer.extend_from_slice(samples);
 
         if self.buffer.len() < self.options.window_length {
             return Ok(());
@@ -117,13 +120,6 @@ impl Visualizer {
         Ok(())
     }
 
-    /// Picks the `n` frequencies to display the spectrogram at.
-    fn frequencies(&self, n: usize) -> impl Iterator<Item = f32> {
-        let step = (self.options.max_freq / self.options.min_freq).powf(1.0 / (n as f32 - 1.0));
-        let min_freq = self.options.min_freq;
-        (0..n).map(move |i| min_freq * step.powi(i as i32))
-    }
-
     /// Get the amplitude of the spectrum at the given point.
     ///
     /// If it's exactly in the spectrum list, we return that. Otherwise we lerp between the two
@@ -154,6 +150,7 @@ impl Visualizer {
         }
 
         let u64_amplitudes = self
+            .options
             .frequencies(width)
             .map(|freq| self.amplitude(freq) * (freq / 400.0).powf(2.0).min(1.0))
             // rescale
@@ -1,4 +1,4 @@
-use std::{cell::RefCell, sync::Arc};
+use std::{cell::RefCell, cmp::Reverse, sync::Arc};
 
 use eyre::Result;
 use itertools::Itertools;
@@ -9,10 +9,85 @@ use ratatui::{
     Frame,
 };
 
-use crate::{library::Track, ui::Ui};
+use crate::{
+    library::Track,
+    ratings::{Ratings, TrackRating, MAX_STARS},
+    stats::Stats,
+    ui::{history_list::format_age, Ui},
+};
 
 use super::ActiveState;
 
+/// Width of the rating, play count, skip count and last played columns, including the spaces
+/// before them.
+const STATS_WIDTH: usize = 7 + 6 + 6 + 10;
+
+/// What we know about tracks besides their tags, for showing and sorting by.
+#[derive(Debug, Clone, Copy)]
+pub struct TrackInfo<'a> {
+    pub stats: &'a Stats,
+    pub ratings: &'a Ratings,
+}
+
+/// How to order the track list.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrackSort {
+    /// Grouped by album, in track number order.
+    #[default]
+    Album,
+    Title,
+    /// Highest rated first, with loved tracks before others with the same rating.
+    Rating,
+    /// Most played first.
+    Plays,
+    /// Most skipped first.
+    Skips,
+    /// Most recently played first.
+    LastPlayed,
+}
+
+impl TrackSort {
+    pub fn next(self) -> Self {
+        match self {
+            TrackSort::Album => TrackSort::Title,
+            TrackSort::Title => TrackSort::Rating,
+            TrackSort::Rating => TrackSort::Plays,
+            TrackSort::Plays => TrackSort::Skips,
+            TrackSort::Skips => TrackSort::LastPlayed,
+            TrackSort::LastPlayed => TrackSort::Album,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TrackSort::Album => "album",
+            TrackSort::Title => "title",
+            TrackSort::Rating => "rating",
+            TrackSort::Plays => "plays",
+            TrackSort::Skips => "skips",
+            TrackSort::LastPlayed => "last played",
+        }
+    }
+
+    /// Sorts `tracks` in place. Sorting by album leaves them as they are.
+    pub fn apply(self, tracks: &mut [Arc<Track>], info: TrackInfo) {
+        let TrackInfo { stats, ratings } = info;
+        match self {
+            TrackSort::Album => (),
+            TrackSort::Title => tracks.sort_by(|a, b| a.title.cmp(&b.title)),
+            TrackSort::Rating => tracks.sort_by_key(|t| {
+                let rating = ratings.track(&t.path);
+                Reverse((rating.stars, rating.loved))
+            }),
+            TrackSort::Plays => tracks.sort_by_key(|t| Reverse(stats.get(&t.path).plays)),
+            TrackSort::Skips => tracks.sort_by_key(|t| Reverse(stats.get(&t.path).skips)),
+            TrackSort::LastPlayed => {
+                tracks.sort_by_key(|t| Reverse(stats.get(&t.path).last_played))
+            }
+        }
+    }
+}
+
 /// Corresponds to a single row in the track list.
 #[derive(Debug)]
 pub enum TrackListItem {
@@ -23,10 +98,27 @@ pub enum TrackListItem {
 }
 
 impl TrackListItem {
-    fn as_list_item(&self, ui: &Ui, current_track: Option<Arc<Track>>) -> ListItem {
+    fn as_list_item(
+        &self,
+        ui: &Ui,
+        current_track: Option<Arc<Track>>,
+        info: TrackInfo,
+        width: usize,
+    ) -> ListItem {
         match self {
             TrackListItem::Track(track) => {
-                let list_item = ListItem::new(track.title.as_deref().unwrap_or("<unknown>"));
+                let title = track.title.as_deref().unwrap_or("<unknown>");
+                let rating = format_rating(info.ratings.track(&track.path));
+                let stats = info.stats.get(&track.path);
+                let last_played = stats.last_played.map_or("never".to_owned(), |time| {
+                    format_age(time.elapsed().unwrap_or_default())
+                });
+                let title_width = width.saturating_sub(STATS_WIDTH);
+                let title = title.chars().take(title_width).collect::<String>();
+                let list_item = ListItem::new(format!(
+                    "{title:<title_width$}{rating:>7}{:>6}{:>6}{last_played:>10}",
+                    stats.plays, stats.skips
+                ));
                 if current_track.as_ref() == Some(track) {
                     list_item.style(ui.theme.now_playing_track)
                 } else {
@@ -47,6 +139,8 @@ impl TrackListItem {
 #[derive(Debug, Default)]
 pub struct TrackList {
     items: Vec<TrackListItem>,
+    sort: TrackSort,
+    title: Option<String>,
     state: RefCell<ListState>,
 }
 
@@ -55,10 +149,31 @@ impl TrackList {
     pub fn new(items: Vec<TrackListItem>) -> Self {
         Self {
             items,
+            sort: TrackSort::Album,
+            title: None,
+            state: RefCell::new(ListState::default()),
+        }
+    }
+
+    /// A flat list of `tracks`, without section headings, in the given order.
+    pub fn sorted(mut tracks: Vec<Arc<Track>>, sort: TrackSort, info: TrackInfo) -> Self {
+        sort.apply(&mut tracks, info);
+        Self {
+            items: tracks.into_iter().map(TrackListItem::Track).collect(),
+            sort,
+            title: None,
             state: RefCell::new(ListState::default()),
         }
     }
 
+    /// Uses `title` instead of the default, which says how the list is sorted.
+    pub fn with_title(self, title: impl Into<String>) -> Self {
+        Self {
+            title: Some(title.into()),
+            ..self
+        }
+    }
+
     /// Move the selection by `amount`, which must either be -1 or 1. If the selection would move
     /// to a section header, keep moving. If that would take us off the edge, do nothing.
     pub fn move_selection(&mut self, amount: isize) {
@@ -95,6 +210,13 @@ impl TrackList {
         }))
     }
 
+    pub fn select_track(&mut self, track: &Track) {
+        self.state.get_mut().select(self.items.iter().position(|item| match item {
+            TrackListItem::Track(t) => **t == *track,
+            _ => false,
+        }))
+    }
+
     pub fn selected(&self) -> Option<Arc<Track>> {
         self.state.borrow().selected().map(|i| match &self.items[i] {
             TrackListItem::Track(track) => track.clone(),
@@ -117,16 +239,23 @@ impl TrackList {
         frame: &mut Frame,
         area: Rect,
         current_track: Option<Arc<Track>>,
+        info: TrackInfo,
     ) -> Result<()> {
+        let title = match &self.title {
+            Some(title) => title.clone(),
+            None => format!("Tracks by {} (s to sort)", self.sort.name()),
+        };
         let block = Block::default()
-            .title("Tracks")
+            .title(format!("{title} / rating / plays / skips / last played"))
             .borders(Borders::ALL)
             .border_style(ui.border(state));
 
+        // Leave room for the borders.
+        let width = area.width.saturating_sub(2) as usize;
         let list = List::new(
             self.items
                 .iter()
-                .map(|item| item.as_list_item(ui, current_track.clone()))
+                .map(|item| item.as_list_item(ui, current_track.clone(), info, width))
                 .collect_vec(),
         )
         .highlight_style(Style::default().fg(Color::Cyan).bg(Color::Rgb(30, 30, 30)))
@@ -135,3 +264,15 @@ impl TrackList {
         Ok(())
     }
 }
+
+/// Formats a rating as a heart (if loved) followed by stars, or blank if unrated.
+pub fn format_rating(rating: TrackRating) -> String {
+    let heart = if rating.loved { "♥" } else { " " };
+    let stars = rating.stars.map_or(String::new(), format_stars);
+    format!("{heart}{stars}")
+}
+
+/// Formats a number of stars as filled stars followed by empty ones.
+pub fn format_stars(stars: u8) -> String {
+    "★".repeat(stars.into()) + &"☆".repeat(MAX_STARS.saturating_sub(stars).into())
+}